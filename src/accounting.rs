@@ -0,0 +1,111 @@
+//!
+//! Persistent traffic accounting.
+//!
+//! Requests and bytes served are counted per day and per size, and
+//! periodically saved to a JSON file, which is read back at startup.
+//!
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::offset::Local;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+// How often we save the counters.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Traffic {
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+// day ("2021-05-01") -> size in bytes -> traffic.
+type Days = BTreeMap<String, BTreeMap<u64, Traffic>>;
+
+pub struct Accounting {
+    path: Option<PathBuf>,
+    days: Mutex<Days>,
+    dirty: AtomicBool,
+}
+
+fn today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+impl Accounting {
+    /// Read the saved counters from `path`. A missing file is not an error.
+    pub fn load(path: Option<PathBuf>) -> io::Result<Accounting> {
+        let mut days = Days::new();
+        if let Some(path) = path.as_ref() {
+            match fs::read_to_string(path) {
+                Ok(data) => {
+                    days = serde_json::from_str(&data).map_err(|e| {
+                        io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+                    })?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(io::Error::new(e.kind(), format!("{:?}: {}", path, e))),
+            }
+        }
+        Ok(Accounting {
+            path,
+            days: Mutex::new(days),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Count a request for a file of `size` bytes.
+    pub fn request(&self, size: u64) {
+        let mut days = self.days.lock().unwrap();
+        let traffic = days.entry(today()).or_default().entry(size).or_default();
+        traffic.requests += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Count the bytes sent for a file of `size` bytes.
+    pub fn bytes(&self, size: u64, bytes: u64) {
+        let mut days = self.days.lock().unwrap();
+        let traffic = days.entry(today()).or_default().entry(size).or_default();
+        traffic.bytes += bytes;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the counters to disk, if anything changed.
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let data = {
+            let days = self.days.lock().unwrap();
+            serde_json::to_string_pretty(&*days).unwrap()
+        };
+        // write to a temp file, then rename, so we never leave a half-written file.
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let res = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, path));
+        if res.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Save the counters periodically. Never returns.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.save() {
+                log::error!("accounting: {:?}: {}", self.path.as_ref().unwrap(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,492 @@
+//!
+//! ACME (RFC 8555) client, to get certificates from Let's Encrypt.
+//!
+//! Only the HTTP-01 challenge is supported. The key authorizations are
+//! written to files in the cache directory, so that every worker process
+//! can serve them on /.well-known/acme-challenge/.
+//!
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use chrono::Utc;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time::{Duration, Instant};
+
+use crate::tls::{self, CertStore};
+use crate::Acme;
+
+// How often we check if the certificate needs to be (re)loaded or renewed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Let's Encrypt certificates are valid for 90 days. Renew when there
+// are 30 days left.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 86400);
+
+// Wait this long after a failed order.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Timeout for one request to the ACME server.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Polling of pending authorizations and orders.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_TRIES: u32 = 30;
+
+/// Let's Encrypt production.
+pub const LETSENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+#[derive(Deserialize)]
+struct Directory {
+    #[serde(rename = "newNonce")]
+    new_nonce: String,
+    #[serde(rename = "newAccount")]
+    new_account: String,
+    #[serde(rename = "newOrder")]
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+/// The certificate chain in the cache directory.
+pub fn cert_file(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("cert.pem")
+}
+
+/// The private key of the certificate in the cache directory.
+pub fn key_file(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("key.pem")
+}
+
+/// Where the key authorizations of pending challenges are.
+pub fn challenge_dir(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("challenges")
+}
+
+/// Tokens are base64url, so they are safe to use as a filename.
+pub fn valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Keep the certificate in `certs` up to date. Never returns.
+///
+/// The certificate is loaded from the cache directory when it changes.
+/// Only if `issue` is set do we order new certificates, in prefork mode
+/// the other workers wait for the first one to do that.
+pub async fn run(acme: Acme, certs: Arc<CertStore>, issue: bool) {
+    let cert = cert_file(&acme.cache_dir);
+    let key = key_file(&acme.cache_dir);
+    let mut loaded = None;
+    let mut next_order = Instant::now();
+
+    loop {
+        reload(&certs, &key, &cert, &mut loaded);
+        if issue && Instant::now() >= next_order && needs_renewal(&cert) {
+            log::info!("acme: ordering certificate for {}", acme.domains.join(", "));
+            match order(&acme).await {
+                Ok(()) => reload(&certs, &key, &cert, &mut loaded),
+                Err(e) => {
+                    log::error!("acme: {}", e);
+                    next_order = Instant::now() + RETRY_INTERVAL;
+                }
+            }
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+// Load the certificate if it was modified since we last loaded it.
+fn reload(certs: &CertStore, key: &Path, cert: &Path, loaded: &mut Option<SystemTime>) {
+    let modified = match fs::metadata(cert).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(e) => {
+            // Not there yet is normal, gone after it was loaded is not.
+            if loaded.take().is_some() {
+                log::error!("acme: {:?}: {}, keeping the old certificate", cert, e);
+            }
+            return;
+        }
+    };
+    if *loaded == Some(modified) {
+        return;
+    }
+    match certs.load(key, cert) {
+        Ok(()) => log::info!("acme: loaded {:?}", cert),
+        Err(e) => log::error!("acme: {}", e),
+    }
+    *loaded = Some(modified);
+}
+
+// Renew if the certificate expires soon, or cannot be read.
+fn needs_renewal(cert: &Path) -> bool {
+    let not_after = tls::load_certs(cert)
+        .ok()
+        .and_then(|certs| tls::not_after(&certs[0].0));
+    match not_after {
+        Some(not_after) => match (not_after - Utc::now()).to_std() {
+            Ok(left) => left < RENEW_BEFORE,
+            Err(_) => true,
+        },
+        None => true,
+    }
+}
+
+// Order a certificate, and save it and its key in the cache directory.
+async fn order(acme: &Acme) -> io::Result<()> {
+    let challenges = challenge_dir(&acme.cache_dir);
+    fs::create_dir_all(&challenges)?;
+
+    let mut client = AcmeClient::new(acme).await?;
+    let contact: Vec<_> = acme
+        .contact
+        .iter()
+        .map(|c| format!("mailto:{}", c))
+        .collect();
+    let account = json!({ "termsOfServiceAgreed": true, "contact": contact });
+    let (kid, _) = client
+        .post(&client.directory.new_account.clone(), Some(&account))
+        .await?;
+    client.kid = Some(
+        kid.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "newAccount: no account URL"))?,
+    );
+
+    let identifiers: Vec<_> = acme
+        .domains
+        .iter()
+        .map(|d| json!({ "type": "dns", "value": d }))
+        .collect();
+    let new_order = json!({ "identifiers": identifiers });
+    let (url, body) = client
+        .post(&client.directory.new_order.clone(), Some(&new_order))
+        .await?;
+    let url = url.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "newOrder: no order URL"))?;
+    let order: Order = parse(&body)?;
+
+    for authz in &order.authorizations {
+        client.authorize(authz, &challenges).await?;
+    }
+
+    // The CSR, with a new key for every certificate.
+    let mut params = rcgen::CertificateParams::new(acme.domains.clone());
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    let cert = rcgen::Certificate::from_params(params)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let csr = cert
+        .serialize_request_der()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    client
+        .post(&order.finalize, Some(&json!({ "csr": b64(&csr) })))
+        .await?;
+
+    let order: Order = client.poll(&url).await?;
+    let chain = match (order.status.as_str(), order.certificate) {
+        ("valid", Some(chain)) => chain,
+        (status, _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("order is {}", status),
+            ))
+        }
+    };
+    let (_, chain) = client.post(&chain, None).await?;
+
+    // Both files are written before either is renamed into place, the
+    // certificate last: workers reload when the certificate changes, and
+    // then find the key that belongs to it.
+    let key = cert.serialize_private_key_pem();
+    let key_tmp = write_tmp(&key_file(&acme.cache_dir), key.as_bytes(), 0o600)?;
+    let cert_tmp = write_tmp(&cert_file(&acme.cache_dir), &chain, 0o644)?;
+    fs::rename(&key_tmp, key_file(&acme.cache_dir))?;
+    fs::rename(&cert_tmp, cert_file(&acme.cache_dir))
+}
+
+struct AcmeClient {
+    http: Client<HttpsConnector<HttpConnector>>,
+    directory: Directory,
+    rng: SystemRandom,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(acme: &Acme) -> io::Result<AcmeClient> {
+        let http = Client::builder().build::<_, Body>(HttpsConnector::with_native_roots());
+        let rng = SystemRandom::new();
+        let key = account_key(&acme.cache_dir, &rng)?;
+
+        // The public key is an uncompressed point: 0x04, x, y.
+        let point = key.public_key().as_ref();
+        let (x, y) = (b64(&point[1..33]), b64(&point[33..65]));
+        let jwk = json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y });
+        // RFC 7638: the members in lexicographic order, no whitespace.
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let thumbprint = b64(digest::digest(&digest::SHA256, canonical.as_bytes()).as_ref());
+
+        let req = Request::get(acme.directory.as_str())
+            .body(Body::empty())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let (_, body) = fetch(&http, req).await?;
+        Ok(AcmeClient {
+            http,
+            directory: parse(&body)?,
+            rng,
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    // Complete the HTTP-01 challenge of an authorization.
+    async fn authorize(&mut self, url: &str, challenges: &Path) -> io::Result<()> {
+        let (_, body) = self.post(url, None).await?;
+        let authz: Authorization = parse(&body)?;
+        if authz.status == "valid" {
+            return Ok(());
+        }
+        let domain = authz.identifier.value;
+        let challenge = authz
+            .challenges
+            .into_iter()
+            .find(|c| c.kind == "http-01" && valid_token(&c.token))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{}: no http-01 challenge", domain),
+                )
+            })?;
+
+        let path = challenges.join(&challenge.token);
+        let key_authz = format!("{}.{}", challenge.token, self.thumbprint);
+        write_file(&path, key_authz.as_bytes(), 0o644)?;
+        let res = async {
+            self.post(&challenge.url, Some(&json!({}))).await?;
+            self.poll::<Authorization>(url).await
+        }
+        .await;
+        let _ = fs::remove_file(&path);
+
+        match res?.status.as_str() {
+            "valid" => Ok(()),
+            status => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{}: authorization is {}", domain, status),
+            )),
+        }
+    }
+
+    // POST-as-GET an authorization or order until it is no longer pending.
+    async fn poll<T: DeserializeOwned>(&mut self, url: &str) -> io::Result<T> {
+        for _ in 0..POLL_TRIES {
+            let (_, body) = self.post(url, None).await?;
+            let value: Value = parse(&body)?;
+            match value["status"].as_str() {
+                Some("pending") | Some("processing") => tokio::time::sleep(POLL_INTERVAL).await,
+                _ => {
+                    return serde_json::from_value(value)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{}: still pending", url),
+        ))
+    }
+
+    // Send a signed request. Without a payload, this is a POST-as-GET.
+    // Returns the Location header and the body.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> io::Result<(Option<String>, Bytes)> {
+        // A nonce can be rejected, in which case we get a new one.
+        for _ in 0..3 {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let body = self.sign(url, &nonce, payload)?;
+            let req = Request::post(url)
+                .header("content-type", "application/jose+json")
+                .body(Body::from(body))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let (parts, body) = fetch(&self.http, req).await?;
+            self.nonce = header(&parts, "replay-nonce");
+            if parts.status.is_success() {
+                return Ok((header(&parts, "location"), body));
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            let detail = problem["detail"].as_str().unwrap_or_default();
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{}: {} {}", url, parts.status, detail),
+            ));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{}: bad nonce", url),
+        ))
+    }
+
+    async fn new_nonce(&self) -> io::Result<String> {
+        let req = Request::head(self.directory.new_nonce.as_str())
+            .body(Body::empty())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let (parts, _) = fetch(&self.http, req).await?;
+        header(&parts, "replay-nonce")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "newNonce: no nonce"))
+    }
+
+    // JWS in flattened JSON serialization, signed with ES256.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> io::Result<String> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match self.kid.as_ref() {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = b64(protected.to_string().as_bytes());
+        let payload = payload
+            .map(|p| b64(p.to_string().as_bytes()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "cannot sign request"))?;
+        let jws = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature.as_ref()),
+        });
+        Ok(jws.to_string())
+    }
+}
+
+// Send a request and read the response body.
+async fn fetch(
+    http: &Client<HttpsConnector<HttpConnector>>,
+    req: Request<Body>,
+) -> io::Result<(http::response::Parts, Bytes)> {
+    let uri = req.uri().clone();
+    let res = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let (parts, body) = http.request(req).await?.into_parts();
+        Ok::<_, hyper::Error>((parts, hyper::body::to_bytes(body).await?))
+    })
+    .await;
+    match res {
+        Ok(Ok(res)) => Ok(res),
+        Ok(Err(e)) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{}: {}", uri, e),
+        )),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{}: timeout", uri),
+        )),
+    }
+}
+
+// The account key, generated the first time.
+fn account_key(cache_dir: &Path, rng: &SystemRandom) -> io::Result<EcdsaKeyPair> {
+    let path = cache_dir.join("account.key");
+    let pkcs8 = match fs::read(&path) {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "cannot generate account key"))?;
+            write_file(&path, pkcs8.as_ref(), 0o600)?;
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(io::Error::new(e.kind(), format!("{:?}: {}", path, e))),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?}: invalid account key", path),
+        )
+    })
+}
+
+// Write a file and rename it into place, so readers never see half of it.
+fn write_file(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
+    let tmp = write_tmp(path, data, mode)?;
+    fs::rename(&tmp, path)
+}
+
+// Write the data for `path` to a temporary file next to it.
+fn write_tmp(path: &Path, data: &[u8], mode: u32) -> io::Result<PathBuf> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    Ok(tmp)
+}
+
+fn header(parts: &http::response::Parts, name: &str) -> Option<String> {
+    parts
+        .headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> io::Result<T> {
+    serde_json::from_slice(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
@@ -0,0 +1,218 @@
+//!
+//! Access control for the transfer routes, with bearer tokens or
+//! signed URLs.
+//!
+//! A signed URL has `expires=<unix time>&signature=<hex>` in the query,
+//! where the signature is the HMAC-SHA256 of `<path>?expires=<unix time>`
+//! with the url-secret as key.
+//!
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::{constant_time, hmac};
+
+use crate::Auth;
+
+/// Why a request was refused.
+#[derive(Debug)]
+pub enum Denied {
+    // No credentials: 401.
+    Unauthorized,
+    // Wrong or expired credentials: 403.
+    Forbidden,
+}
+
+impl warp::reject::Reject for Denied {}
+
+// Decode a hex string.
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok().filter(|p| p.len() == 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+// Is the token one of the configured ones.
+fn valid_token(auth: &Auth, token: &str) -> bool {
+    auth.tokens
+        .iter()
+        .any(|t| constant_time::verify_slices_are_equal(t.as_bytes(), token.as_bytes()).is_ok())
+}
+
+// Is the signature right and not expired.
+fn valid_signature(auth: &Auth, path: &str, expires: &str, signature: &str) -> bool {
+    let secret = match auth.url_secret.as_ref() {
+        Some(secret) => secret,
+        None => return false,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    match expires.parse::<u64>() {
+        Ok(expires) if expires >= now => {}
+        _ => return false,
+    }
+    let signature = match from_hex(signature) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let msg = format!("{}?expires={}", path, expires);
+    hmac::verify(&key, msg.as_bytes(), &signature).is_ok()
+}
+
+/// Check the Authorization header or the signature in the query,
+/// if a token or url-secret is configured.
+pub fn check(
+    auth: &Auth,
+    authorization: Option<&str>,
+    path: &str,
+    query: &str,
+) -> Result<(), Denied> {
+    if auth.tokens.is_empty() && auth.url_secret.is_none() {
+        return Ok(());
+    }
+
+    if let Some(authorization) = authorization {
+        let token = authorization
+            .strip_prefix("Bearer ")
+            .or_else(|| authorization.strip_prefix("bearer "));
+        return match token {
+            Some(token) if valid_token(auth, token.trim()) => Ok(()),
+            _ => Err(Denied::Forbidden),
+        };
+    }
+
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|kv| kv.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    };
+    match (param("expires"), param("signature")) {
+        (Some(expires), Some(signature)) if valid_signature(auth, path, expires, signature) => {
+            Ok(())
+        }
+        (None, None) => Err(Denied::Unauthorized),
+        _ => Err(Denied::Forbidden),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth {
+            client_ca: None,
+            tokens: vec!["s3cret".to_string()],
+            url_secret: Some("key".to_string()),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // The query of a signed URL for `path`.
+    fn signed(path: &str, expires: u64) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"key");
+        let msg = format!("{}?expires={}", path, expires);
+        let signature: String = hmac::sign(&key, msg.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("expires={}&signature={}", expires, signature)
+    }
+
+    fn unauthorized(res: Result<(), Denied>) -> bool {
+        matches!(res, Err(Denied::Unauthorized))
+    }
+
+    fn forbidden(res: Result<(), Denied>) -> bool {
+        matches!(res, Err(Denied::Forbidden))
+    }
+
+    #[test]
+    fn nothing_configured() {
+        let auth = Auth {
+            tokens: Vec::new(),
+            url_secret: None,
+            ..auth()
+        };
+        assert!(check(&auth, None, "/1MB.bin", "").is_ok());
+    }
+
+    #[test]
+    fn no_credentials() {
+        assert!(unauthorized(check(&auth(), None, "/1MB.bin", "")));
+        assert!(unauthorized(check(&auth(), None, "/1MB.bin", "cc=bbr")));
+    }
+
+    #[test]
+    fn bearer_token() {
+        let auth = auth();
+        assert!(check(&auth, Some("Bearer s3cret"), "/1MB.bin", "").is_ok());
+        assert!(check(&auth, Some("bearer s3cret "), "/1MB.bin", "").is_ok());
+        assert!(forbidden(check(&auth, Some("Bearer wrong"), "/1MB.bin", "")));
+        assert!(forbidden(check(&auth, Some("Basic s3cret"), "/1MB.bin", "")));
+        assert!(forbidden(check(&auth, Some("s3cret"), "/1MB.bin", "")));
+    }
+
+    #[test]
+    fn signed_url() {
+        let query = signed("/1MB.bin", now() + 60);
+        assert!(check(&auth(), None, "/1MB.bin", &query).is_ok());
+        let query = format!("cc=bbr&{}", query);
+        assert!(check(&auth(), None, "/1MB.bin", &query).is_ok());
+    }
+
+    #[test]
+    fn signed_url_expired() {
+        let query = signed("/1MB.bin", now() - 1);
+        assert!(forbidden(check(&auth(), None, "/1MB.bin", &query)));
+    }
+
+    #[test]
+    fn signed_url_wrong_path() {
+        let query = signed("/1MB.bin", now() + 60);
+        assert!(forbidden(check(&auth(), None, "/1GB.bin", &query)));
+    }
+
+    #[test]
+    fn signed_url_bad_signature() {
+        let expires = now() + 60;
+        let query = signed("/1MB.bin", expires);
+        let signature = query.rsplit('=').next().unwrap();
+        let bad = [
+            "zz".repeat(32),
+            signature[1..].to_string(),
+            signature[..62].to_string(),
+            String::new(),
+        ];
+        for signature in bad.iter() {
+            let query = format!("expires={}&signature={}", expires, signature);
+            assert!(forbidden(check(&auth(), None, "/1MB.bin", &query)));
+        }
+        let query = format!("expires={}", expires);
+        assert!(forbidden(check(&auth(), None, "/1MB.bin", &query)));
+    }
+
+    #[test]
+    fn signed_url_without_secret() {
+        let auth = Auth {
+            url_secret: None,
+            ..auth()
+        };
+        let query = signed("/1MB.bin", now() + 60);
+        assert!(forbidden(check(&auth, None, "/1MB.bin", &query)));
+    }
+}
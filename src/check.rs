@@ -0,0 +1,125 @@
+//!
+//! Checking the config, for --check-config.
+//!
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::logsink::Destination;
+use crate::server;
+use crate::template;
+use crate::tls;
+use crate::{add_listener, find_path, validate, Config};
+
+/// Check everything that can be checked without starting the server,
+/// print the problems, and exit.
+pub fn run(config_file: &str, config: &Config) -> ! {
+    let problems = check(config);
+    for problem in &problems {
+        eprintln!("{}: {}", config_file, problem);
+    }
+    if problems.is_empty() {
+        println!("{}: OK", config_file);
+        std::process::exit(0);
+    }
+    eprintln!("{}: {} problem(s) found", config_file, problems.len());
+    std::process::exit(1);
+}
+
+fn check(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Err(e) = validate(config) {
+        problems.push(e);
+    }
+
+    // Listen addresses.
+    let sections = [
+        ("http", config.http.as_ref().map(|h| &h.listen)),
+        ("https", config.https.as_ref().map(|h| &h.listen)),
+        ("metrics", config.metrics.as_ref().map(|m| &m.listen)),
+    ];
+    for (section, listen) in sections.iter() {
+        for l in listen.iter().flat_map(|l| l.iter()) {
+            if let Err(e) = add_listener(l, config.v6only, &mut Vec::new()) {
+                problems.push(format!("{}: listen {}: {}", section, l, e));
+            }
+        }
+    }
+
+    // Sizes on the index page.
+    let sizes = config.index.sizes.iter();
+    let labels = config.index.size_info.iter().map(|i| &i.size);
+    for size in sizes.chain(labels) {
+        if let Err(e) = server::size(size) {
+            problems.push(format!("index: size {}: {}", size, e));
+        }
+    }
+
+    // The certificate and key, and whether they belong together.
+    if let Some(https) = config.https.as_ref() {
+        if let (Some(key), Some(chain)) = (https.key.as_ref(), https.chain.as_ref()) {
+            let key = find_path("/etc/ssl/private", key);
+            let chain = find_path("/etc/ssl/certs", chain);
+            match (key, chain) {
+                (Ok(key), Ok(chain)) => {
+                    if let Err(e) = tls::check_pair(&key, &chain) {
+                        problems.push(format!("https: {}", e));
+                    }
+                }
+                (key, chain) => {
+                    for (p, e) in key.err().into_iter().chain(chain.err()) {
+                        problems.push(format!("https: {:?}: {}", p, e));
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(client_ca) = config.auth.as_ref().and_then(|a| a.client_ca.as_ref()) {
+        if let Err(e) = tls::load_client_ca(client_ca) {
+            problems.push(format!("auth: {}", e));
+        }
+    }
+
+    if let Err(e) = template::check(config) {
+        problems.push(format!("index: {}", e));
+    }
+
+    if let Some(Destination::File(path)) = config.access_log.as_deref().map(Destination::parse) {
+        if let Err(e) = writable(&path) {
+            problems.push(format!("access-log: {:?}: {}", path, e));
+        }
+    }
+
+    // Directories that are served.
+    let dirs = [
+        ("data-dir", config.data_dir.as_ref()),
+        ("index: assets", config.index.assets.as_ref()),
+    ];
+    for (name, dir) in dirs.iter() {
+        if let Some(dir) = dir.filter(|d| !d.is_dir()) {
+            problems.push(format!("{}: {:?}: not a directory", name, dir));
+        }
+    }
+
+    problems
+}
+
+// Can the file be opened for appending, or created if it does not
+// exist. The check does not create it.
+fn writable(path: &Path) -> std::io::Result<()> {
+    if path.exists() {
+        return OpenOptions::new().append(true).open(path).map(|_| ());
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let c_dir = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    if unsafe { libc::access(c_dir.as_ptr(), libc::W_OK) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
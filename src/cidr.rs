@@ -0,0 +1,192 @@
+//! IP network (CIDR) type.
+//!
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::de;
+
+/// An IPv4 or IPv6 network, like `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Cidr> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return None;
+        }
+        // An IPv4-mapped network (::ffff:10.0.0.0/104) is an IPv4
+        // network, since addresses are compared as IPv4 addresses.
+        match canonical(addr) {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix_len >= 96 => Some(Cidr {
+                addr: IpAddr::V4(v4),
+                prefix_len: prefix_len - 96,
+            }),
+            _ => Some(Cidr { addr, prefix_len }),
+        }
+    }
+
+    /// The network address as an integer, host bits cleared.
+    pub fn network(&self) -> u128 {
+        mask(ip_to_u128(self.addr), self.width(), self.prefix_len)
+    }
+
+    fn width(&self) -> u8 {
+        match self.addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    /// Check if `ip` is part of this network. IPv4-mapped IPv6
+    /// addresses are treated as IPv4 addresses.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = canonical(*ip);
+        match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                mask(ip_to_u128(ip), self.width(), self.prefix_len) == self.network()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Turn an IPv4-mapped IPv6 address (::ffff:1.2.3.4) into an IPv4 address.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip6) => match ip6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => {
+                let o = ip6.octets();
+                IpAddr::from([o[12], o[13], o[14], o[15]])
+            }
+            _ => ip,
+        },
+        _ => ip,
+    }
+}
+
+/// An IP address as an integer, for prefix lookups.
+pub fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip4) => u32::from(ip4) as u128,
+        IpAddr::V6(ip6) => u128::from(ip6),
+    }
+}
+
+/// Clear all host bits.
+pub fn mask(addr: u128, width: u8, prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        return 0;
+    }
+    let host_bits = (width - prefix_len) as u32;
+    addr >> host_bits << host_bits
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap();
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("{}: {}", s, e))?;
+        let prefix_len = match (parts.next(), addr) {
+            (Some(len), _) => len
+                .parse::<u8>()
+                .map_err(|_| format!("{}: invalid prefix length", s))?,
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+        Cidr::new(addr, prefix_len).ok_or_else(|| format!("{}: invalid prefix length", s))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Cidr {
+    fn deserialize<D>(deserializer: D) -> Result<Cidr, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s: String = de::Deserialize::deserialize(deserializer)?;
+        s.parse::<Cidr>().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_zero() {
+        assert!(cidr("0.0.0.0/0").contains(&ip("192.0.2.1")));
+        assert!(cidr("0.0.0.0/0").contains(&ip("255.255.255.255")));
+        assert!(!cidr("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(&ip("192.0.2.1")));
+        // Host bits in the address are ignored.
+        assert!(cidr("10.1.2.3/0").contains(&ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn host_routes() {
+        assert_eq!(cidr("192.0.2.1"), cidr("192.0.2.1/32"));
+        assert!(cidr("192.0.2.1/32").contains(&ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1/32").contains(&ip("192.0.2.2")));
+        assert_eq!(cidr("2001:db8::1"), cidr("2001:db8::1/128"));
+        assert!(cidr("2001:db8::1/128").contains(&ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(&ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn prefixes() {
+        assert!(cidr("10.0.0.0/8").contains(&ip("10.255.255.255")));
+        assert!(!cidr("10.0.0.0/8").contains(&ip("11.0.0.0")));
+        assert!(cidr("2001:db8::/32").contains(&ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(&ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn v4_mapped() {
+        // A mapped address is matched as an IPv4 address.
+        assert!(cidr("10.0.0.0/8").contains(&ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(&ip("::ffff:11.1.2.3")));
+        assert!(!cidr("::/0").contains(&ip("::ffff:10.1.2.3")));
+        // A mapped network is an IPv4 network.
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(&ip("10.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(&ip("::ffff:10.1.2.3")));
+        assert_eq!(canonical(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(canonical(ip("::192.0.2.1")), ip("::192.0.2.1"));
+    }
+}
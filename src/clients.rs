@@ -0,0 +1,139 @@
+//!
+//! Per-client limits: concurrent streams, and requests per minute.
+//!
+//! IPv6 clients are tracked per /64, since that is what a single
+//! client usually gets.
+//!
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::time::{Duration, Instant};
+
+use crate::cidr;
+
+// Requests are counted per minute.
+const WINDOW: Duration = Duration::from_secs(60);
+
+// What to tell a client that has too many streams open.
+pub const STREAMS_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Why a request was refused.
+pub enum Refused {
+    Streams,
+    // With the time until the next window.
+    Requests(Duration),
+}
+
+impl Refused {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Refused::Streams => "too many streams",
+            Refused::Requests(_) => "too many requests",
+        }
+    }
+}
+
+/// The clients that have been active recently.
+pub struct Clients {
+    state: Mutex<State>,
+}
+
+struct State {
+    clients: HashMap<IpAddr, Client>,
+    pruned: Instant,
+}
+
+struct Client {
+    streams: usize,
+    window: Instant,
+    requests: u32,
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        Clients {
+            state: Mutex::new(State {
+                clients: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Count a request of `ip`, and start a stream if the limits allow it.
+    /// The stream ends when the returned guard is dropped.
+    pub fn start(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        max_streams: Option<usize>,
+        max_requests: Option<u32>,
+    ) -> Result<ClientGuard, Refused> {
+        let key = key(ip);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+
+        let client = state.clients.entry(key).or_insert(Client {
+            streams: 0,
+            window: now,
+            requests: 0,
+        });
+        if now.duration_since(client.window) >= WINDOW {
+            client.window = now;
+            client.requests = 0;
+        }
+        client.requests = client.requests.saturating_add(1);
+        if let Some(max) = max_requests {
+            if client.requests > max {
+                return Err(Refused::Requests(
+                    WINDOW - now.duration_since(client.window),
+                ));
+            }
+        }
+        if let Some(max) = max_streams {
+            if client.streams >= max {
+                return Err(Refused::Streams);
+            }
+        }
+        client.streams += 1;
+        Ok(ClientGuard {
+            clients: self.clone(),
+            key,
+        })
+    }
+}
+
+impl State {
+    // Forget clients without streams whose window has passed.
+    fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.pruned) < WINDOW {
+            return;
+        }
+        self.pruned = now;
+        self.clients
+            .retain(|_, c| c.streams > 0 || now.duration_since(c.window) < WINDOW);
+    }
+}
+
+// The address, or the /64 for IPv6.
+fn key(ip: IpAddr) -> IpAddr {
+    match cidr::canonical(ip) {
+        IpAddr::V6(ip6) => IpAddr::V6(cidr::mask(u128::from(ip6), 128, 64).into()),
+        ip => ip,
+    }
+}
+
+/// Ends the stream of a client when dropped.
+pub struct ClientGuard {
+    clients: Arc<Clients>,
+    key: IpAddr,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let mut state = self.clients.state.lock().unwrap();
+        if let Some(client) = state.clients.get_mut(&self.key) {
+            client.streams -= 1;
+        }
+    }
+}
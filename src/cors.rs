@@ -0,0 +1,79 @@
+//!
+//! CORS, so that a speedtest page on another origin can use the server.
+//!
+use http::header::{HeaderMap, HeaderValue};
+use http::{Method, Response, StatusCode};
+use hyper::Body;
+use warp::reply::Response as HyperResponse;
+
+use crate::Cors;
+
+// Response headers that scripts may read. Content-Length is needed
+// to show progress.
+const EXPOSE_HEADERS: &str = "content-length, content-range, accept-ranges, retry-after, \
+                              x-request-id, x-warmup-length, x-server-timestamp, x-seed";
+
+impl Cors {
+    // The value for Access-Control-Allow-Origin, if the origin is allowed.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if self.origins.iter().any(|o| o == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let o = origin.to_str().ok()?;
+        if self.origins.iter().any(|a| a.eq_ignore_ascii_case(o)) {
+            return Some(origin.clone());
+        }
+        None
+    }
+
+    fn allow_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+}
+
+/// Answer a preflight request (OPTIONS with Access-Control-Request-Method).
+/// If the origin or method is not allowed, the answer has no CORS headers,
+/// and the browser will not send the actual request.
+pub fn preflight<B>(cors: &Cors, req: &http::Request<B>) -> Option<HyperResponse> {
+    let headers = req.headers();
+    let method = headers.get("access-control-request-method")?;
+    let origin = headers.get("origin")?;
+    if req.method() != Method::OPTIONS {
+        return None;
+    }
+
+    // Vary and Access-Control-Allow-Origin are added by apply().
+    let mut resp = Response::builder().status(StatusCode::NO_CONTENT);
+    let allowed = method
+        .to_str()
+        .map(|m| cors.allow_method(m))
+        .unwrap_or(false);
+    if cors.allow_origin(origin).is_some() && allowed {
+        resp = resp.header("access-control-allow-methods", cors.methods.join(", "));
+        // Allow the request headers that were asked for, or just
+        // the configured ones.
+        if cors.headers.is_empty() {
+            if let Some(h) = headers.get("access-control-request-headers") {
+                resp = resp.header("access-control-allow-headers", h);
+            }
+        } else {
+            resp = resp.header("access-control-allow-headers", cors.headers.join(", "));
+        }
+        if let Some(max_age) = cors.max_age {
+            resp = resp.header("access-control-max-age", max_age);
+        }
+    }
+    resp.body(Body::empty()).ok()
+}
+
+/// Add the CORS headers to a response, if the origin is allowed.
+pub fn apply(cors: &Cors, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+    headers.append("vary", HeaderValue::from_static("origin"));
+    if let Some(allow_origin) = origin.and_then(|o| cors.allow_origin(o)) {
+        headers.insert("access-control-allow-origin", allow_origin);
+        headers.insert(
+            "access-control-expose-headers",
+            HeaderValue::from_static(EXPOSE_HEADERS),
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//!
+//! Capability discovery document, served at /.well-known/speedtest.
+//!
+//! Lets clients find out what this particular instance supports.
+//!
+use serde::Serialize;
+
+use crate::server::MAX_FILE_SIZE;
+use crate::Config;
+
+// Units that are accepted in the size of a file.
+const UNITS: &[&str] = &["B", "kB", "MB", "GB", "TB", "KiB", "MiB", "GiB", "TiB"];
+
+#[derive(Debug, Serialize)]
+pub struct Discovery {
+    version: &'static str,
+    // URL templates.
+    download: &'static str,
+    infinite: &'static str,
+    upload: Option<&'static str>,
+    latency: &'static str,
+    result: Option<&'static str>,
+    stats: Option<&'static str>,
+    openapi: &'static str,
+    files: &'static str,
+    websocket: Option<&'static str>,
+    // Limits and features.
+    max_file_size: u64,
+    units: &'static [&'static str],
+    sizes: Vec<String>,
+    warmup: bool,
+    http: bool,
+    https: bool,
+    http2: bool,
+    quic: bool,
+}
+
+/// Build the discovery document.
+pub fn discovery(config: &Config) -> Discovery {
+    Discovery {
+        version: env!("CARGO_PKG_VERSION"),
+        download: "/{size}",
+        infinite: "/infinite",
+        upload: if config.upload {
+            Some("/upload")
+        } else if config.tr143 {
+            Some("/{size}")
+        } else {
+            None
+        },
+        latency: "/empty",
+        result: if config.transfer_results {
+            Some("/result/{id}")
+        } else {
+            None
+        },
+        stats: if config.public_stats {
+            Some("/stats.json")
+        } else {
+            None
+        },
+        openapi: "/openapi.json",
+        files: "/api/files.json",
+        websocket: if config.websocket { Some("/ws") } else { None },
+        max_file_size: config.max_file_size.unwrap_or(MAX_FILE_SIZE),
+        units: UNITS,
+        sizes: config.index.sizes.clone(),
+        warmup: true,
+        http: config.http.is_some(),
+        https: config.https.is_some(),
+        http2: config
+            .https
+            .as_ref()
+            .and_then(|h| h.http2.as_ref())
+            .filter(|h2| h2.disable)
+            .is_none(),
+        quic: false,
+    }
+}
@@ -0,0 +1,53 @@
+//!
+//! A dedicated pool of threads for generating random data.
+//!
+//! Generating data for very fast streams takes a lot of CPU. On the
+//! tokio worker threads that delays the handling of all the other
+//! connections, so it can be moved to a pool of its own.
+//!
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use once_cell::sync::OnceCell;
+
+pub type Job = Box<dyn FnOnce() + Send>;
+
+// Length of the job queue, per thread.
+const QUEUE_PER_THREAD: usize = 64;
+
+static POOL: OnceCell<SyncSender<Job>> = OnceCell::new();
+
+/// Start the pool with `threads` threads.
+pub fn start(threads: usize) -> io::Result<()> {
+    let (tx, rx) = sync_channel::<Job>(threads * QUEUE_PER_THREAD);
+    let rx = Arc::new(Mutex::new(rx));
+    for i in 0..threads {
+        let rx = rx.clone();
+        thread::Builder::new()
+            .name(format!("generator-{}", i))
+            .spawn(move || loop {
+                let job = match rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                // A panicking job should not take the thread with it.
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            })?;
+    }
+    let _ = POOL.set(tx);
+    Ok(())
+}
+
+/// Queue a job on the pool. If there is no pool, or its queue is
+/// full, the job is handed back.
+pub fn spawn(job: Job) -> Result<(), Job> {
+    match POOL.get() {
+        Some(tx) => tx.try_send(job).map_err(|e| match e {
+            TrySendError::Full(job) | TrySendError::Disconnected(job) => job,
+        }),
+        None => Err(job),
+    }
+}
@@ -24,6 +24,29 @@ impl Lehmer64_3 {
         }
         (self.state[self.pos as usize] >> 64) as u64
     }
+
+    /// Advance the generator by `n` outputs, without generating them.
+    pub fn skip(&mut self, n: u64) {
+        let steps = self.pos as u64 + n;
+        let m = pow(0xda942042e4dd58b5u128, steps / 3);
+        mul(&mut self.state[0], m);
+        mul(&mut self.state[1], m);
+        mul(&mut self.state[2], m);
+        self.pos = (steps % 3) as u32;
+    }
+}
+
+// a^e (mod 2^128).
+fn pow(mut a: u128, mut e: u64) -> u128 {
+    let mut r = 1u128;
+    while e > 0 {
+        if e & 1 == 1 {
+            r = r.wrapping_mul(a);
+        }
+        a = a.wrapping_mul(a);
+        e >>= 1;
+    }
+    r
 }
 
 impl RngCore for Lehmer64_3 {
@@ -99,3 +122,37 @@ impl SeedableRng for Lehmer64 {
         Lehmer64(n as u128)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rng() -> Lehmer64_3 {
+        Lehmer64_3::from_seed(*b"0123456789abcdefghijklmn")
+    }
+
+    #[test]
+    fn skip_is_fill_then_drop() {
+        for n in [0u64, 1, 2, 3, 4, 5, 1000, 1001, 1002] {
+            let mut full = vec![0u8; 8 * (n as usize + 16)];
+            rng().fill_bytes(&mut full);
+
+            let mut skipped = rng();
+            skipped.skip(n);
+            let mut rest = vec![0u8; 8 * 16];
+            skipped.fill_bytes(&mut rest);
+
+            assert_eq!(rest, full[8 * n as usize..], "skip({})", n);
+        }
+    }
+
+    #[test]
+    fn skip_twice() {
+        let mut once = rng();
+        once.skip(10);
+        let mut twice = rng();
+        twice.skip(4);
+        twice.skip(6);
+        assert_eq!(once.next_u64(), twice.next_u64());
+    }
+}
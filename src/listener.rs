@@ -0,0 +1,497 @@
+//!
+//! Listeners: accepting connections, serving them, and supervision.
+//!
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use hyper::{Body, Request};
+use once_cell::sync::OnceCell;
+use rustls::Session;
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tokio::time::{Duration, Instant};
+use tokio_rustls::TlsAcceptor;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+use crate::logger::LogInfo;
+use crate::proxy;
+use crate::server::FileServer;
+use crate::shutdown;
+use crate::tcpinfo::{self, TcpInfo};
+use crate::tls;
+use crate::Http2;
+
+// Backoff between restarts.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// If a listener ran for at least this long, it was up.
+const UP_THRESHOLD: Duration = Duration::from_secs(5);
+
+// Time a proxy gets to send the PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run a listener, and restart it with backoff when it fails.
+///
+/// `start` binds the listener and returns the server future. Only if the
+/// listener has been down for longer than `max_down` do we give up,
+/// and return the last error.
+pub async fn supervise<F, S>(name: String, max_down: Duration, mut start: F) -> String
+where
+    F: FnMut() -> Result<S, String>,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let mut down_since: Option<Instant> = None;
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let err = match start() {
+            Ok(srv) => {
+                let started = Instant::now();
+                let res = task::spawn(srv).await;
+                if shutdown::is_started() {
+                    return format!("{}: shut down", name);
+                }
+                if started.elapsed() >= UP_THRESHOLD {
+                    down_since = None;
+                    backoff = MIN_BACKOFF;
+                }
+                match res {
+                    Ok(()) => String::from("server exited unexpectedly"),
+                    Err(err) => match err.try_into_panic() {
+                        Ok(cause) => match cause.downcast_ref::<String>() {
+                            Some(msg) => msg.clone(),
+                            None => String::from("server panicked"),
+                        },
+                        Err(err) => err.to_string(),
+                    },
+                }
+            }
+            Err(err) => err,
+        };
+
+        let down = *down_since.get_or_insert_with(Instant::now);
+        if down.elapsed() >= max_down {
+            return format!("{}: {}", name, err);
+        }
+        log::warn!("{}: {}, restarting in {:?}", name, err, backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+/// Socket options for the connections on a listener.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    // Congestion control algorithm.
+    pub congestion_control: Option<String>,
+    // Algorithms that can be selected with ?cc=.
+    pub allowed_congestion_control: Vec<String>,
+    // Connections start with a PROXY protocol header.
+    pub proxy_protocol: bool,
+    // Kernel socket buffer sizes.
+    pub send_buffer: Option<usize>,
+    pub receive_buffer: Option<usize>,
+    // Set TCP_NODELAY.
+    pub nodelay: bool,
+    // HTTP/2 settings.
+    pub http2: Option<Http2>,
+}
+
+impl SocketOptions {
+    /// Apply to a listening socket. The connections it accepts inherit
+    /// the buffer sizes and the congestion control algorithm. The buffer
+    /// sizes must be set before listen(), for the TCP window scale.
+    pub fn apply_listener<S: AsRawFd>(&self, socket: &S) -> io::Result<()> {
+        let socket = SockRef::from(socket);
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.receive_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(cc) = self.congestion_control.as_ref() {
+            tcpinfo::set_congestion_control(socket.as_raw_fd(), cc).map_err(|e| {
+                io::Error::new(e.kind(), format!("congestion control {}: {}", cc, e))
+            })?;
+        }
+        Ok(())
+    }
+
+    // Apply to a newly accepted connection.
+    fn apply(&self, stream: &TcpStream) {
+        if self.nodelay {
+            if let Err(e) = stream.set_nodelay(true) {
+                log::debug!("set nodelay: {}", e);
+            }
+        }
+    }
+}
+
+/// Information about a connection. Request handlers can get at
+/// it through the `conn_info()` filter.
+pub struct ConnInfo {
+    pub remote_addr: SocketAddr,
+    // Is this a TLS connection.
+    pub tls: bool,
+    // The socket, as long as the connection is open.
+    fd: Mutex<Option<RawFd>>,
+    options: Arc<SocketOptions>,
+    // CN of the client certificate.
+    client_cn: OnceCell<String>,
+}
+
+impl ConnInfo {
+    fn new(
+        remote_addr: SocketAddr,
+        tls: bool,
+        stream: &TcpStream,
+        options: Arc<SocketOptions>,
+    ) -> ConnInfo {
+        ConnInfo {
+            remote_addr,
+            tls,
+            fd: Mutex::new(Some(stream.as_raw_fd())),
+            options,
+            client_cn: OnceCell::new(),
+        }
+    }
+
+    /// The common name of the client certificate, if there was one.
+    pub fn client_cn(&self) -> Option<&str> {
+        self.client_cn.get().map(String::as_str)
+    }
+
+    /// Select the congestion control algorithm, if allowed on this listener.
+    pub fn set_congestion_control(&self, name: &str) -> io::Result<()> {
+        if !self
+            .options
+            .allowed_congestion_control
+            .iter()
+            .any(|a| a == name)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("congestion control {} not allowed", name),
+            ));
+        }
+        match *self.fd.lock().unwrap() {
+            Some(fd) => tcpinfo::set_congestion_control(fd, name),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    /// TCP statistics of the connection, if it is still open.
+    pub fn tcp_info(&self) -> Option<TcpInfo> {
+        // Holding the lock makes sure the socket is not closed under us.
+        let fd = self.fd.lock().unwrap();
+        fd.and_then(tcpinfo::tcp_info)
+    }
+}
+
+/// Filter that returns the information about the connection.
+pub fn conn_info() -> impl Filter<Extract = (Option<Arc<ConnInfo>>,), Error = Infallible> + Copy {
+    warp::ext::optional::<Arc<ConnInfo>>()
+}
+
+// Wrapper around a TcpStream that invalidates ConnInfo::fd when dropped.
+// `prefix` is data that was already read, after the PROXY header.
+struct Conn {
+    stream: TcpStream,
+    info: Arc<ConnInfo>,
+    prefix: Vec<u8>,
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.info.fd.lock().unwrap().take();
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = std::cmp::min(self.prefix.len(), buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Bind a listening socket. With `reuse_port`, several processes
+/// can bind the same address (prefork mode). IPv6 sockets are
+/// dual-stack unless `v6only` is set, whatever the OS default is.
+pub fn bind(
+    addr: SocketAddr,
+    reuse_port: bool,
+    v6only: bool,
+    options: &SocketOptions,
+) -> io::Result<TcpListener> {
+    TcpListener::from_std(bind_std(addr, reuse_port, v6only, options)?)
+}
+
+/// The same, as a std (non-blocking) socket.
+pub fn bind_std(
+    addr: SocketAddr,
+    reuse_port: bool,
+    v6only: bool,
+    options: &SocketOptions,
+) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6only)?;
+    }
+    options.apply_listener(&socket)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Accept connections and serve `routes` on them, over TLS if `tls` is set.
+pub async fn serve<R>(
+    listener: TcpListener,
+    options: Arc<SocketOptions>,
+    tls: Option<TlsAcceptor>,
+    server: FileServer,
+    routes: BoxedFilter<(R,)>,
+) where
+    R: Reply + 'static,
+{
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown::started() => return,
+        };
+        let (mut stream, addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Most likely out of file descriptors, back off a bit.
+                log::error!("accept: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        options.apply(&stream);
+        let options = options.clone();
+        let tls = tls.clone();
+        let server = server.clone();
+        let routes = routes.clone();
+        let conn_guard = shutdown::ConnGuard::new();
+        task::spawn(async move {
+            let _conn_guard = conn_guard;
+            // Behind a proxy, the client address is in the PROXY header.
+            let (remote_addr, prefix) = if options.proxy_protocol {
+                let header = proxy::read_header(&mut stream);
+                match tokio::time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                    Ok(Ok((client, prefix))) => (client.unwrap_or(addr), prefix),
+                    Ok(Err(e)) => return log::debug!("{}: {}", addr, e),
+                    Err(_) => return log::debug!("{}: PROXY protocol: timeout", addr),
+                }
+            } else {
+                (addr, Vec::new())
+            };
+            let info = Arc::new(ConnInfo::new(remote_addr, tls.is_some(), &stream, options));
+            let conn = Conn {
+                stream,
+                info: info.clone(),
+                prefix,
+            };
+            match tls {
+                Some(tls) => match tls.accept(conn).await {
+                    Ok(stream) => {
+                        let certs = stream.get_ref().1.get_peer_certificates();
+                        if let Some(cn) = certs.and_then(|c| tls::common_name(&c.first()?.0)) {
+                            let _ = info.client_cn.set(cn);
+                        }
+                        serve_conn(stream, info, server, routes).await
+                    }
+                    Err(e) => log::debug!("{}: TLS handshake: {}", remote_addr, e),
+                },
+                None => serve_conn(conn, info, server, routes).await,
+            }
+        });
+    }
+}
+
+// Serve HTTP on a connection. Every request gets the ConnInfo as an
+// extension, pre- and post-routing is done, and the request is logged when
+// the response is ready (unless the response is a stream, which logs
+// itself).
+async fn serve_conn<T, R>(io: T, info: Arc<ConnInfo>, server: FileServer, routes: BoxedFilter<(R,)>)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    R: Reply + 'static,
+{
+    let warp_service = warp::service(routes);
+    let remote_addr = info.remote_addr;
+    // The HTTP/2 settings of the listener.
+    let mut http = Http::new();
+    if let Some(h2) = info.options.http2.as_ref() {
+        http.http1_only(h2.disable);
+        http.http2_adaptive_window(h2.adaptive_window);
+        if let Some(size) = h2.initial_stream_window_size {
+            http.http2_initial_stream_window_size(size as u32);
+        }
+        if let Some(size) = h2.initial_connection_window_size {
+            http.http2_initial_connection_window_size(size as u32);
+        }
+        if let Some(max) = h2.max_concurrent_streams {
+            http.http2_max_concurrent_streams(max);
+        }
+    }
+    let io = HintWriter {
+        io,
+        pending: Arc::new(Mutex::new(Vec::new())),
+    };
+    let pending = io.pending.clone();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let service = service_fn(move |mut req: Request<Body>| {
+        req.extensions_mut().insert(info.clone());
+        // warp has no filter for the HTTP version.
+        let version = req.version();
+        req.extensions_mut().insert(version);
+        server.stats().count_request();
+        let log_info = LogInfo::from_request(&req);
+        let mut warp_service = warp_service.clone();
+        let server = server.clone();
+        let pending = pending.clone();
+        // Only the first request can be sure that no earlier (pipelined)
+        // response is still being written.
+        let first = requests.fetch_add(1, Ordering::Relaxed) == 0;
+        async move {
+            let origin = req.headers().get("origin").cloned();
+            let mut resp = match server.pre_route(&mut req) {
+                Some(resp) => resp,
+                None => {
+                    if let Some(hints) = server.early_hints(&req).filter(|_| first) {
+                        pending.lock().unwrap().extend_from_slice(&hints);
+                    }
+                    warp_service.call(req).await?
+                }
+            };
+            server.post_route(origin.as_ref(), &mut resp);
+            server.log(log_info, &resp);
+            Ok::<_, Infallible>(resp)
+        }
+    });
+    let conn = http.serve_connection(io, service).with_upgrades();
+    tokio::pin!(conn);
+    // When shutting down, finish the current request and then close.
+    let res = tokio::select! {
+        res = conn.as_mut() => res,
+        _ = shutdown::started() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(e) = res {
+        log::debug!("{}: {}", remote_addr, e);
+    }
+}
+
+// Writes a queued informational (103 Early Hints) response before
+// whatever hyper writes next, which is the head of the final response.
+// HTTP/1.1 only: hyper does not support sending 1xx responses itself.
+// That is only true if no other response is being written, so hints are
+// only queued for the first request of a connection.
+struct HintWriter<T> {
+    io: T,
+    pending: Arc<Mutex<Vec<u8>>>,
+}
+
+impl<T: AsyncWrite + Unpin> HintWriter<T> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pending = self.pending.lock().unwrap();
+        while !pending.is_empty() {
+            match Pin::new(&mut self.io).poll_write(cx, &pending[..]) {
+                Poll::Ready(Ok(n)) => drop(pending.drain(..n)),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for HintWriter<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for HintWriter<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures::ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
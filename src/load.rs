@@ -0,0 +1,111 @@
+//!
+//! Load shedding: watch the CPU and NIC utilization of the host.
+//!
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::time::{Duration, Instant};
+
+use crate::LoadShedding;
+
+// How often we sample.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Shared overload state.
+pub struct LoadMonitor {
+    overloaded: AtomicBool,
+}
+
+impl LoadMonitor {
+    pub fn new() -> LoadMonitor {
+        LoadMonitor {
+            overloaded: AtomicBool::new(false),
+        }
+    }
+
+    pub fn overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+}
+
+// Busy and total jiffies from the first line of /proc/stat.
+fn cpu_times() -> Option<(u64, u64)> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let line = stat.lines().next()?;
+    let fields: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|f| f.parse().ok())
+        .collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    // user nice system idle iowait irq softirq steal ...
+    let total: u64 = fields.iter().take(8).sum();
+    let idle = fields[3] + fields.get(4).cloned().unwrap_or(0);
+    Some((total - idle, total))
+}
+
+// Bytes sent on an interface.
+fn tx_bytes(interface: &str) -> Option<u64> {
+    let path = format!("/sys/class/net/{}/statistics/tx_bytes", interface);
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Sample the CPU and NIC utilization, and update the monitor. Never returns.
+pub async fn run(config: LoadShedding, monitor: Arc<LoadMonitor>) {
+    let mut cpu = cpu_times();
+    let mut tx = config.interface.as_ref().and_then(|i| tx_bytes(i));
+    let mut last = Instant::now();
+
+    if config.max_cpu.is_some() && cpu.is_none() {
+        log::warn!("load-shedding: cannot read CPU usage from /proc/stat");
+    }
+    if let (Some(interface), None) = (config.interface.as_ref(), tx) {
+        log::warn!("load-shedding: cannot read statistics for {}", interface);
+    }
+
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let elapsed = now.duration_since(last).as_secs_f64();
+        last = now;
+        let mut overloaded = false;
+
+        // CPU usage in percent.
+        let cpu2 = cpu_times();
+        if let (Some(max_cpu), Some((busy1, total1)), Some((busy2, total2))) =
+            (config.max_cpu, cpu, cpu2)
+        {
+            if total2 > total1 {
+                let pct = 100 * (busy2 - busy1) / (total2 - total1);
+                if pct >= max_cpu as u64 {
+                    overloaded = true;
+                }
+            }
+        }
+        cpu = cpu2;
+
+        // Outgoing bandwidth in bytes/sec.
+        let tx2 = config.interface.as_ref().and_then(|i| tx_bytes(i));
+        if let (Some(max_bw), Some(tx1), Some(tx2)) = (config.max_bandwidth, tx, tx2) {
+            if elapsed > 0.0 && tx2 >= tx1 {
+                let rate = (tx2 - tx1) as f64 / elapsed;
+                if rate >= max_bw as f64 {
+                    overloaded = true;
+                }
+            }
+        }
+        tx = tx2;
+
+        if monitor.overloaded.swap(overloaded, Ordering::Relaxed) != overloaded {
+            if overloaded {
+                log::warn!("load-shedding: server overloaded, limiting new requests");
+            } else {
+                log::info!("load-shedding: load back to normal");
+            }
+        }
+    }
+}
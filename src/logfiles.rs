@@ -0,0 +1,142 @@
+//!
+//! Housekeeping for rotated access log files.
+//!
+//! Rotated files live next to the access log, and are named after it,
+//! like `access.log.1` or `access.log.2021-05-01`. They are gzipped, and
+//! old ones are removed, in a background task.
+//!
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio::task;
+use tokio::time::Duration;
+
+use crate::LogRetention;
+
+// How often we look at the rotated files.
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+// Files that were written to recently might not be complete yet.
+const MIN_AGE: Duration = Duration::from_secs(60);
+
+struct LogFile {
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+}
+
+// Find the rotated log files, newest first.
+fn rotated_files(access_log: &Path) -> io::Result<Vec<LogFile>> {
+    let dir = match access_log.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match access_log.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Ok(Vec::new()),
+    };
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with(&prefix) || name.ends_with(".tmp") {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        if !meta.is_file() {
+            continue;
+        }
+        files.push(LogFile {
+            path: entry.path(),
+            modified: meta.modified()?,
+            size: meta.len(),
+        });
+    }
+    files.sort_by_key(|f| Reverse(f.modified));
+    Ok(files)
+}
+
+// Gzip a file, and remove the original.
+fn compress(path: &Path) -> io::Result<PathBuf> {
+    let mut gz = path.as_os_str().to_owned();
+    gz.push(".gz");
+    let gz = PathBuf::from(gz);
+    let mut tmp = gz.clone().into_os_string();
+    tmp.push(".tmp");
+
+    let mut input = fs::File::open(path)?;
+    let output = fs::File::create(&tmp)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    let res = io::copy(&mut input, &mut encoder)
+        .and_then(|_| encoder.finish())
+        .and_then(|f| f.sync_all())
+        .and_then(|_| fs::rename(&tmp, &gz));
+    if let Err(e) = res {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::remove_file(path)?;
+    Ok(gz)
+}
+
+/// Compress rotated files, then enforce the retention policy.
+pub fn cleanup(access_log: &Path, config: &LogRetention) -> io::Result<()> {
+    let now = SystemTime::now();
+    let age = |f: &LogFile| now.duration_since(f.modified).unwrap_or_default();
+
+    if config.compress {
+        for file in rotated_files(access_log)? {
+            let is_gz = file.path.extension().map(|e| e == "gz").unwrap_or(false);
+            if !is_gz && age(&file) >= MIN_AGE {
+                if let Err(e) = compress(&file.path) {
+                    log::error!("{:?}: compress: {}", file.path, e);
+                }
+            }
+        }
+    }
+
+    let mut total = 0;
+    for (idx, file) in rotated_files(access_log)?.into_iter().enumerate() {
+        total += file.size;
+        let too_many = config.max_files.map(|m| idx >= m).unwrap_or(false);
+        let too_old = config
+            .max_age
+            .map(|m| age(&file) > Duration::from_secs(m * 86400))
+            .unwrap_or(false);
+        let too_big = config.max_size.map(|m| total > m).unwrap_or(false);
+        if too_many || too_old || too_big {
+            log::info!("removing old log file {:?}", file.path);
+            if let Err(e) = fs::remove_file(&file.path) {
+                log::error!("{:?}: {}", file.path, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove all but the newest `keep` rotated files.
+pub fn prune(access_log: &Path, keep: usize) -> io::Result<()> {
+    for file in rotated_files(access_log)?.into_iter().skip(keep) {
+        log::info!("removing old log file {:?}", file.path);
+        fs::remove_file(&file.path)?;
+    }
+    Ok(())
+}
+
+/// Run `cleanup` periodically. Never returns.
+pub async fn run(access_log: PathBuf, config: LogRetention) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (access_log, config) = (access_log.clone(), config.clone());
+        let res = task::spawn_blocking(move || cleanup(&access_log, &config)).await;
+        if let Ok(Err(e)) = res {
+            log::error!("log retention: {}", e);
+        }
+    }
+}
@@ -1,32 +1,245 @@
-use std::fs;
-use std::io::Write;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use chrono::{offset::Local, DateTime};
+use chrono::offset::{Local, Utc};
+use chrono::DateTime;
+use chrono_tz::Tz;
 use hyper::body::Body;
+use serde::de;
 use tokio_stream::Stream;
 use warp::reply::Response as HyperResponse;
 use warp::Filter;
 
-use crate::remoteip;
+use crate::listener::{self, ConnInfo};
+use crate::logsink::{self, Destination, Sink};
+use crate::prefork;
+use crate::remoteip::{self, Trusted};
+use crate::Config;
 
 /// A LogInfo keeps the same kind of info as a warp::log::Info, but it
 /// also keeps a byte counter, and can log-on-drop, so it is possible
 /// to log the amount of bytes transfered for a streaming body.
 pub struct LogInfo {
     data: Option<LogInfoData>,
-    access_log: Option<Arc<Mutex<String>>>,
-    do_xff: bool,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+/// Format of the access log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    // apache-like, the default.
+    Apache,
+    // Common Log Format.
+    Common,
+    // Combined Log Format.
+    Combined,
+    // One JSON object per line.
+    Json,
+    // W3C Extended Log File Format.
+    W3c,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "apache" => Ok(LogFormat::Apache),
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            "w3c" => Ok(LogFormat::W3c),
+            _ => Err(format!(
+                "{}: unknown log format (apache, common, combined, json, w3c)",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for LogFormat {
+    fn deserialize<D>(deserializer: D) -> Result<LogFormat, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s: String = de::Deserialize::deserialize(deserializer)?;
+        s.parse::<LogFormat>().map_err(de::Error::custom)
+    }
+}
+
+/// Timezone of the timestamps in the access log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogTimezone {
+    Local,
+    Tz(Tz),
+}
+
+impl LogTimezone {
+    /// Current time, formatted.
+    pub fn now(&self, fmt: &str) -> String {
+        self.format(Utc::now(), fmt)
+    }
+
+    /// A time, formatted in this timezone.
+    pub fn format(&self, time: DateTime<Utc>, fmt: &str) -> String {
+        match self {
+            LogTimezone::Local => time.with_timezone(&Local).format(fmt).to_string(),
+            LogTimezone::Tz(tz) => time.with_timezone(tz).format(fmt).to_string(),
+        }
+    }
+}
+
+impl FromStr for LogTimezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogTimezone, String> {
+        match s {
+            "local" => Ok(LogTimezone::Local),
+            "utc" | "UTC" => Ok(LogTimezone::Tz(Tz::UTC)),
+            _ => s.parse::<Tz>().map(LogTimezone::Tz),
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for LogTimezone {
+    fn deserialize<D>(deserializer: D) -> Result<LogTimezone, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s: String = de::Deserialize::deserialize(deserializer)?;
+        s.parse::<LogTimezone>().map_err(de::Error::custom)
+    }
+}
+
+// How long flush() waits for the writer.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Fields of the W3C format.
+const W3C_FIELDS: &str = "date time c-ip cs-method cs-uri-stem cs-version sc-status sc-bytes \
+                          time-taken cs(User-Agent) cs(Referer)";
+
+/// The access log file, and how to write it.
+pub struct AccessLog {
+    writer: mpsc::Sender<Message>,
+    format: LogFormat,
+    timezone: LogTimezone,
+    trusted: Trusted,
+    tcp_info: bool,
+}
+
+impl AccessLog {
+    pub fn new(config: &Config) -> Option<Arc<AccessLog>> {
+        let dest = Destination::parse(config.access_log.as_ref()?);
+        let format = config.log_format.unwrap_or(LogFormat::Apache);
+        let timezone = config.log_timezone.unwrap_or(LogTimezone::Local);
+
+        // In prefork mode, only the first worker rotates the file.
+        let rotation = config
+            .log_rotation
+            .clone()
+            .filter(|_| prefork::worker_id().unwrap_or(0) == 0);
+        let sink = logsink::sink(dest, format, timezone, rotation);
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name(String::from("access-log"))
+            .spawn(move || writer(sink, rx))
+            .ok()?;
+
+        Some(Arc::new(AccessLog {
+            writer: tx,
+            format,
+            timezone,
+            trusted: Trusted::new(config),
+            tcp_info: config.tcp_info,
+        }))
+    }
+
+    /// Reopen the file (or socket), e.g. after logrotate.
+    pub fn reopen(&self) {
+        let _ = self.writer.send(Message::Reopen);
+    }
+
+    /// Wait until the lines that were logged so far are written.
+    pub fn flush(&self) {
+        let (tx, rx) = mpsc::channel();
+        if self.writer.send(Message::Flush(tx)).is_ok() {
+            let _ = rx.recv_timeout(FLUSH_TIMEOUT);
+        }
+    }
+
+    fn write(&self, line: String) {
+        let _ = self.writer.send(Message::Line(line));
+    }
+}
+
+enum Message {
+    Line(String),
+    Reopen,
+    Flush(mpsc::Sender<()>),
+}
+
+// Write the log lines to the sink, in a thread of its own.
+fn writer(mut sink: Box<dyn Sink>, rx: mpsc::Receiver<Message>) {
+    while let Ok(msg) = rx.recv() {
+        match msg {
+            Message::Line(line) => sink.write(&line),
+            Message::Reopen => sink.reopen(),
+            Message::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// The directives at the start of a W3C log file.
+pub fn w3c_header() -> String {
+    format!(
+        "#Software: speedtest-fileserver-rs {}\n#Version: 1.0\n#Date: {}\n#Fields: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        Utc::now().format("%Y-%m-%d %H:%M:%S"),
+        W3C_FIELDS,
+    )
+}
+
+/// Marker in the extensions of a response that logs itself when done.
+#[derive(Clone, Copy)]
+pub struct Streamed;
+
+/// Number of bytes received from the client, in the extensions of a
+/// response to an upload. Logged instead of the length of the response.
+#[derive(Clone, Copy)]
+pub struct Received(pub u64);
+
+/// How a streamed transfer ended. The stream sets it when it cuts off
+/// the transfer. Otherwise it is "completed", or "aborted" if the client
+/// went away first.
+#[derive(Clone, Default)]
+pub struct EndReason(Arc<Mutex<Option<&'static str>>>);
+
+impl EndReason {
+    pub fn set(&self, reason: &'static str) {
+        self.0.lock().unwrap().get_or_insert(reason);
+    }
+
+    fn get(&self) -> Option<&'static str> {
+        *self.0.lock().unwrap()
+    }
 }
 
 #[derive(Clone)]
 struct LogInfoData {
     start: Instant,
+    // When the request came in, for the timestamp.
+    time: DateTime<Utc>,
+    // A download or upload, for which the throughput is logged.
+    transfer: bool,
     remote_addr: Option<SocketAddr>,
+    conn: Option<Arc<ConnInfo>>,
     method: http::Method,
     status: http::StatusCode,
     path: String,
@@ -37,11 +250,12 @@ struct LogInfoData {
     xff: Option<String>,
     xri: Option<String>,
     fwd: Option<String>,
+    end: Option<EndReason>,
 }
 
 impl LogInfo {
     pub fn new() -> impl Filter<Extract = (LogInfo,), Error = warp::reject::Rejection> + Copy {
-        warp::addr::remote()
+        listener::conn_info()
             .and(warp::method())
             .and(warp::path::full())
             .and(warp::header::optional::<String>("referer"))
@@ -49,88 +263,153 @@ impl LogInfo {
             .and(warp::header::optional::<String>("x-forwarded-for"))
             .and(warp::header::optional::<String>("x-real-ip"))
             .and(warp::header::optional::<String>("forwarded"))
+            .and(warp::ext::optional::<http::Version>())
             .map(
-                |addr: Option<SocketAddr>,
+                |conn: Option<Arc<ConnInfo>>,
                  method: http::Method,
                  path: warp::path::FullPath,
                  referer: Option<String>,
                  agent: Option<String>,
                  xff: Option<String>,
                  xri: Option<String>,
-                 fwd: Option<String>| {
+                 fwd: Option<String>,
+                 version: Option<http::Version>| {
                     let data = LogInfoData {
                         start: Instant::now(),
-                        remote_addr: addr,
+                        time: Utc::now(),
+                        transfer: false,
+                        remote_addr: conn.as_ref().map(|c| c.remote_addr),
+                        conn,
                         method,
                         status: http::StatusCode::OK,
                         path: path.as_str().to_string(),
-                        version: http::Version::HTTP_11,
+                        version: version.unwrap_or(http::Version::HTTP_11),
                         length: 0,
                         referer,
                         agent,
                         xff,
                         xri,
                         fwd,
+                        end: None,
                     };
                     LogInfo {
                         data: Some(data),
                         access_log: None,
-                        do_xff: false,
                     }
                 },
             )
     }
 
-    // Turn a warp::log::Info into a LogInfo.
-    #[allow(dead_code)]
-    pub fn from_warp_log_info(
-        info: warp::log::Info,
-        access_log: Option<Arc<Mutex<String>>>,
-        do_xff: bool,
-    ) -> LogInfo {
-        let headers = info.request_headers();
+    /// Build a LogInfo from a request. The status is filled in later.
+    pub fn from_request<B>(req: &http::Request<B>) -> LogInfo {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|s| s.to_string())
+        };
+        let conn = req.extensions().get::<Arc<ConnInfo>>().cloned();
         let data = LogInfoData {
             start: Instant::now(),
-            remote_addr: info.remote_addr(),
-            method: info.method().clone(),
-            status: info.status(),
-            path: info.path().to_string(),
-            version: info.version(),
+            time: Utc::now(),
+            transfer: false,
+            remote_addr: conn.as_ref().map(|c| c.remote_addr),
+            conn,
+            method: req.method().clone(),
+            status: http::StatusCode::OK,
+            path: req.uri().path().to_string(),
+            version: req.version(),
             length: 0,
-            referer: info.referer().map(|s| s.to_string()),
-            agent: info.user_agent().map(|s| s.to_string()),
-            xff: headers
-                .get("x-forwarded-for")
-                .map(|v| v.to_str().ok())
-                .flatten()
-                .map(|s| s.to_string()),
-            xri: headers
-                .get("x-real-ip")
-                .map(|v| v.to_str().ok())
-                .flatten()
-                .map(|s| s.to_string()),
-            fwd: headers
-                .get("forwarded")
-                .map(|v| v.to_str().ok())
-                .flatten()
-                .map(|s| s.to_string()),
+            referer: header("referer"),
+            agent: header("user-agent"),
+            xff: header("x-forwarded-for"),
+            xri: header("x-real-ip"),
+            fwd: header("forwarded"),
+            end: None,
         };
         LogInfo {
-            access_log,
-            do_xff,
             data: Some(data),
+            access_log: None,
+        }
+    }
+
+    /// A LogInfo for a command on a websocket, logged as a request for
+    /// `<path>/<command>`, like /ws/download/100MB.
+    pub fn command(&self, command: &str) -> LogInfo {
+        let data = self.data.clone().map(|d| LogInfoData {
+            start: Instant::now(),
+            time: Utc::now(),
+            transfer: false,
+            status: http::StatusCode::OK,
+            path: format!("{}/{}", d.path.trim_end_matches('/'), command),
+            length: 0,
+            end: None,
+            ..d
+        });
+        LogInfo {
+            data,
+            access_log: None,
+        }
+    }
+
+    /// Set the status of the response.
+    pub fn set_status(&mut self, status: http::StatusCode) {
+        if let Some(data) = self.data.as_mut() {
+            data.status = status;
+        }
+    }
+
+    /// Set the number of bytes transferred (received, for an upload).
+    pub fn set_length(&mut self, length: u64) {
+        if let Some(data) = self.data.as_mut() {
+            data.length = length;
+            data.transfer = true;
+        }
+    }
+
+    /// Log how the transfer ended, as set through the returned EndReason.
+    pub fn end_reason(&mut self) -> EndReason {
+        let end = EndReason::default();
+        if let Some(data) = self.data.as_mut() {
+            data.end = Some(end.clone());
         }
+        end
+    }
+
+    /// The HTTP version of the request.
+    pub fn version(&self) -> http::Version {
+        self.data
+            .as_ref()
+            .map(|d| d.version)
+            .unwrap_or(http::Version::HTTP_11)
+    }
+
+    /// The connection the request came in on.
+    pub fn conn(&self) -> Option<Arc<ConnInfo>> {
+        self.data.as_ref().and_then(|d| d.conn.clone())
+    }
+
+    /// The address of the client, taking X-Forwarded-For etc into account.
+    pub fn remote_ip(&self, trusted: &Trusted) -> Option<IpAddr> {
+        let data = self.data.as_ref()?;
+        let addr = remoteip::parse(
+            data.remote_addr,
+            trusted,
+            data.xff.as_ref(),
+            data.xri.as_ref(),
+            data.fwd.as_ref(),
+        );
+        addr.map(|a| a.ip())
     }
 
     /// Log configuration. Call this before wrapping the response.
-    pub fn log_on_drop(&mut self, access_log: Option<Arc<Mutex<String>>>, do_xff: bool) {
+    pub fn log_on_drop(&mut self, access_log: Option<Arc<AccessLog>>) {
         self.access_log = access_log;
-        self.do_xff = do_xff;
     }
 
     /// Wrap the response so we can count the number of bytes transferred and then log.
     pub fn wrap<T, E>(
-        self,
+        mut self,
         builder: http::response::Builder,
         strm: T,
     ) -> http::Result<HyperResponse>
@@ -138,10 +417,14 @@ impl LogInfo {
         T: Stream<Item = Result<bytes::Bytes, E>> + Send + Unpin + 'static,
         E: std::error::Error + Send + Sync + 'static,
     {
+        let builder = builder.extension(Streamed);
         if self.access_log.is_none() {
             return builder.body(Body::wrap_stream(strm));
         }
 
+        if let Some(data) = self.data.as_mut() {
+            data.transfer = true;
+        }
         let w = LogCounter {
             strm,
             log_info: self,
@@ -157,18 +440,10 @@ impl LogInfo {
             _ => return,
         };
 
-        // open logfile.
-        let access_log = access_log.lock().unwrap();
-        let mut options = fs::OpenOptions::new();
-        let mut file = match options.create(true).append(true).open(access_log.as_str()) {
-            Ok(file) => file,
-            Err(_) => return,
-        };
-
         // calculate client address.
         let addr = remoteip::parse(
             data.remote_addr,
-            self.do_xff,
+            &access_log.trusted,
             data.xff.as_ref(),
             data.xri.as_ref(),
             data.fwd.as_ref(),
@@ -182,9 +457,6 @@ impl LogInfo {
             &addr
         };
 
-        let now: DateTime<Local> = Local::now();
-        let timestamp = now.format("%d/%b/%Y:%H:%M:%S %z");
-
         let referer = data.referer.as_ref().map(|s| s.as_str()).unwrap_or("");
         let agent = data.agent.as_ref().map(|s| s.as_str()).unwrap_or("");
         let length = if data.length == 0 {
@@ -194,23 +466,126 @@ impl LogInfo {
         };
 
         let elapsed_ms = data.start.elapsed().as_millis() as f64;
+        // Throughput of a download or upload, in Mbit/s.
+        let mbps = if data.transfer && data.length > 0 && elapsed_ms > 0f64 {
+            Some((data.length * 8) as f64 / (elapsed_ms * 1000f64))
+        } else {
+            None
+        };
+        let end = data.end.as_ref().map(|e| e.get().unwrap_or("aborted"));
 
-        // log format, apache like:
-        // remote - - [date] "METHOD path version" status length "referer" "agent"
-        let _ = writeln!(
-            file,
-            "{remote} - - [{date}] \"{method} {path} {version:?}\" {status} {length} \"{referer}\" \"{agent}\" {elapsed:.03}s",
-            remote = addr,
-            date = timestamp,
-            method = data.method,
-            path = data.path,
-            version = data.version,
-            status = data.status.as_u16(),
-            length = length,
-            referer = referer,
-            agent = agent,
-            elapsed = elapsed_ms / 1000f64,
-        );
+        // The CN of the client certificate goes in the user field, which
+        // has the same rules as a W3C field.
+        let client_cn = data.conn.as_ref().and_then(|conn| conn.client_cn());
+        let user = w3c_field(client_cn.unwrap_or(""));
+
+        // TCP statistics, read when the transfer is done.
+        let tcp_info = data
+            .conn
+            .as_ref()
+            .filter(|_| access_log.tcp_info)
+            .and_then(|conn| conn.tcp_info());
+
+        match access_log.format {
+            LogFormat::Common | LogFormat::Combined => {
+                let timestamp = access_log
+                    .timezone
+                    .format(data.time, "%d/%b/%Y:%H:%M:%S %z");
+
+                // remote - user [date] "METHOD path version" status length
+                let mut line = format!(
+                    "{remote} - {user} [{date}] \"{method} {path} {version:?}\" {status} {length}",
+                    remote = addr,
+                    user = user,
+                    date = timestamp,
+                    method = data.method,
+                    path = data.path,
+                    version = data.version,
+                    status = data.status.as_u16(),
+                    length = length,
+                );
+                // .. "referer" "agent"
+                if access_log.format == LogFormat::Combined {
+                    line.push_str(&format!(" \"{}\" \"{}\"", referer, agent));
+                }
+                access_log.write(line);
+            }
+            LogFormat::Json => {
+                let line = serde_json::json!({
+                    "time": access_log.timezone.format(data.time, "%Y-%m-%dT%H:%M:%S%.3f%:z"),
+                    "remote": addr,
+                    "client_cn": client_cn,
+                    "method": data.method.as_str(),
+                    "path": data.path,
+                    "version": format!("{:?}", data.version),
+                    "status": data.status.as_u16(),
+                    "bytes": data.length,
+                    "duration": elapsed_ms / 1000f64,
+                    "duration_ms": elapsed_ms as u64,
+                    "mbps": mbps,
+                    "end": end,
+                    "referer": data.referer,
+                    "agent": data.agent,
+                    "tcp_info": tcp_info,
+                });
+                access_log.write(line.to_string());
+            }
+            LogFormat::Apache => {
+                let tcp_info = tcp_info.map(|i| format!(" {}", i)).unwrap_or_default();
+                let end = end.map(|e| format!(" end={}", e)).unwrap_or_default();
+                let mbps = mbps
+                    .map(|m| format!(" speed={:.2}Mbit/s", m))
+                    .unwrap_or_default();
+                let timestamp = access_log
+                    .timezone
+                    .format(data.time, "%d/%b/%Y:%H:%M:%S %z");
+
+                // log format, apache like:
+                // remote - user [date] "METHOD path version" status length "referer" "agent"
+                access_log.write(format!(
+                    "{remote} - {user} [{date}] \"{method} {path} {version:?}\" {status} {length} \"{referer}\" \"{agent}\" {elapsed:.03}s{mbps}{end}{tcp_info}",
+                    remote = addr,
+                    user = user,
+                    date = timestamp,
+                    method = data.method,
+                    path = data.path,
+                    version = data.version,
+                    status = data.status.as_u16(),
+                    length = length,
+                    referer = referer,
+                    agent = agent,
+                    elapsed = elapsed_ms / 1000f64,
+                    mbps = mbps,
+                    end = end,
+                    tcp_info = tcp_info,
+                ));
+            }
+            LogFormat::W3c => {
+                // W3C timestamps are always UTC.
+                access_log.write(format!(
+                    "{date} {remote} {method} {path} {version:?} {status} {length} {elapsed:.03} {agent} {referer}",
+                    date = data.time.format("%Y-%m-%d %H:%M:%S"),
+                    remote = addr,
+                    method = data.method,
+                    path = w3c_field(&data.path),
+                    version = data.version,
+                    status = data.status.as_u16(),
+                    length = data.length,
+                    elapsed = elapsed_ms / 1000f64,
+                    agent = w3c_field(agent),
+                    referer = w3c_field(referer),
+                ));
+            }
+        }
+    }
+}
+
+// Fields in the W3C format cannot contain spaces, and cannot be empty.
+fn w3c_field(s: &str) -> String {
+    if s.is_empty() {
+        String::from("-")
+    } else {
+        s.replace(' ', "+")
     }
 }
 
@@ -234,6 +609,12 @@ where
                 }
                 Poll::Ready(Some(Ok(item)))
             }
+            Poll::Ready(None) => {
+                if let Some(end) = self.log_info.data.as_ref().and_then(|d| d.end.as_ref()) {
+                    end.set("completed");
+                }
+                Poll::Ready(None)
+            }
             other => other,
         }
     }
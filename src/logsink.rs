@@ -0,0 +1,340 @@
+//!
+//! Where the access log goes: a file, syslog, or the systemd journal.
+//!
+use std::fs;
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use chrono::offset::Utc;
+
+use crate::logfiles;
+use crate::logger::{self, LogFormat, LogTimezone};
+use crate::report;
+use crate::LogRotation;
+
+// How often the file sink checks if the file was moved away.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Local syslog and journald sockets.
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// Name we log under.
+const IDENT: &str = "speedtest-fileserver";
+
+/// A destination for log lines.
+pub trait Sink: Send {
+    /// Write one line (without newline).
+    fn write(&mut self, line: &str);
+    /// Reopen the destination, on SIGHUP.
+    fn reopen(&mut self) {}
+}
+
+/// The value of the access-log setting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    File(PathBuf),
+    // Local syslog, or syslog over UDP to host:port.
+    Syslog(Option<String>),
+    Journald,
+}
+
+impl Destination {
+    pub fn parse(s: &str) -> Destination {
+        match s {
+            "syslog" => Destination::Syslog(None),
+            "journald" => Destination::Journald,
+            _ => match s.strip_prefix("syslog://") {
+                Some(addr) => Destination::Syslog(Some(addr.to_string())),
+                None => Destination::File(PathBuf::from(s)),
+            },
+        }
+    }
+}
+
+/// Create the sink for a destination. Rotation only applies to files.
+pub fn sink(
+    dest: Destination,
+    format: LogFormat,
+    timezone: LogTimezone,
+    rotation: Option<LogRotation>,
+) -> Box<dyn Sink> {
+    match dest {
+        Destination::File(path) => Box::new(FileSink::new(path, format, timezone, rotation)),
+        Destination::Syslog(addr) => Box::new(SyslogSink::new(addr)),
+        Destination::Journald => Box::new(JournaldSink::new()),
+    }
+}
+
+// Appends to a file. Reopens the file on request, or if it was moved
+// away, and rotates it if configured.
+struct FileSink {
+    path: PathBuf,
+    format: LogFormat,
+    timezone: LogTimezone,
+    rotation: Option<LogRotation>,
+    file: Option<fs::File>,
+    // Identity of the open file, size, and the day it was opened.
+    id: (u64, u64),
+    size: u64,
+    day: String,
+    checked: Instant,
+}
+
+impl FileSink {
+    fn new(
+        path: PathBuf,
+        format: LogFormat,
+        timezone: LogTimezone,
+        rotation: Option<LogRotation>,
+    ) -> FileSink {
+        FileSink {
+            path,
+            format,
+            timezone,
+            rotation,
+            file: None,
+            id: (0, 0),
+            size: 0,
+            day: String::new(),
+            checked: Instant::now(),
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let meta = file.metadata()?;
+        self.id = (meta.dev(), meta.ino());
+        self.size = meta.len();
+        self.day = self.timezone.now("%Y-%m-%d");
+        self.file = Some(file);
+        Ok(())
+    }
+
+    // Once a second, see if the file was moved away (by logrotate).
+    fn check(&mut self) {
+        if self.file.is_none() || self.checked.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+        let moved = match fs::metadata(&self.path) {
+            Ok(meta) => (meta.dev(), meta.ino()) != self.id,
+            Err(_) => true,
+        };
+        if moved {
+            self.file = None;
+        }
+    }
+
+    fn must_rotate(&self) -> bool {
+        let rotation = match self.rotation.as_ref() {
+            Some(rotation) => rotation,
+            None => return false,
+        };
+        let too_big = rotation.max_size.map(|m| self.size >= m).unwrap_or(false);
+        too_big || (rotation.daily && self.timezone.now("%Y-%m-%d") != self.day)
+    }
+
+    // Move the file to <path>.<timestamp>, and remove old ones.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let stamp = self.timezone.now("%Y%m%d-%H%M%S");
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", stamp));
+        let mut target = PathBuf::from(&rotated);
+        let mut n = 1;
+        while target.exists() {
+            let mut name = rotated.clone();
+            name.push(format!(".{}", n));
+            target = PathBuf::from(name);
+            n += 1;
+        }
+        fs::rename(&self.path, &target)?;
+        if let Some(keep) = self.rotation.as_ref().and_then(|r| r.keep) {
+            logfiles::prune(&self.path, keep)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, line: &str) {
+        self.check();
+        if self.file.is_none() {
+            if let Err(e) = self.open() {
+                log::error!("{:?}: {}", self.path, e);
+                return;
+            }
+        }
+
+        // A new W3C file starts with the directives.
+        let mut data = String::new();
+        if self.size == 0 && self.format == LogFormat::W3c {
+            data.push_str(&logger::w3c_header());
+        }
+        data.push_str(line);
+        data.push('\n');
+
+        let file = self.file.as_mut().unwrap();
+        if file.write_all(data.as_bytes()).is_ok() {
+            self.size += data.len() as u64;
+        }
+
+        if self.must_rotate() {
+            if let Err(e) = self.rotate() {
+                log::error!("{:?}: rotate: {}", self.path, e);
+            }
+        }
+    }
+
+    fn reopen(&mut self) {
+        self.file = None;
+    }
+}
+
+enum SyslogSocket {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+// RFC 5424 syslog, to the local syslog daemon or over UDP.
+struct SyslogSink {
+    addr: Option<String>,
+    socket: Option<SyslogSocket>,
+    hostname: String,
+    pid: u32,
+    failed: bool,
+}
+
+impl SyslogSink {
+    fn new(addr: Option<String>) -> SyslogSink {
+        SyslogSink {
+            addr,
+            socket: None,
+            hostname: report::hostname(),
+            pid: std::process::id(),
+            failed: false,
+        }
+    }
+
+    fn connect(&self) -> io::Result<SyslogSocket> {
+        match self.addr.as_ref() {
+            Some(addr) => {
+                let socket = UdpSocket::bind("[::]:0").or_else(|_| UdpSocket::bind("0.0.0.0:0"))?;
+                socket.connect(addr.as_str())?;
+                Ok(SyslogSocket::Udp(socket))
+            }
+            None => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(SYSLOG_SOCKET)?;
+                Ok(SyslogSocket::Unix(socket))
+            }
+        }
+    }
+
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        if self.socket.is_none() {
+            self.socket = Some(self.connect()?);
+        }
+        let res = match self.socket.as_ref().unwrap() {
+            SyslogSocket::Unix(s) => s.send(msg),
+            SyslogSocket::Udp(s) => s.send(msg),
+        };
+        if res.is_err() {
+            // Reconnect next time, the syslog daemon might have restarted.
+            self.socket = None;
+        }
+        res.map(|_| ())
+    }
+}
+
+impl Sink for SyslogSink {
+    fn write(&mut self, line: &str) {
+        // <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG,
+        // facility daemon (3), severity info (6).
+        let msg = format!(
+            "<30>1 {} {} {} {} access - {}",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.6fZ"),
+            self.hostname,
+            IDENT,
+            self.pid,
+            line
+        );
+        match self.send(msg.as_bytes()) {
+            Ok(()) => self.failed = false,
+            Err(e) => {
+                if !self.failed {
+                    log::error!("access log: syslog: {}", e);
+                }
+                self.failed = true;
+            }
+        }
+    }
+
+    fn reopen(&mut self) {
+        self.socket = None;
+    }
+}
+
+// The native journald protocol.
+struct JournaldSink {
+    socket: Option<UnixDatagram>,
+    failed: bool,
+}
+
+impl JournaldSink {
+    fn new() -> JournaldSink {
+        JournaldSink {
+            socket: None,
+            failed: false,
+        }
+    }
+
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        if self.socket.is_none() {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNALD_SOCKET)?;
+            self.socket = Some(socket);
+        }
+        let res = self.socket.as_ref().unwrap().send(msg);
+        if res.is_err() {
+            self.socket = None;
+        }
+        res.map(|_| ())
+    }
+}
+
+impl Sink for JournaldSink {
+    fn write(&mut self, line: &str) {
+        let mut msg = format!("PRIORITY=6\nSYSLOG_IDENTIFIER={}\n", IDENT).into_bytes();
+        if line.contains('\n') {
+            // Binary form: name, newline, length, value.
+            msg.extend_from_slice(b"MESSAGE\n");
+            msg.extend_from_slice(&(line.len() as u64).to_le_bytes());
+            msg.extend_from_slice(line.as_bytes());
+            msg.push(b'\n');
+        } else {
+            msg.extend_from_slice(format!("MESSAGE={}\n", line).as_bytes());
+        }
+        match self.send(&msg) {
+            Ok(()) => self.failed = false,
+            Err(e) => {
+                if !self.failed {
+                    log::error!("access log: journald: {}", e);
+                }
+                self.failed = true;
+            }
+        }
+    }
+
+    fn reopen(&mut self) {
+        self.socket = None;
+    }
+}
@@ -1,22 +1,75 @@
 use std::convert::TryFrom;
 use std::net::{AddrParseError, IpAddr, SocketAddr};
-use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use futures::stream::{FuturesUnordered, StreamExt};
 use serde::Deserialize;
 use structopt::StructOpt;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task;
+use tokio_rustls::TlsAcceptor;
+use warp::{filters::BoxedFilter, Reply};
 
+use listener::SocketOptions;
+use logsink::Destination;
+
+mod accounting;
+mod acme;
+mod auth;
+mod check;
+mod cidr;
+mod clients;
+mod cors;
+mod discovery;
+mod genpool;
 mod lehmer64;
+mod listener;
+mod load;
+mod logfiles;
 mod logger;
+mod logsink;
+mod metrics;
+mod numa;
+mod openapi;
+mod policy;
+mod prefork;
+mod privdrop;
+mod proxy;
+mod randompool;
 mod randomstream;
 mod remoteip;
+mod report;
+mod results;
+mod rewrite;
+mod rolling;
 mod server;
+mod shutdown;
+mod stall;
+mod stats;
+mod systemd;
+mod tcpinfo;
 mod template;
+mod throttle;
+mod ticketer;
+mod tls;
+mod ws;
+mod xoshiro;
 
 const CONFIG_FILE: &'static str = "/etc/speedtest-fileserver.cfg";
 
+// Used when there is no config file, with the command line options
+// on top.
+const DEFAULT_CONFIG: &str = "
+http {
+    listen 127.0.0.1:3000, [::1]:3000;
+}
+index {
+    sizes 1MB, 10MB, 100MB, 1GB, 10GB;
+}
+";
+
 // Configuration file settings.
 #[derive(Clone, Deserialize, Debug)]
 pub struct Config {
@@ -33,6 +86,26 @@ pub struct Config {
     #[serde(rename = "access-log")]
     pub access_log: Option<String>,
 
+    // Format of the access log.
+    #[serde(rename = "log-format")]
+    pub log_format: Option<logger::LogFormat>,
+
+    // Timezone of the timestamps in the access log.
+    #[serde(rename = "log-timezone")]
+    pub log_timezone: Option<logger::LogTimezone>,
+
+    // What to do with rotated access log files.
+    #[serde(rename = "log-retention")]
+    pub log_retention: Option<LogRetention>,
+
+    // Rotate the access log by size and/or daily.
+    #[serde(rename = "log-rotation")]
+    pub log_rotation: Option<LogRotation>,
+
+    // Serve the files in this directory as well.
+    #[serde(rename = "data-dir")]
+    pub data_dir: Option<PathBuf>,
+
     // max file size.
     #[serde(
         default,
@@ -41,9 +114,402 @@ pub struct Config {
     )]
     pub max_file_size: Option<u64>,
 
-    // Use X-Forwarded-For/X-Real-Ip/Forwarded headers (unused for now).
+    // What downloads look like, unless ?pattern= is used.
+    #[serde(rename = "data-pattern")]
+    pub data_pattern: Option<randomstream::Pattern>,
+
+    // The generator of random data.
+    pub rng: Option<randomstream::RngKind>,
+
+    // Per-stream bandwidth limit, unless a policy sets one.
+    #[serde(default, rename = "rate-limit", deserialize_with = "deserialize_rate")]
+    pub rate_limit: Option<u64>,
+
+    // Bandwidth limit for all streams together.
+    #[serde(
+        default,
+        rename = "max-total-bandwidth",
+        deserialize_with = "deserialize_rate"
+    )]
+    pub max_total_bandwidth: Option<u64>,
+
+    // Use X-Forwarded-For/X-Real-Ip/Forwarded headers from any client.
     #[serde(rename = "use-xff-headers", default)]
     pub xff: bool,
+
+    // Or only from these proxies.
+    #[serde(rename = "trusted-proxies", default)]
+    pub trusted_proxies: Vec<cidr::Cidr>,
+
+    // Add TCP statistics (TCP_INFO) to the access log.
+    #[serde(rename = "log-tcp-info", default)]
+    pub tcp_info: bool,
+
+    // Keep the server-side results of transfers, for /result/<id>.
+    #[serde(rename = "transfer-results", default)]
+    pub transfer_results: bool,
+
+    // Requests with an "Early-Data: 1" header (set by a front-end proxy
+    // that accepted TLS early data) are only allowed for small
+    // endpoints, the rest gets a 425.
+    #[serde(rename = "early-data-header", default)]
+    pub early_data_header: bool,
+
+    // Behave like a TR-143 diagnostics server.
+    #[serde(rename = "tr143-mode", default)]
+    pub tr143: bool,
+
+    // A download is cut off if sending a chunk takes longer than this.
+    #[serde(rename = "send-timeout", default = "default_send_timeout")]
+    pub send_timeout: u64,
+
+    // Maximum time a download may take, in seconds.
+    #[serde(rename = "max-transfer-duration")]
+    pub max_transfer_duration: Option<u64>,
+
+    // Send "Connection: close" with every download, so that every
+    // download uses a new connection.
+    #[serde(rename = "close-connection", default)]
+    pub close_connection: bool,
+
+    // LibreSpeed compatible garbage.php, empty.php and getIP.php.
+    #[serde(default)]
+    pub librespeed: bool,
+
+    // WebSocket speedtest protocol on /ws.
+    #[serde(default)]
+    pub websocket: bool,
+
+    // Add an X-Server-Timestamp header to /empty responses.
+    #[serde(rename = "server-timestamp", default)]
+    pub server_timestamp: bool,
+
+    // Accept upload tests on /upload.
+    #[serde(default)]
+    pub upload: bool,
+
+    // Concurrent downloads per client (per /64 for IPv6).
+    #[serde(rename = "max-streams-per-ip")]
+    pub max_streams_per_ip: Option<usize>,
+
+    // Downloads per client per minute.
+    #[serde(rename = "max-requests-per-minute")]
+    pub max_requests_per_minute: Option<u32>,
+
+    // Bandwidth policies for networks and AS numbers.
+    #[serde(rename = "policy", default)]
+    pub policies: Vec<Policy>,
+
+    // Save traffic counters (per day, per size) in this file.
+    #[serde(rename = "accounting-file")]
+    pub accounting_file: Option<PathBuf>,
+
+    // Serve /stats.json.
+    #[serde(rename = "public-stats", default)]
+    pub public_stats: bool,
+
+    // Serve /stats, with the statistics of the last 15 minutes.
+    #[serde(rename = "live-stats", default)]
+    pub live_stats: bool,
+
+    // Prefix to AS number mapping (CAIDA pfx2as format).
+    #[serde(rename = "asn-database")]
+    pub asn_database: Option<PathBuf>,
+
+    // Push statistics to a central aggregator.
+    pub report: Option<Report>,
+
+    // Serve data from a shared pool of random data.
+    #[serde(rename = "random-pool")]
+    pub random_pool: Option<Pool>,
+
+    // Prometheus metrics on /metrics.
+    pub metrics: Option<Metrics>,
+
+    // Generate random data on this many dedicated threads.
+    #[serde(rename = "generator-threads")]
+    pub generator_threads: Option<usize>,
+
+    // Refuse large requests when the host is overloaded.
+    #[serde(rename = "load-shedding")]
+    pub load_shedding: Option<LoadShedding>,
+
+    // Rewrites and redirects of legacy paths.
+    #[serde(rename = "path", default)]
+    pub paths: Vec<PathMap>,
+
+    // CORS headers, for speedtest pages on other origins.
+    pub cors: Option<Cors>,
+
+    // Client certificates, bearer tokens or signed URLs.
+    pub auth: Option<Auth>,
+
+    // Contents of /.well-known/security.txt.
+    #[serde(rename = "security-txt")]
+    pub security_txt: Option<SecurityTxt>,
+
+    // Log when the server (not the network) slows down transfers.
+    #[serde(rename = "stall-detection")]
+    pub stall_detection: Option<StallDetection>,
+
+    // Pin the process to a NUMA node.
+    pub numa: Option<Numa>,
+
+    // Number of worker processes (prefork mode).
+    pub workers: Option<usize>,
+
+    // IPv6 listeners only accept IPv6 connections. A listener on just
+    // a port then gets separate IPv4 and IPv6 sockets.
+    #[serde(default)]
+    pub v6only: bool,
+
+    // Drop root privileges after binding the sockets.
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<PathBuf>,
+
+    // Exit if a listener has been down for this many seconds.
+    #[serde(
+        rename = "listener-down-timeout",
+        default = "default_listener_down_timeout"
+    )]
+    pub listener_down_timeout: u64,
+
+    // On SIGTERM, wait this many seconds for running transfers.
+    #[serde(rename = "drain-timeout", default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+fn default_listener_down_timeout() -> u64 {
+    60
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_send_timeout() -> u64 {
+    20
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Pool {
+    // Size of the pool.
+    #[serde(default = "default_pool_size", deserialize_with = "deserialize_size")]
+    pub size: Option<u64>,
+    // Regenerate the entire pool every this many seconds (0: never).
+    #[serde(default = "default_pool_refresh")]
+    pub refresh: u64,
+}
+
+fn default_pool_size() -> Option<u64> {
+    Some(64 * 1024 * 1024)
+}
+
+fn default_pool_refresh() -> u64 {
+    3600
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct LoadShedding {
+    // CPU usage in percent.
+    #[serde(rename = "max-cpu")]
+    pub max_cpu: Option<u32>,
+    // Network interface to watch.
+    pub interface: Option<String>,
+    // Outgoing bandwidth on that interface.
+    #[serde(
+        default,
+        rename = "max-bandwidth",
+        deserialize_with = "deserialize_rate"
+    )]
+    pub max_bandwidth: Option<u64>,
+    // Max file size while overloaded.
+    #[serde(
+        default = "default_shed_file_size",
+        rename = "max-file-size",
+        deserialize_with = "deserialize_size"
+    )]
+    pub max_file_size: Option<u64>,
+}
+
+fn default_shed_file_size() -> Option<u64> {
+    Some(10_000_000)
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct LogRetention {
+    // gzip rotated files.
+    #[serde(default)]
+    pub compress: bool,
+    // Keep at most this many rotated files ..
+    #[serde(rename = "max-files")]
+    pub max_files: Option<usize>,
+    // .. for at most this many days ..
+    #[serde(rename = "max-age")]
+    pub max_age: Option<u64>,
+    // .. taking up at most this much space.
+    #[serde(default, rename = "max-size", deserialize_with = "deserialize_size")]
+    pub max_size: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct LogRotation {
+    // Rotate when the file is this big ..
+    #[serde(default, rename = "max-size", deserialize_with = "deserialize_size")]
+    pub max_size: Option<u64>,
+    // .. and/or at midnight.
+    #[serde(default)]
+    pub daily: bool,
+    // Number of rotated files to keep.
+    pub keep: Option<usize>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Cors {
+    // Allowed origins, or "*" for any.
+    pub origins: Vec<String>,
+    // Allowed methods.
+    #[serde(default = "default_cors_methods")]
+    pub methods: Vec<String>,
+    // Allowed request headers. By default, the ones the browser asks for.
+    #[serde(default)]
+    pub headers: Vec<String>,
+    // How long browsers may cache a preflight response, in seconds.
+    #[serde(rename = "max-age")]
+    pub max_age: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Auth {
+    // Clients on the https listeners need a certificate signed by this CA.
+    #[serde(rename = "client-ca")]
+    pub client_ca: Option<PathBuf>,
+    // Bearer tokens that give access to downloads and uploads.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    // Secret for signed URLs.
+    #[serde(rename = "url-secret")]
+    pub url_secret: Option<String>,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST", "PUT"]
+        .iter()
+        .map(|m| m.to_string())
+        .collect()
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Numa {
+    // Either a NUMA node ..
+    pub node: Option<usize>,
+    // .. or the node of this network interface.
+    pub interface: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct StallDetection {
+    // Delays longer than this many milliseconds are stalls.
+    #[serde(default = "default_stall_threshold")]
+    pub threshold: u64,
+}
+
+fn default_stall_threshold() -> u64 {
+    50
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Report {
+    // URL to POST the report to.
+    pub url: String,
+    // Name of this instance, defaults to the hostname.
+    #[serde(rename = "instance-id")]
+    pub instance_id: Option<String>,
+    // Shared secret for the HMAC-SHA256 signature.
+    pub secret: Option<String>,
+    // Interval in seconds.
+    #[serde(default = "default_report_interval")]
+    pub interval: u64,
+}
+
+// RFC 9116 fields.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct SecurityTxt {
+    pub contact: Vec<String>,
+    pub expires: String,
+    #[serde(default)]
+    pub encryption: Vec<String>,
+    #[serde(default)]
+    pub acknowledgments: Vec<String>,
+    #[serde(rename = "preferred-languages", default)]
+    pub preferred_languages: Vec<String>,
+    #[serde(default)]
+    pub canonical: Vec<String>,
+    #[serde(default)]
+    pub policy: Vec<String>,
+    #[serde(default)]
+    pub hiring: Vec<String>,
+}
+
+impl SecurityTxt {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut add = |name: &str, values: &[String]| {
+            for v in values {
+                out.push_str(&format!("{}: {}\n", name, v));
+            }
+        };
+        add("Contact", &self.contact);
+        add("Expires", std::slice::from_ref(&self.expires));
+        add("Encryption", &self.encryption);
+        add("Acknowledgments", &self.acknowledgments);
+        if !self.preferred_languages.is_empty() {
+            add(
+                "Preferred-Languages",
+                &[self.preferred_languages.join(", ")],
+            );
+        }
+        add("Canonical", &self.canonical);
+        add("Policy", &self.policy);
+        add("Hiring", &self.hiring);
+        out
+    }
+}
+
+fn default_report_interval() -> u64 {
+    60
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Policy {
+    #[serde(rename = "__label__")]
+    pub name: String,
+    // Networks this policy applies to.
+    #[serde(default)]
+    pub networks: Vec<cidr::Cidr>,
+    // AS numbers this policy applies to.
+    #[serde(default)]
+    pub asn: Vec<u32>,
+    // Per-stream bandwidth limit.
+    #[serde(default, rename = "rate-limit", deserialize_with = "deserialize_rate")]
+    pub rate_limit: Option<u64>,
+    // Maximum bytes per client per day.
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub quota: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct PathMap {
+    #[serde(rename = "__label__")]
+    pub path: String,
+    // Serve this path instead.
+    pub rewrite: Option<String>,
+    // Or redirect to this URL ..
+    #[serde(default, deserialize_with = "deserialize_uri")]
+    pub redirect: Option<http::Uri>,
+    // .. with this status (default 301).
+    pub status: Option<u16>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -52,12 +518,63 @@ pub struct Index {
     pub sizes: Vec<String>,
     #[serde(default)]
     pub partials: Vec<String>,
+    // List every size in both decimal (MB) and binary (MiB) units.
+    #[serde(rename = "both-units", default)]
+    pub both_units: bool,
+    // Optional per-size settings.
+    #[serde(rename = "size", default)]
+    pub size_info: Vec<SizeInfo>,
+    // Assets the index page should preload (sent as Link headers).
+    #[serde(default)]
+    pub preload: Vec<String>,
+    // Also send them in a "103 Early Hints" response.
+    #[serde(rename = "early-hints", default)]
+    pub early_hints: bool,
+    // Directory with CSS, JS, images etc. for the template, served
+    // under /static/.
+    pub assets: Option<PathBuf>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
+pub struct SizeInfo {
+    #[serde(rename = "__label__")]
+    pub size: String,
+    // Description shown on the index page.
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Metrics {
+    // [addr:]port to serve the metrics on, instead of on the http
+    // and https listeners.
+    #[serde(default)]
+    pub listen: Vec<String>,
+}
+
+#[derive(Clone, Default, Deserialize, Debug)]
 pub struct Http {
     // [addr:]port to listen on.
     pub listen: Vec<String>,
+    // TCP congestion control.
+    #[serde(rename = "congestion-control")]
+    pub congestion_control: Option<String>,
+    #[serde(rename = "allowed-congestion-control", default)]
+    pub allowed_congestion_control: Vec<String>,
+    // Connections start with a PROXY protocol (v1 or v2) header.
+    #[serde(rename = "proxy-protocol", default)]
+    pub proxy_protocol: bool,
+    // Kernel socket buffer sizes (SO_SNDBUF, SO_RCVBUF).
+    #[serde(default, rename = "send-buffer", deserialize_with = "deserialize_size")]
+    pub send_buffer: Option<u64>,
+    #[serde(
+        default,
+        rename = "receive-buffer",
+        deserialize_with = "deserialize_size"
+    )]
+    pub receive_buffer: Option<u64>,
+    // Set TCP_NODELAY on the connections.
+    #[serde(default)]
+    pub nodelay: bool,
     #[serde(deserialize_with = "deserialize_uri", default)]
     pub redirect: Option<http::Uri>,
 }
@@ -66,27 +583,134 @@ pub struct Http {
 pub struct Https {
     // [addr:]port to listen on.
     pub listen: Vec<String>,
+    // TCP congestion control.
+    #[serde(rename = "congestion-control")]
+    pub congestion_control: Option<String>,
+    #[serde(rename = "allowed-congestion-control", default)]
+    pub allowed_congestion_control: Vec<String>,
+    // Connections start with a PROXY protocol (v1 or v2) header.
+    #[serde(rename = "proxy-protocol", default)]
+    pub proxy_protocol: bool,
+    // Kernel socket buffer sizes (SO_SNDBUF, SO_RCVBUF).
+    #[serde(default, rename = "send-buffer", deserialize_with = "deserialize_size")]
+    pub send_buffer: Option<u64>,
+    #[serde(
+        default,
+        rename = "receive-buffer",
+        deserialize_with = "deserialize_size"
+    )]
+    pub receive_buffer: Option<u64>,
+    // Set TCP_NODELAY on the connections.
+    #[serde(default)]
+    pub nodelay: bool,
 
     // TLS certificate chain file
-    pub chain: String,
+    pub chain: Option<String>,
 
     // TLS certificate key file
-    pub key: String,
+    pub key: Option<String>,
+
+    // Or get the certificate from an ACME CA.
+    pub acme: Option<Acme>,
+
+    // Enable session tickets, and rotate the key every this many seconds.
+    #[serde(rename = "ticket-key-rotation")]
+    pub ticket_key_rotation: Option<u64>,
+
+    // HTTP/2 settings.
+    pub http2: Option<Http2>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Http2 {
+    // Only offer HTTP/1.1.
+    #[serde(default)]
+    pub disable: bool,
+    // Flow control windows, the defaults of hyper are 1MiB and 1MiB.
+    #[serde(
+        rename = "initial-stream-window-size",
+        default,
+        deserialize_with = "deserialize_size"
+    )]
+    pub initial_stream_window_size: Option<u64>,
+    #[serde(
+        rename = "initial-connection-window-size",
+        default,
+        deserialize_with = "deserialize_size"
+    )]
+    pub initial_connection_window_size: Option<u64>,
+    // Grow the windows based on the measured bandwidth-delay product.
+    #[serde(rename = "adaptive-window", default)]
+    pub adaptive_window: bool,
+    #[serde(rename = "max-concurrent-streams")]
+    pub max_concurrent_streams: Option<u32>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Acme {
+    // Names to put in the certificate.
+    pub domains: Vec<String>,
+    // Contact email address for the CA.
+    #[serde(default)]
+    pub contact: Vec<String>,
+    // Account key, certificate and key are stored here.
+    #[serde(rename = "cache-dir")]
+    pub cache_dir: PathBuf,
+    // Directory URL of the CA.
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+}
+
+fn default_acme_directory() -> String {
+    acme::LETSENCRYPT.to_string()
+}
+
+impl Http {
+    fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            congestion_control: self.congestion_control.clone(),
+            allowed_congestion_control: self.allowed_congestion_control.clone(),
+            proxy_protocol: self.proxy_protocol,
+            send_buffer: self.send_buffer.map(|s| s as usize),
+            receive_buffer: self.receive_buffer.map(|s| s as usize),
+            nodelay: self.nodelay,
+            http2: None,
+        }
+    }
+}
+
+impl Https {
+    fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            congestion_control: self.congestion_control.clone(),
+            allowed_congestion_control: self.allowed_congestion_control.clone(),
+            proxy_protocol: self.proxy_protocol,
+            send_buffer: self.send_buffer.map(|s| s as usize),
+            receive_buffer: self.receive_buffer.map(|s| s as usize),
+            nodelay: self.nodelay,
+            http2: self.http2.clone(),
+        }
+    }
 }
 
 // Add a sockaddr to the list of listeners.
 //
-// If "addr" specifies just a port, we should add two sockaddrs: one for IPv4, one for IPv6.
-// However, right now warp doesn't know about `v6_only`, so for now just bind to
-// an IPv6 socket, which (at least on linux/freebsd) is dual-stack.
+// If "addr" specifies just a port, we bind to an IPv6 socket that is
+// dual-stack (listener::bind turns off IPV6_V6ONLY). With `v6only`,
+// we add two sockaddrs: one for IPv4, one for IPv6.
 //
-fn add_listener(addr: &str, listen: &mut Vec<(SocketAddr, String)>) -> Result<(), AddrParseError> {
+fn add_listener(
+    addr: &str,
+    v6only: bool,
+    listen: &mut Vec<(SocketAddr, String)>,
+) -> Result<(), AddrParseError> {
     if let Ok(port) = addr.parse::<u16>() {
-        /*
-        listen.push((
-            SocketAddr::new(IpAddr::V4(0u32.into()), port),
-            format!("*:{}", port),
-        ));*/
+        if v6only {
+            listen.push((
+                SocketAddr::new(IpAddr::V4(0u32.into()), port),
+                format!("*:{}", port),
+            ));
+        }
         listen.push((
             SocketAddr::new(IpAddr::V6(0u128.into()), port),
             format!("[::]:{}", port),
@@ -116,45 +740,113 @@ macro_rules! die {
 
 // add
 fn resolve_path(dir: &str, file: &str) -> PathBuf {
+    find_path(dir, file)
+        .map_err(|(p, e)| die!(std => "{:?}: {}", p, e))
+        .unwrap()
+}
+
+// A relative path that does not exist is looked up in `dir`.
+fn find_path(dir: &str, file: &str) -> Result<PathBuf, (PathBuf, std::io::Error)> {
     let mut p = file.parse::<PathBuf>().unwrap();
     if p.is_relative() && p.metadata().is_err() {
         let mut d = dir.parse::<PathBuf>().unwrap();
         d.push(&p);
         p = d;
     }
-    if let Err(e) = p.metadata() {
-        die!(std => "{:?}: {}", p, e);
+    match p.metadata() {
+        Ok(_) => Ok(p),
+        Err(e) => Err((p, e)),
     }
-    p
 }
 
-#[derive(Debug, StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 #[structopt(name = "speedtest-fileserver", about = "Speedtest file server.")]
 struct Opts {
     /// location of config file.
     #[structopt(short, long)]
     config: Option<String>,
+
+    /// [addr:]port to listen on for http, instead of the config file.
+    #[structopt(long, use_delimiter = true)]
+    listen: Vec<String>,
+
+    /// the file sizes on the index page, like 10MB,100MB,1GB.
+    #[structopt(long, use_delimiter = true)]
+    sizes: Vec<String>,
+
+    /// maximum file size.
+    #[structopt(long, parse(try_from_str = server::size))]
+    max_file_size: Option<u64>,
+
+    /// location of the access log.
+    #[structopt(long)]
+    access_log: Option<String>,
+
+    /// check the config file and exit.
+    #[structopt(long)]
+    check_config: bool,
+}
+
+impl Opts {
+    // The config file. Without --config, it does not have to exist.
+    fn config_file(&self) -> String {
+        self.config.clone().unwrap_or(CONFIG_FILE.to_string())
+    }
+
+    // Put the command line options over the config file.
+    fn apply(&self, config: &mut Config) {
+        if !self.listen.is_empty() {
+            match config.http.as_mut() {
+                Some(http) => http.listen = self.listen.clone(),
+                None => {
+                    config.http = Some(Http {
+                        listen: self.listen.clone(),
+                        ..Http::default()
+                    })
+                }
+            }
+        }
+        if !self.sizes.is_empty() {
+            config.index.sizes = self.sizes.clone();
+        }
+        if self.max_file_size.is_some() {
+            config.max_file_size = self.max_file_size;
+        }
+        if self.access_log.is_some() {
+            config.access_log = self.access_log.clone();
+        }
+    }
 }
 
-async fn async_main() {
+async fn async_main(inherited: Vec<systemd::Inherited>) {
     // Parse options.
     let opts = Opts::from_args();
 
     // Read config file.
-    let config_file = opts.config.unwrap_or(CONFIG_FILE.to_string());
-    let config: Config = curlyconf::from_file(&config_file)
-        .map_err(|e| die!(std => "config: {}", e))
+    let config_file = opts.config_file();
+    let config = read_config(&opts, false)
+        .map_err(|e| die!(std => "{}", e))
         .unwrap();
+    if opts.check_config {
+        check::run(&config_file, &config);
+    }
+    if let Err(e) = validate(&config) {
+        die!(std => "{}: {}", config_file, e);
+    }
 
-    if config.http.is_none() && config.https.is_none() {
-        die!(std => "{}: at least one of 'http' or 'https' must be enabled", config_file);
+    // In prefork mode, the parent only supervises the workers.
+    let workers = config.workers.unwrap_or(1);
+    let worker = prefork::worker_id();
+    if workers > 1 && worker.is_none() {
+        prefork::supervise(workers).await;
+        return;
     }
 
     // Parse the http config section.
     let mut http_listen = Vec::new();
     if let Some(http) = config.http.as_ref() {
         for l in &http.listen {
-            if let Err(e) = add_listener(l, &mut http_listen) {
+            if let Err(e) = add_listener(l, config.v6only, &mut http_listen) {
                 die!(std => "{}: {}", l, e);
             }
         }
@@ -164,81 +856,684 @@ async fn async_main() {
     let mut https_listen = Vec::new();
     let https = config.https.as_ref().map(|https| {
         for l in &https.listen {
-            if let Err(e) = add_listener(l, &mut https_listen) {
+            if let Err(e) = add_listener(l, config.v6only, &mut https_listen) {
                 die!(std => "{}: {}", l, e);
             }
         }
-        let https_key = resolve_path("/etc/ssl/private", &https.key);
-        let https_chain = resolve_path("/etc/ssl/certs", &https.chain);
-        (https_key, https_chain)
+        match (https.key.as_ref(), https.chain.as_ref()) {
+            (Some(key), Some(chain)) => {
+                let https_key = resolve_path("/etc/ssl/private", key);
+                let https_chain = resolve_path("/etc/ssl/certs", chain);
+                Some((https_key, https_chain))
+            }
+            _ => None,
+        }
     });
 
+    // Parse the metrics config section.
+    let mut metrics_listen = Vec::new();
+    if let Some(metrics) = config.metrics.as_ref() {
+        for l in &metrics.listen {
+            if let Err(e) = add_listener(l, config.v6only, &mut metrics_listen) {
+                die!(std => "{}: {}", l, e);
+            }
+        }
+    }
+
+    // Pin to a NUMA node before allocating any buffers.
+    if let Some(numa) = config.numa.as_ref() {
+        match numa::pin(numa) {
+            Ok(node) => log::info!("running on NUMA node {}", node),
+            Err(e) => die!(std => "{}: numa: {}", config_file, e),
+        }
+    }
+
     // build routes.
-    let server = server::FileServer::new(&config);
+    let server = server::FileServer::new(&config)
+        .map_err(|e| die!(std => "{}: {}", config_file, e))
+        .unwrap();
     let http_redirect = config.http.as_ref().map(|h| h.redirect.as_ref()).flatten();
     let http_routes = server.routes(http_redirect);
     let https_routes = server.routes(None);
+    log_index(&config);
+
+    // Reload the config on SIGHUP.
+    task::spawn(reload_on_sighup(
+        opts.clone(),
+        config.clone(),
+        server.clone(),
+    ));
+
+    // Save the traffic counters periodically.
+    if config.accounting_file.is_some() {
+        task::spawn(server.stats().accounting().run());
+    }
+
+    // Compress and expire rotated log files (in one worker only).
+    if let Some(retention) = config
+        .log_retention
+        .clone()
+        .filter(|_| worker.unwrap_or(0) == 0)
+    {
+        if let Some(Destination::File(access_log)) =
+            config.access_log.as_deref().map(Destination::parse)
+        {
+            task::spawn(logfiles::run(access_log, retention));
+        }
+    }
+
+    // Start reporting to the central aggregator.
+    if let Some(report) = config.report.clone() {
+        task::spawn(report::run(report, server.stats(), server.load_monitor()));
+    }
+
+    // Regenerate the random pool in the background.
+    if let (Some(pool), Some(cfg)) = (server.random_pool(), config.random_pool.as_ref()) {
+        if cfg.refresh > 0 {
+            task::spawn(pool.refresh(Duration::from_secs(cfg.refresh)));
+        }
+    }
+
+    // Start the generator pool.
+    if let Some(threads) = config.generator_threads {
+        if let Err(e) = genpool::start(threads) {
+            die!(std => "{}: generator-threads: {}", config_file, e);
+        }
+    }
 
-    // Run all servers.
+    if config.rng == Some(randomstream::RngKind::Simd) && !xoshiro::simd_available() {
+        log::warn!("rng simd: no SIMD support detected, using the plain code");
+    }
+
+    // Start watching the load.
+    if let Some(load_shedding) = config.load_shedding.clone() {
+        task::spawn(load::run(load_shedding, server.load_monitor()));
+    }
+
+    // Watch the event loop.
+    if let Some(stall_detection) = config.stall_detection.as_ref() {
+        let threshold = Duration::from_millis(stall_detection.threshold);
+        task::spawn(stall::run(threshold, server.stats()));
+    }
+
+    // Load the certificates, and reload them when they change. Or get
+    // them from the ACME CA, in prefork mode only the first worker
+    // orders certificates.
+    let tls_acceptor = https.map(|files| {
+        let https = config.https.as_ref().unwrap();
+        let certs = Arc::new(tls::CertStore::new());
+        match (files, https.acme.clone()) {
+            (Some((key, chain)), _) => {
+                if let Err(e) = certs.load(&key, &chain) {
+                    die!(std => "https: {}", e);
+                }
+                // Reloads happen after the chroot.
+                let chroot = config.chroot.as_deref();
+                let key = privdrop::chrooted(&key, chroot);
+                let chain = privdrop::chrooted(&chain, chroot);
+                task::spawn(tls::watch(certs.clone(), key, chain));
+            }
+            (None, Some(acme)) => {
+                let issue = worker.unwrap_or(0) == 0;
+                task::spawn(acme::run(acme, certs.clone(), issue));
+            }
+            (None, None) => unreachable!(),
+        }
+        let client_ca = config.auth.as_ref().and_then(|a| a.client_ca.as_deref());
+        tls::acceptor(certs, https, client_ca)
+            .map_err(|e| die!(std => "https: {}", e))
+            .unwrap()
+    });
+
+    // Run all servers. Each listener is supervised, and restarted if it fails.
+    let max_down = Duration::from_secs(config.listener_down_timeout);
     let mut handles = Vec::new();
-    for (addr, name) in &http_listen {
-        match warp::serve(http_routes.clone()).try_bind_ephemeral(addr.clone()) {
-            Ok((_, srv)) => {
-                log::info!("Listening on {}", name);
-                handles.push(task::spawn(srv));
+    let http_options = Arc::new(
+        config
+            .http
+            .as_ref()
+            .map(Http::socket_options)
+            .unwrap_or_default(),
+    );
+    let https_options = Arc::new(
+        config
+            .https
+            .as_ref()
+            .map(Https::socket_options)
+            .unwrap_or_default(),
+    );
+    // Listening sockets passed by systemd are used for the configured
+    // listeners with the same address. Others are served as http, unless
+    // their FileDescriptorName is "https".
+    // If we are going to drop privileges, the other sockets are bound
+    // right here, so that restarting a listener does not need root.
+    let mut inherited = inherited;
+    let bind_now = config.user.is_some();
+    let mut take = |addr: &SocketAddr, options: &SocketOptions| {
+        if let Some(idx) = inherited.iter().position(|i| i.addr == *addr) {
+            return Some(inherited.remove(idx).listener);
+        }
+        if !bind_now {
+            return None;
+        }
+        match listener::bind_std(*addr, workers > 1, config.v6only, options) {
+            Ok(listener) => Some(listener),
+            Err(e) => die!(std => "{}: {}", addr, e),
+        }
+    };
+    let mut http_listen: Vec<_> = http_listen
+        .into_iter()
+        .map(|(addr, name)| (addr, name, take(&addr, &http_options)))
+        .collect();
+    let mut https_listen: Vec<_> = https_listen
+        .into_iter()
+        .map(|(addr, name)| (addr, name, take(&addr, &https_options)))
+        .collect();
+    let metrics_listen: Vec<_> = metrics_listen
+        .into_iter()
+        .map(|(addr, name)| (addr, name, take(&addr, &SocketOptions::default())))
+        .collect();
+    for i in inherited.drain(..) {
+        let name = format!("{} (systemd)", i.addr);
+        match i.name.as_str() {
+            "https" if config.https.is_some() => {
+                https_listen.push((i.addr, name, Some(i.listener)))
             }
-            Err(e) => die!(log => "{}: {}", name, e),
+            "https" => die!(log => "{}: https socket, but https is not configured", i.addr),
+            _ => http_listen.push((i.addr, name, Some(i.listener))),
         }
     }
 
-    if let Some((https_key, https_chain)) = https {
-        for (addr, name) in &https_listen {
-            // why no try_bind_ephemeral in the TlsServer?
-            let srv = warp::serve(https_routes.clone());
-            let srv = srv
-                .tls()
-                .key_path(&https_key)
-                .cert_path(&https_chain)
-                .bind(addr.clone());
-            log::info!("Listening on {}", name);
-            handles.push(task::spawn(srv));
+    let listeners = http_listen
+        .into_iter()
+        .map(|l| (l, http_options.clone(), None, http_routes.clone()))
+        .chain(https_listen.into_iter().map(|l| {
+            (
+                l,
+                https_options.clone(),
+                tls_acceptor.clone(),
+                https_routes.clone(),
+            )
+        }));
+    for ((addr, name, inherited), options, tls, routes) in listeners {
+        handles.push(spawn_listener(
+            addr,
+            name,
+            inherited,
+            workers > 1,
+            config.v6only,
+            max_down,
+            options,
+            tls,
+            server.clone(),
+            routes,
+        ));
+    }
+    let metrics_routes = server.metrics_routes();
+    for (addr, name, inherited) in metrics_listen {
+        handles.push(spawn_listener(
+            addr,
+            name,
+            inherited,
+            workers > 1,
+            config.v6only,
+            max_down,
+            Arc::new(SocketOptions::default()),
+            None,
+            server.clone(),
+            metrics_routes.clone(),
+        ));
+    }
+
+    // All sockets are bound and the key files are read, so we
+    // no longer need to be root. The socket of systemd is connected
+    // first, a chroot would hide it.
+    if let Some(user) = config.user.as_ref() {
+        systemd::connect_notify();
+        let group = config.group.as_deref();
+        if let Err(e) = privdrop::drop_privileges(user, group, config.chroot.as_deref()) {
+            die!(log => "{}: user {}: {}", config_file, user, e);
         }
+        log::info!("running as user {}", user);
     }
 
-    // The tasks should never return, only on error. So _if_ one
-    // returns, abort the entire process.
+    // Tell systemd we are up.
+    systemd::notify("READY=1");
+    task::spawn(systemd::watchdog());
+
+    // The supervisors only return if a listener has been down for too long.
+    // If that happens, abort the entire process.
     let mut task_waiter = FuturesUnordered::new();
     for handle in handles.drain(..) {
         task_waiter.push(handle);
     }
-    if let Some(Err(err)) = task_waiter.next().await {
-        if let Ok(cause) = err.try_into_panic() {
-            if let Some(err) = cause.downcast_ref::<String>() {
-                die!(log => "fatal: {}", err);
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    tokio::select! {
+        res = task_waiter.next() => match res {
+            Some(Ok(err)) => die!(log => "fatal: {}", err),
+            Some(Err(err)) => die!(log => "fatal: {}", err),
+            None => die!(log => "server exited unexpectedly"),
+        },
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+
+    // Stop accepting connections, and give the running transfers
+    // some time to finish.
+    systemd::notify("STOPPING=1");
+    let drain_timeout = Duration::from_secs(config.drain_timeout);
+    log::info!(
+        "shutting down, waiting up to {:?} for transfers",
+        drain_timeout
+    );
+    shutdown::start();
+    let left = shutdown::drain(drain_timeout).await;
+    if left > 0 {
+        log::warn!("shutting down, closing {} connections", left);
+    }
+    let _ = task::spawn_blocking(move || {
+        server.flush_log();
+        // The totals since the last periodic save.
+        if let Err(e) = server.stats().accounting().save() {
+            log::error!("accounting: {}", e);
+        }
+    })
+    .await;
+}
+
+// Read and check the config file. Without a config file the built-in
+// default is used, but not on reload, where that is an error.
+fn read_config(opts: &Opts, reload: bool) -> Result<Config, String> {
+    let config_file = &opts.config_file();
+    let missing = opts.config.is_none() && !Path::new(config_file).exists();
+    let mut config: Config = if missing && !reload {
+        curlyconf::from_str(DEFAULT_CONFIG).map_err(|e| format!("default config: {}", e))?
+    } else {
+        curlyconf::from_file(config_file).map_err(|e| format!("config: {}", e))?
+    };
+    opts.apply(&mut config);
+
+    if config.http.is_none() && config.https.is_none() {
+        return Err(format!(
+            "{}: at least one of 'http' or 'https' must be enabled",
+            config_file
+        ));
+    }
+    if config.user.is_none() && (config.group.is_some() || config.chroot.is_some()) {
+        return Err(format!(
+            "{}: 'group' and 'chroot' need 'user' to be set",
+            config_file
+        ));
+    }
+
+    // In prefork mode, workers each keep their own traffic counters,
+    // and report separately.
+    if let (Some(id), true) = (prefork::worker_id(), config.workers.unwrap_or(1) > 1) {
+        if let Some(file) = config.accounting_file.as_mut() {
+            let mut name = file.clone().into_os_string();
+            name.push(format!(".{}", id));
+            *file = PathBuf::from(name);
+        }
+        if let Some(report) = config.report.as_mut() {
+            let instance = report.instance_id.take().unwrap_or_else(report::hostname);
+            report.instance_id = Some(format!("{}-{}", instance, id));
+        }
+    }
+    Ok(config)
+}
+
+// Check the settings that depend on each other.
+fn validate(config: &Config) -> Result<(), String> {
+    if let Some(https) = config.https.as_ref() {
+        if https.ticket_key_rotation == Some(0) {
+            return Err("https: ticket-key-rotation must be > 0".to_string());
+        }
+        if let Some(h2) = https.http2.as_ref() {
+            let windows = [
+                h2.initial_stream_window_size,
+                h2.initial_connection_window_size,
+            ];
+            if windows.iter().flatten().any(|&w| w > i32::MAX as u64) {
+                return Err("https: http2: window size must be < 2GiB".to_string());
+            }
+        }
+        match (
+            https.acme.as_ref(),
+            https.key.as_ref(),
+            https.chain.as_ref(),
+        ) {
+            (Some(acme), None, None) => {
+                // The account and certificates are written after the chroot.
+                if config.chroot.is_some() {
+                    return Err("https: acme cannot be used with chroot".to_string());
+                }
+                if acme.domains.is_empty() {
+                    return Err("https: acme: at least one domain is required".to_string());
+                }
+                // The HTTP-01 challenge is served on the http listener.
+                if config.http.is_none() {
+                    return Err("https: acme needs the http listener (port 80)".to_string());
+                }
+            }
+            (None, Some(key), Some(chain)) => {
+                // The files are reloaded after the chroot, so they must be inside it.
+                if let Some(chroot) = config.chroot.as_ref() {
+                    let files = [("/etc/ssl/private", key), ("/etc/ssl/certs", chain)];
+                    for (dir, file) in files.iter() {
+                        match find_path(dir, file) {
+                            Ok(path) if !path.starts_with(chroot) => {
+                                return Err(format!(
+                                    "https: {:?} is not inside chroot {:?}",
+                                    path, chroot
+                                ));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            (Some(_), _, _) => {
+                return Err("https: use either acme or key and chain".to_string());
+            }
+            _ => return Err("https: key and chain (or acme) are required".to_string()),
+        }
+    }
+    let buffers = [
+        ("http", config.http.as_ref().map(Http::socket_options)),
+        ("https", config.https.as_ref().map(Https::socket_options)),
+    ];
+    for (section, options) in buffers.iter() {
+        let options = match options {
+            Some(options) => options,
+            None => continue,
+        };
+        let sizes = [options.send_buffer, options.receive_buffer];
+        if sizes.iter().flatten().any(|&s| s > i32::MAX as usize) {
+            return Err(format!("{}: socket buffer size must be < 2GiB", section));
+        }
+        // Only loaded modules are listed, the kernel loads others when
+        // they are first used. So just warn, the congestion-control of a
+        // listener is set at startup and fails there if it does not exist.
+        if let Ok(available) =
+            std::fs::read_to_string("/proc/sys/net/ipv4/tcp_available_congestion_control")
+        {
+            let available: Vec<&str> = available.split_whitespace().collect();
+            let names = options
+                .congestion_control
+                .iter()
+                .chain(options.allowed_congestion_control.iter());
+            for name in names.filter(|name| !available.contains(&name.as_str())) {
+                log::warn!(
+                    "{}: congestion control {} not loaded (have: {})",
+                    section,
+                    name,
+                    available.join(", ")
+                );
+            }
+        }
+    }
+    if config.log_retention.is_some() {
+        match config.access_log.as_deref().map(Destination::parse) {
+            Some(Destination::File(_)) => {}
+            _ => return Err("log-retention: access-log is not a file".to_string()),
+        }
+    }
+    if let Some(report) = config.report.as_ref() {
+        if report.interval == 0 {
+            return Err("report: interval must be > 0".to_string());
+        }
+        match report.url.parse::<http::Uri>() {
+            Ok(uri)
+                if matches!(uri.scheme_str(), Some("http") | Some("https"))
+                    && uri.authority().is_some() => {}
+            _ => return Err(format!("report: {}: not a http or https URL", report.url)),
+        }
+    }
+    if config.asn_database.is_none() {
+        if let Some(policy) = config.policies.iter().find(|p| !p.asn.is_empty()) {
+            return Err(format!("policy {}: asn needs an asn-database", policy.name));
+        }
+    }
+    if config.generator_threads == Some(0) {
+        return Err("generator-threads must be > 0".to_string());
+    }
+    if let Some(security_txt) = config.security_txt.as_ref() {
+        if security_txt.contact.is_empty() {
+            return Err("security-txt: at least one contact is required".to_string());
+        }
+    }
+    // Every worker has its own limiters, quotas and session ticket keys.
+    // The limits would be multiplied by the number of workers, and a
+    // ticket from one worker would not be accepted by another.
+    if config.workers.map(|w| w > 1).unwrap_or(false) {
+        let per_worker = [
+            ("max-total-bandwidth", config.max_total_bandwidth.is_some()),
+            ("max-streams-per-ip", config.max_streams_per_ip.is_some()),
+            (
+                "max-requests-per-minute",
+                config.max_requests_per_minute.is_some(),
+            ),
+            (
+                "policy quota",
+                config.policies.iter().any(|p| p.quota.is_some()),
+            ),
+            (
+                "https: ticket-key-rotation",
+                config
+                    .https
+                    .as_ref()
+                    .map(|h| h.ticket_key_rotation.is_some())
+                    .unwrap_or(false),
+            ),
+        ];
+        if let Some((name, _)) = per_worker.iter().find(|(_, set)| *set) {
+            return Err(format!("workers: {} cannot be used with workers", name));
+        }
+    }
+    if let Some(auth) = config.auth.as_ref() {
+        if auth.client_ca.is_none() && auth.tokens.is_empty() && auth.url_secret.is_none() {
+            return Err("auth: client-ca, tokens or url-secret is required".to_string());
+        }
+        if auth.client_ca.is_some() && config.https.is_none() {
+            return Err("auth: client-ca needs the https listener".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Which index page is served on "/".
+fn log_index(config: &Config) {
+    match config.index.file.as_ref() {
+        Some(file) => log::info!("index: {:?}", file),
+        None => log::info!("index: built-in template"),
+    }
+    if let Some(dir) = config.data_dir.as_ref() {
+        let index_html = dir.join("index.html");
+        if index_html.exists() {
+            log::warn!("{:?}: not served on /, the index is", index_html);
+        }
+    }
+}
+
+// Settings that can only be changed with a restart.
+fn needs_restart(old: &Config, new: &Config) -> Option<&'static str> {
+    let listen = |c: &Config| {
+        (
+            c.http.as_ref().map(|h| h.listen.clone()),
+            c.https.as_ref().map(|h| {
+                (
+                    h.listen.clone(),
+                    h.key.clone(),
+                    h.chain.clone(),
+                    h.acme.clone(),
+                    h.http2.clone(),
+                )
+            }),
+            c.metrics.as_ref().map(|m| m.listen.clone()),
+            c.auth.as_ref().map(|a| a.client_ca.clone()),
+        )
+    };
+    let socket_options = |c: &Config| {
+        (
+            c.http.as_ref().map(Http::socket_options),
+            c.https.as_ref().map(Https::socket_options),
+        )
+    };
+    if listen(old) != listen(new)
+        || socket_options(old) != socket_options(new)
+        || old.v6only != new.v6only
+    {
+        return Some("listeners");
+    }
+    if old.workers != new.workers {
+        return Some("workers");
+    }
+
+    // The routes are built once, and these are only used at startup.
+    let https = |c: &Config| c.https.as_ref().map(|h| h.ticket_key_rotation);
+    let redirect = |c: &Config| c.http.as_ref().map(|h| h.redirect.clone());
+    let startup = [
+        ("random-pool", old.random_pool != new.random_pool),
+        (
+            "accounting-file",
+            old.accounting_file != new.accounting_file,
+        ),
+        ("live-stats", old.live_stats != new.live_stats),
+        ("data-dir", old.data_dir != new.data_dir),
+        ("index assets", old.index.assets != new.index.assets),
+        ("upload", old.upload != new.upload),
+        ("websocket", old.websocket != new.websocket),
+        ("librespeed", old.librespeed != new.librespeed),
+        ("tr143-mode", old.tr143 != new.tr143),
+        ("public-stats", old.public_stats != new.public_stats),
+        (
+            "transfer-results",
+            old.transfer_results != new.transfer_results,
+        ),
+        ("metrics", old.metrics != new.metrics),
+        (
+            "security-txt",
+            old.security_txt.is_some() != new.security_txt.is_some(),
+        ),
+        ("http redirect", redirect(old) != redirect(new)),
+        ("https ticket-key-rotation", https(old) != https(new)),
+        ("report", old.report != new.report),
+        ("load-shedding", old.load_shedding != new.load_shedding),
+        (
+            "stall-detection",
+            old.stall_detection != new.stall_detection,
+        ),
+        (
+            "generator-threads",
+            old.generator_threads != new.generator_threads,
+        ),
+        ("numa", old.numa != new.numa),
+        ("log-retention", old.log_retention != new.log_retention),
+        ("user", old.user != new.user),
+        ("group", old.group != new.group),
+        ("chroot", old.chroot != new.chroot),
+    ];
+    startup
+        .iter()
+        .find(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+}
+
+// Re-read the config file on SIGHUP, and reopen the access log.
+async fn reload_on_sighup(opts: Opts, mut config: Config, server: server::FileServer) {
+    let config_file = opts.config_file();
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("SIGHUP: {}", e);
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        let new_config = match read_config(&opts, true) {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                log::error!("reload: {}", e);
+                server.reopen_log();
+                continue;
             }
+        };
+        if let Err(e) = validate(&new_config) {
+            log::error!("reload: {}: {}", config_file, e);
+            server.reopen_log();
+            continue;
         }
+        if let Some(what) = needs_restart(&config, &new_config) {
+            log::error!("reload: {}: changing {} needs a restart", config_file, what);
+            server.reopen_log();
+            continue;
+        }
+        if let Err(e) = server.reload(&new_config) {
+            log::error!("reload: {}", e);
+            server.reopen_log();
+            continue;
+        }
+        config = new_config;
+        log::info!("reloaded {}", config_file);
+        log_index(&config);
     }
-    die!(log => "server exited unexpectedly");
+}
+
+// Start a supervised listener, on a socket inherited from systemd or
+// on a newly bound one.
+#[allow(clippy::too_many_arguments)]
+fn spawn_listener<R>(
+    addr: SocketAddr,
+    name: String,
+    inherited: Option<std::net::TcpListener>,
+    reuse_port: bool,
+    v6only: bool,
+    max_down: Duration,
+    options: Arc<SocketOptions>,
+    tls: Option<TlsAcceptor>,
+    server: server::FileServer,
+    routes: BoxedFilter<(R,)>,
+) -> task::JoinHandle<String>
+where
+    R: Reply + 'static,
+{
+    let lname = name.clone();
+    let start = move || {
+        let listener = match inherited.as_ref() {
+            Some(l) => l
+                .try_clone()
+                .and_then(|l| l.set_nonblocking(true).map(|_| l))
+                .and_then(|l| options.apply_listener(&l).map(|_| l))
+                .and_then(tokio::net::TcpListener::from_std),
+            None => listener::bind(addr, reuse_port, v6only, &options),
+        }
+        .map_err(|e| e.to_string())?;
+        log::info!("Listening on {}", lname);
+        Ok(listener::serve(
+            listener,
+            options.clone(),
+            tls.clone(),
+            server.clone(),
+            routes.clone(),
+        ))
+    };
+    task::spawn(listener::supervise(name, max_down, start))
 }
 
 fn main() {
     let env = env_logger::Env::default().default_filter_or("info");
     env_logger::init_from_env(env);
 
+    // Take the sockets from systemd before any threads are started,
+    // since that changes the environment.
+    let inherited = systemd::listen_fds();
+
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        .on_thread_start(|| {
-            let hook = panic::take_hook();
-            panic::set_hook(Box::new(move |info| {
-                match info.payload().downcast_ref::<String>() {
-                    Some(msg) if msg.contains("error binding to") => {}
-                    _ => hook(info),
-                }
-            }));
-        })
         .build()
         .unwrap();
-    rt.block_on(async_main());
+    rt.block_on(async_main(inherited));
 }
 
 use serde::de;
@@ -252,6 +1547,14 @@ where
     server::size(&s).map(Some).map_err(de::Error::custom)
 }
 
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let s: String = de::Deserialize::deserialize(deserializer)?;
+    throttle::rate(&s).map(Some).map_err(de::Error::custom)
+}
+
 fn deserialize_uri<'de, D>(deserializer: D) -> Result<Option<http::Uri>, D::Error>
 where
     D: de::Deserializer<'de>,
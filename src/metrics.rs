@@ -0,0 +1,93 @@
+//!
+//! Prometheus metrics, in the text exposition format.
+//!
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Buckets for the size of a download, in bytes.
+pub const SIZE_BUCKETS: &[f64] = &[1e6, 1e7, 1e8, 1e9, 1e10];
+
+/// Buckets for the throughput of a download, in bits per second.
+pub const THROUGHPUT_BUCKETS: &[f64] =
+    &[1e6, 1e7, 2.5e7, 5e7, 1e8, 2.5e8, 5e8, 1e9, 2.5e9, 5e9, 1e10];
+
+/// A histogram with fixed buckets.
+pub struct Histogram {
+    bounds: &'static [f64],
+    inner: Mutex<HistogramData>,
+}
+
+struct HistogramData {
+    // Not cumulative, the last one is +Inf.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            inner: Mutex::new(HistogramData {
+                counts: vec![0; bounds.len() + 1],
+                sum: 0f64,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let idx = self
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.bounds.len());
+        let mut inner = self.inner.lock().unwrap();
+        inner.counts[idx] += 1;
+        inner.sum += value;
+    }
+}
+
+/// Builds the text of the /metrics page.
+pub struct Writer {
+    out: String,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { out: String::new() }
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+        let inner = histogram.inner.lock().unwrap();
+        let mut total = 0;
+        for (idx, count) in inner.counts.iter().enumerate() {
+            total += count;
+            let le = match histogram.bounds.get(idx) {
+                Some(bound) => bound.to_string(),
+                None => String::from("+Inf"),
+            };
+            let _ = writeln!(self.out, "{}_bucket{{le=\"{}\"}} {}", name, le, total);
+        }
+        let _ = writeln!(self.out, "{}_sum {}", name, inner.sum);
+        let _ = writeln!(self.out, "{}_count {}", name, total);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
@@ -0,0 +1,90 @@
+//!
+//! NUMA placement.
+//!
+//! On hosts with more than one NUMA node, pin the whole process to the
+//! CPUs of one node, usually the node the NIC is attached to. Memory is
+//! allocated on the node of the CPU that first touches it, so once we
+//! are pinned, buffers like the random pool end up on that node too.
+//!
+use std::fs;
+use std::io;
+
+use crate::Numa;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// The NUMA node a network interface is attached to.
+fn interface_node(interface: &str) -> io::Result<usize> {
+    let path = format!("/sys/class/net/{}/device/numa_node", interface);
+    let data = fs::read_to_string(&path).map_err(|e| invalid(format!("{}: {}", path, e)))?;
+    // -1 means the host is not NUMA, or the kernel doesn't know.
+    match data.trim().parse::<i64>() {
+        Ok(node) if node >= 0 => Ok(node as usize),
+        _ => Err(invalid(format!("{}: no NUMA node for {}", path, interface))),
+    }
+}
+
+// Parse a cpulist like "0-7,16-23".
+fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut parts = range.splitn(2, '-');
+        let start: usize = parts.next()?.parse().ok()?;
+        let end: usize = match parts.next() {
+            Some(end) => end.parse().ok()?,
+            None => start,
+        };
+        cpus.extend(start..=end);
+    }
+    Some(cpus)
+}
+
+// The CPUs of a NUMA node.
+fn node_cpus(node: usize) -> io::Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let data = fs::read_to_string(&path).map_err(|e| invalid(format!("{}: {}", path, e)))?;
+    match parse_cpulist(&data) {
+        Some(cpus) if !cpus.is_empty() => Ok(cpus),
+        _ => Err(invalid(format!("{}: cannot parse cpulist", path))),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(tid: libc::pid_t, cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_tid: i32, _cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "NUMA placement is only supported on Linux",
+    ))
+}
+
+/// Pin all threads of this process (and so, all threads created
+/// later) to the CPUs of the configured NUMA node. Returns the node.
+pub fn pin(config: &Numa) -> io::Result<usize> {
+    let node = match (config.node, config.interface.as_ref()) {
+        (Some(node), _) => node,
+        (None, Some(interface)) => interface_node(interface)?,
+        (None, None) => return Err(invalid("numa: need node or interface".to_string())),
+    };
+    let cpus = node_cpus(node)?;
+    for task in fs::read_dir("/proc/self/task")? {
+        let tid = task?.file_name().to_string_lossy().parse().unwrap_or(0);
+        set_affinity(tid, &cpus)?;
+    }
+    Ok(node)
+}
@@ -0,0 +1,375 @@
+//!
+//! OpenAPI description of the API, generated from the configuration,
+//! so that it only lists the endpoints that are actually enabled.
+//!
+use serde_json::{json, Map, Value};
+
+use crate::server::MAX_FILE_SIZE;
+use crate::Config;
+
+fn size_param() -> Value {
+    json!({
+        "name": "size",
+        "in": "path",
+        "required": true,
+        "description": "Size of the file, with an optional unit, and an optional extension. \
+                        For example 100MB, 1GiB.bin, 97KiB.",
+        "schema": { "type": "string", "example": "100MB.bin" }
+    })
+}
+
+fn json_response(description: &str, schema: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": {
+                "schema": { "$ref": format!("#/components/schemas/{}", schema) }
+            }
+        }
+    })
+}
+
+/// Generate the OpenAPI document.
+pub fn spec(config: &Config) -> Value {
+    let max_size = config.max_file_size.unwrap_or(MAX_FILE_SIZE);
+    let mut paths = Map::new();
+    let mut schemas = Map::new();
+
+    let warmup_param = json!({
+        "name": "warmup",
+        "in": "query",
+        "required": false,
+        "description": "Send this much extra data before the file. The X-Warmup-Length \
+                        header contains the offset where the measured part starts.",
+        "schema": { "type": "string", "example": "2MB" }
+    });
+    let cc_param = json!({
+        "name": "cc",
+        "in": "query",
+        "required": false,
+        "description": "TCP congestion control algorithm, if allowed on the listener.",
+        "schema": { "type": "string", "example": "bbr" }
+    });
+    let pattern_param = json!({
+        "name": "pattern",
+        "in": "query",
+        "required": false,
+        "description": "What the data looks like: random (incompressible), zeros, or text.",
+        "schema": { "type": "string", "enum": [ "random", "zeros", "text" ] }
+    });
+    let seed_param = json!({
+        "name": "seed",
+        "in": "query",
+        "required": false,
+        "description": "Seed of the random data. The X-Seed header contains the seed \
+                        that was used, so that the same data can be requested again.",
+        "schema": { "type": "integer", "format": "uint64" }
+    });
+    let checksum_param = json!({
+        "name": "checksum",
+        "in": "query",
+        "required": false,
+        "description": "Send a SHA-256 of the body in a Content-Digest trailer. HTTP/2 only, \
+                        over HTTP/1.1 the answer is 400.",
+        "schema": { "type": "string", "enum": [ "sha256" ] }
+    });
+    let range_param = json!({
+        "name": "Range",
+        "in": "header",
+        "required": false,
+        "description": "A single byte range. Ignored if warmup or a duration is set, \
+                        or for random data without a seed.",
+        "schema": { "type": "string", "example": "bytes=0-1023" }
+    });
+    let mut download = json!({
+        "get": {
+            "summary": "Download a file with random data",
+            "description": format!(
+                "The maximum size is {} bytes. Instead of a size, a duration like 30s \
+                 sends data for that many seconds (at most 300), and \"infinite\" sends data \
+                 until the client closes the connection. Both without a Content-Length.",
+                max_size
+            ),
+            "parameters": [
+                size_param(), warmup_param, cc_param, pattern_param,
+                seed_param, checksum_param, range_param
+            ],
+            "responses": {
+                "200": {
+                    "description": "Random data, or the requested pattern",
+                    "content": {
+                        "application/octet-stream": {
+                            "schema": { "type": "string", "format": "binary" }
+                        }
+                    }
+                },
+                "206": { "description": "The requested range of the random data" },
+                "400": { "description": "Size or duration cannot be parsed or is too large" },
+                "416": { "description": "Range not satisfiable" },
+                "429": { "description": "Quota or per-client limit exceeded" },
+                "503": { "description": "Server overloaded" }
+            }
+        }
+    });
+    download["head"] = json!({
+        "summary": "The headers of a download (Content-Length), without the data",
+        "parameters": [ size_param() ],
+        "responses": {
+            "200": { "description": "Headers only" },
+            "400": { "description": "Size or duration cannot be parsed or is too large" }
+        }
+    });
+    if config.tr143 {
+        download["put"] = json!({
+            "summary": "Upload data, which is discarded",
+            "parameters": [ size_param() ],
+            "requestBody": {
+                "content": {
+                    "application/octet-stream": {
+                        "schema": { "type": "string", "format": "binary" }
+                    }
+                }
+            },
+            "responses": {
+                "200": { "description": "Upload received" },
+                "413": { "description": "Upload too large" }
+            }
+        });
+    }
+    paths.insert("/{size}".to_string(), download);
+
+    let mut empty = json!({
+        "summary": "Empty response, for measuring the round-trip time",
+        "responses": { "204": { "description": "No content" } }
+    });
+    if config.server_timestamp {
+        empty["responses"]["204"]["headers"] = json!({
+            "X-Server-Timestamp": {
+                "description": "Time on the server, in milliseconds since the epoch",
+                "schema": { "type": "integer" }
+            }
+        });
+    }
+    paths.insert(
+        "/empty".to_string(),
+        json!({ "get": empty.clone(), "head": empty }),
+    );
+
+    if config.upload {
+        let upload = json!({
+            "summary": "Upload data, which is discarded",
+            "description": format!("The maximum size is {} bytes.", max_size),
+            "requestBody": {
+                "content": {
+                    "application/octet-stream": {
+                        "schema": { "type": "string", "format": "binary" }
+                    }
+                }
+            },
+            "responses": {
+                "200": json_response("Upload received", "UploadResult"),
+                "413": { "description": "Upload too large" }
+            }
+        });
+        paths.insert(
+            "/upload".to_string(),
+            json!({ "post": upload.clone(), "put": upload }),
+        );
+        schemas.insert(
+            "UploadResult".to_string(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "bytes": { "type": "integer", "description": "Bytes received" },
+                    "duration": { "type": "number", "description": "Seconds" },
+                    "throughput": { "type": "number", "description": "Bits per second" }
+                }
+            }),
+        );
+    }
+
+    if config.websocket {
+        paths.insert(
+            "/ws".to_string(),
+            json!({
+                "get": {
+                    "summary": "WebSocket speedtest protocol",
+                    "description": "Send \"download <size>\" to receive random data in binary \
+                                    messages, or \"upload\", binary messages and \"done\" \
+                                    to upload. The server sends {\"bytes\": <n>} every 250ms, \
+                                    and an UploadResult-like summary at the end.",
+                    "responses": {
+                        "101": { "description": "Switching to the WebSocket protocol" }
+                    }
+                }
+            }),
+        );
+    }
+
+    if config.transfer_results {
+        paths.insert(
+            "/result/{id}".to_string(),
+            json!({
+                "get": {
+                    "summary": "Server-side result of a recent download",
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "description": "The X-Request-Id header of the download",
+                        "schema": { "type": "string" }
+                    }],
+                    "responses": {
+                        "200": json_response("Transfer result", "TransferResult"),
+                        "404": { "description": "Unknown or expired id" }
+                    }
+                }
+            }),
+        );
+        schemas.insert(
+            "TransferResult".to_string(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "id": { "type": "string" },
+                    "size": { "type": "integer", "description": "Requested size in bytes" },
+                    "bytes": { "type": "integer", "description": "Bytes sent" },
+                    "complete": { "type": "boolean" },
+                    "duration": { "type": "number", "description": "Seconds" },
+                    "throughput": { "type": "number", "description": "Bits per second" },
+                    "warmup": { "type": "integer" },
+                    "measured_bytes": { "type": "integer" },
+                    "measured_duration": { "type": "number" },
+                    "measured_throughput": { "type": "number" },
+                    "tcp_info": {
+                        "type": "object",
+                        "nullable": true,
+                        "properties": {
+                            "rtt": { "type": "integer", "description": "Microseconds" },
+                            "rttvar": { "type": "integer", "description": "Microseconds" },
+                            "retransmits": { "type": "integer" },
+                            "cwnd": { "type": "integer", "description": "Bytes" },
+                            "delivery_rate": {
+                                "type": "integer",
+                                "nullable": true,
+                                "description": "Bytes per second"
+                            }
+                        }
+                    }
+                }
+            }),
+        );
+    }
+
+    if config.public_stats {
+        paths.insert(
+            "/stats.json".to_string(),
+            json!({
+                "get": {
+                    "summary": "Server statistics",
+                    "responses": { "200": json_response("Statistics", "Stats") }
+                }
+            }),
+        );
+        schemas.insert(
+            "Stats".to_string(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "tests_today": { "type": "integer" },
+                    "bytes_served": { "type": "integer" },
+                    "active_streams": { "type": "integer" }
+                }
+            }),
+        );
+    }
+
+    if config.live_stats {
+        paths.insert(
+            "/stats".to_string(),
+            json!({
+                "get": {
+                    "summary": "Statistics of the last 15 minutes",
+                    "responses": { "200": json_response("Statistics", "LiveStats") }
+                }
+            }),
+        );
+        schemas.insert(
+            "LiveStats".to_string(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "active_streams": { "type": "integer" },
+                    "bytes_1m": { "type": "integer" },
+                    "bytes_5m": { "type": "integer" },
+                    "bytes_15m": { "type": "integer" },
+                    "top_networks": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "network": { "type": "string" },
+                                "bytes": { "type": "integer" }
+                            }
+                        }
+                    },
+                    "throughput": {
+                        "type": "array",
+                        "description": "Number of downloads per throughput bucket, \
+                                        up to le bits per second",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "le": { "type": "number", "nullable": true },
+                                "count": { "type": "integer" }
+                            }
+                        }
+                    }
+                }
+            }),
+        );
+    }
+
+    paths.insert(
+        "/api/files.json".to_string(),
+        json!({
+            "get": {
+                "summary": "The files listed on the index page",
+                "responses": { "200": json_response("Files", "Files") }
+            }
+        }),
+    );
+    schemas.insert(
+        "Files".to_string(),
+        json!({
+            "type": "object",
+            "properties": {
+                "version": { "type": "string" },
+                "max_file_size": { "type": "integer" },
+                "files": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string", "example": "100MB.bin" },
+                            "size": { "type": "string", "example": "100MB" },
+                            "bytes": { "type": "integer" },
+                            "url": { "type": "string", "example": "/100MB.bin" },
+                            "label": { "type": "string" }
+                        }
+                    }
+                }
+            }
+        }),
+    );
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Speedtest fileserver",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": { "schemas": schemas },
+    })
+}
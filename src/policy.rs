@@ -0,0 +1,192 @@
+//!
+//! Bandwidth policies for networks and AS numbers.
+//!
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{offset::Local, NaiveDate};
+
+use crate::cidr::{self, Cidr};
+use crate::Config;
+use crate::Policy;
+
+/// All configured policies, plus the prefix-to-AS table and
+/// the per-client quota accounting.
+pub struct Policies {
+    policies: Vec<Policy>,
+    asn_table: Option<AsnTable>,
+    // Shared with the policies of a reloaded config.
+    usage: Arc<Mutex<Usage>>,
+}
+
+struct Usage {
+    day: NaiveDate,
+    bytes: HashMap<IpAddr, u64>,
+}
+
+impl Policies {
+    pub fn new(config: &Config) -> io::Result<Policies> {
+        let usage = Usage {
+            day: today(),
+            bytes: HashMap::new(),
+        };
+        Policies::build(config, Arc::new(Mutex::new(usage)))
+    }
+
+    /// The policies of a new config, keeping the quota used so far.
+    pub fn reload(&self, config: &Config) -> io::Result<Policies> {
+        Policies::build(config, self.usage.clone())
+    }
+
+    fn build(config: &Config, usage: Arc<Mutex<Usage>>) -> io::Result<Policies> {
+        let asn_table = match config.asn_database.as_ref() {
+            Some(path) if config.policies.iter().any(|p| !p.asn.is_empty()) => {
+                let data = fs::read_to_string(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))?;
+                let table = AsnTable::parse(&data).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+                })?;
+                Some(table)
+            }
+            _ => None,
+        };
+        Ok(Policies {
+            policies: config.policies.clone(),
+            asn_table,
+            usage,
+        })
+    }
+
+    /// Find the first policy that matches this address, either
+    /// by network, or by the AS number the address belongs to.
+    pub fn lookup(&self, ip: IpAddr) -> Option<&Policy> {
+        if self.policies.is_empty() {
+            return None;
+        }
+        let ip = cidr::canonical(ip);
+        let mut asn = None;
+        for policy in &self.policies {
+            if policy.networks.iter().any(|n| n.contains(&ip)) {
+                return Some(policy);
+            }
+            if !policy.asn.is_empty() {
+                if asn.is_none() {
+                    asn = Some(self.asn_table.as_ref().and_then(|t| t.lookup(ip)));
+                }
+                if let Some(Some(asn)) = asn {
+                    if policy.asn.contains(&asn) {
+                        return Some(policy);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Bytes downloaded today by this client.
+    pub fn used(&self, ip: IpAddr) -> u64 {
+        let mut usage = self.usage.lock().unwrap();
+        usage.check_day();
+        usage.bytes.get(&ip).cloned().unwrap_or(0)
+    }
+
+    /// Add to the number of bytes downloaded today by this client.
+    pub fn charge(&self, ip: IpAddr, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.check_day();
+        *usage.bytes.entry(ip).or_insert(0) += bytes;
+    }
+}
+
+impl Usage {
+    // Quota is per day, start afresh at midnight.
+    fn check_day(&mut self) {
+        let today = today();
+        if today != self.day {
+            self.day = today;
+            self.bytes.clear();
+        }
+    }
+}
+
+fn today() -> NaiveDate {
+    Local::now().naive_local().date()
+}
+
+/// Charges the bytes sent to the client's quota when dropped.
+pub struct QuotaGuard {
+    policies: Arc<Policies>,
+    ip: IpAddr,
+    pub bytes: u64,
+}
+
+impl QuotaGuard {
+    pub fn new(policies: Arc<Policies>, ip: IpAddr) -> QuotaGuard {
+        QuotaGuard {
+            policies,
+            ip,
+            bytes: 0,
+        }
+    }
+}
+
+impl Drop for QuotaGuard {
+    fn drop(&mut self) {
+        self.policies.charge(self.ip, self.bytes);
+    }
+}
+
+// Prefix to AS number table, read from a file in the CAIDA
+// "pfx2as" format: "<network> <prefix-length> <asn>" per line.
+struct AsnTable {
+    prefixes: HashMap<(bool, u8, u128), u32>,
+}
+
+impl AsnTable {
+    fn parse(data: &str) -> Result<AsnTable, String> {
+        let mut prefixes = HashMap::new();
+        for (num, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                return Err(format!("line {}: expected 3 fields", num + 1));
+            }
+            let err = || format!("line {}: cannot parse", num + 1);
+            let addr = fields[0].parse::<IpAddr>().map_err(|_| err())?;
+            let prefix_len = fields[1].parse::<u8>().map_err(|_| err())?;
+            // multi-origin prefixes look like "13335_4200" or "13335,4200". Use the first.
+            let asn = fields[2]
+                .split(&['_', ','][..])
+                .next()
+                .unwrap()
+                .parse::<u32>()
+                .map_err(|_| err())?;
+            let net = Cidr::new(addr, prefix_len).ok_or_else(err)?;
+            prefixes.insert((addr.is_ipv4(), prefix_len, net.network()), asn);
+        }
+        Ok(AsnTable { prefixes })
+    }
+
+    // Longest prefix match.
+    fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        let width = if ip.is_ipv4() { 32 } else { 128 };
+        let addr = cidr::ip_to_u128(ip);
+        for prefix_len in (0..=width).rev() {
+            let key = (
+                ip.is_ipv4(),
+                prefix_len,
+                cidr::mask(addr, width, prefix_len),
+            );
+            if let Some(asn) = self.prefixes.get(&key) {
+                return Some(*asn);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,173 @@
+//!
+//! Multi-process (prefork) mode.
+//!
+//! The parent process starts a number of worker processes (by running
+//! itself again), and restarts them if they exit. Every worker binds
+//! the listeners with SO_REUSEPORT, so the kernel spreads the incoming
+//! connections over the workers. Signals sent to the parent are passed
+//! on to the workers.
+//!
+use std::env;
+use std::io;
+use std::process::ExitStatus;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{Duration, Instant};
+
+use crate::systemd;
+
+// Environment variable that tells a process which worker it is.
+const WORKER_ENV: &str = "SPEEDTEST_FILESERVER_WORKER";
+
+// Backoff between restarts of a worker that keeps exiting.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// If a worker ran for at least this long, it was healthy.
+const UP_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// If this process is a worker, its number.
+pub fn worker_id() -> Option<usize> {
+    env::var(WORKER_ENV).ok().and_then(|w| w.parse().ok())
+}
+
+struct Worker {
+    child: Option<Child>,
+    started: Instant,
+    backoff: Duration,
+    restart_at: Instant,
+}
+
+enum Event {
+    Exited(usize, io::Result<ExitStatus>),
+    Terminate,
+    Hangup,
+    Restart,
+}
+
+fn spawn(id: usize) -> Option<Child> {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            log::error!("worker {}: cannot find executable: {}", id, e);
+            return None;
+        }
+    };
+    match Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(WORKER_ENV, id.to_string())
+        .spawn()
+    {
+        Ok(child) => {
+            log::info!("worker {}: started, pid {}", id, child.id().unwrap_or(0));
+            Some(child)
+        }
+        Err(e) => {
+            log::error!("worker {}: {}", id, e);
+            None
+        }
+    }
+}
+
+// Send a signal to all running workers.
+fn kill_all(workers: &[Worker], sig: libc::c_int) {
+    for worker in workers {
+        if let Some(pid) = worker.child.as_ref().and_then(|c| c.id()) {
+            unsafe {
+                libc::kill(pid as libc::pid_t, sig);
+            }
+        }
+    }
+}
+
+/// Run `count` workers, and keep them running. Returns on SIGTERM or
+/// SIGINT, after all workers have exited.
+pub async fn supervise(count: usize) {
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    let mut sighup = signal(SignalKind::hangup()).unwrap();
+
+    let now = Instant::now();
+    let mut workers: Vec<Worker> = (0..count)
+        .map(|_| Worker {
+            child: None,
+            started: now,
+            backoff: MIN_BACKOFF,
+            restart_at: now,
+        })
+        .collect();
+
+    // The workers are not the main process, so systemd only listens to us.
+    systemd::notify("READY=1");
+    tokio::spawn(systemd::watchdog());
+
+    loop {
+        // (Re)start the workers that are due.
+        let now = Instant::now();
+        for (id, worker) in workers.iter_mut().enumerate() {
+            if worker.child.is_none() && worker.restart_at <= now {
+                worker.child = spawn(id);
+                worker.started = now;
+                if worker.child.is_none() {
+                    worker.restart_at = now + worker.backoff;
+                    worker.backoff = std::cmp::min(worker.backoff * 2, MAX_BACKOFF);
+                }
+            }
+        }
+        let next_restart = workers
+            .iter()
+            .filter(|w| w.child.is_none())
+            .map(|w| w.restart_at)
+            .min()
+            .unwrap_or_else(|| now + MAX_BACKOFF);
+
+        // Wait for a worker to exit, or for a signal.
+        let event = {
+            let mut waiting: FuturesUnordered<_> = workers
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(id, w)| w.child.as_mut().map(|c| (id, c)))
+                .map(|(id, child)| async move { (id, child.wait().await) })
+                .collect();
+            tokio::select! {
+                Some((id, status)) = waiting.next() => Event::Exited(id, status),
+                _ = sigterm.recv() => Event::Terminate,
+                _ = sigint.recv() => Event::Terminate,
+                _ = sighup.recv() => Event::Hangup,
+                _ = tokio::time::sleep_until(next_restart) => Event::Restart,
+            }
+        };
+
+        match event {
+            Event::Exited(id, status) => {
+                // Restart it, with backoff if it keeps failing.
+                match status {
+                    Ok(status) => log::error!("worker {}: exited: {}", id, status),
+                    Err(e) => log::error!("worker {}: {}", id, e),
+                }
+                let worker = &mut workers[id];
+                worker.child = None;
+                if worker.started.elapsed() >= UP_THRESHOLD {
+                    worker.backoff = MIN_BACKOFF;
+                }
+                log::warn!("worker {}: restarting in {:?}", id, worker.backoff);
+                worker.restart_at = Instant::now() + worker.backoff;
+                worker.backoff = std::cmp::min(worker.backoff * 2, MAX_BACKOFF);
+            }
+            Event::Hangup => kill_all(&workers, libc::SIGHUP),
+            Event::Restart => {}
+            Event::Terminate => {
+                log::info!("shutting down workers");
+                kill_all(&workers, libc::SIGTERM);
+                for worker in workers.iter_mut() {
+                    if let Some(child) = worker.child.as_mut() {
+                        let _ = child.wait().await;
+                    }
+                }
+                return;
+            }
+        }
+    }
+}
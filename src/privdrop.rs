@@ -0,0 +1,115 @@
+//!
+//! Dropping root privileges, after the listening sockets are bound.
+//!
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+// Size of the buffer for getpwnam_r and getgrnam_r.
+const BUF_SIZE: usize = 16384;
+
+fn cstring(s: &[u8]) -> io::Result<CString> {
+    CString::new(s).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The uid and primary gid of a user.
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = cstring(name.as_bytes())?;
+    let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let res = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("user {}: not found", name),
+        ));
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = cstring(name.as_bytes())?;
+    let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let res = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("group {}: not found", name),
+        ));
+    }
+    Ok(grp.gr_gid)
+}
+
+/// Where `path` is found after changing the root directory to `chroot`.
+/// Paths outside of the chroot are returned unchanged.
+pub fn chrooted(path: &Path, chroot: Option<&Path>) -> PathBuf {
+    match chroot.and_then(|dir| path.strip_prefix(dir).ok()) {
+        Some(rest) => Path::new("/").join(rest),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Change the root directory to `chroot`, if set, and switch to `user`
+/// and `group`. The group defaults to the primary group of the user.
+pub fn drop_privileges(user: &str, group: Option<&str>, chroot: Option<&Path>) -> io::Result<()> {
+    // Look up the names before the chroot, which has no /etc/passwd.
+    let (uid, gid) = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => gid,
+    };
+
+    if let Some(dir) = chroot {
+        let c_dir = cstring(dir.as_os_str().as_bytes())?;
+        check(unsafe { libc::chroot(c_dir.as_ptr()) })?;
+        std::env::set_current_dir("/")?;
+    }
+
+    // Group first, after setuid we are no longer allowed to.
+    check(unsafe { libc::setgroups(1, &gid) })?;
+    check(unsafe { libc::setgid(gid) })?;
+    check(unsafe { libc::setuid(uid) })?;
+
+    // Make sure there is no way back.
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "could still switch back to root",
+        ));
+    }
+    Ok(())
+}
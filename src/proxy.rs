@@ -0,0 +1,267 @@
+//!
+//! PROXY protocol (v1 and v2), as sent by HAProxy and L4 load balancers
+//! in front of the server, to pass on the address of the client.
+//!
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+enum Parsed {
+    Incomplete,
+    // Length of the header, and the client address (None for
+    // health checks by the proxy itself).
+    Done(usize, Option<SocketAddr>),
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("PROXY protocol: {}", msg),
+    )
+}
+
+/// Read the PROXY protocol header from a new connection.
+///
+/// Returns the address of the client, if the proxy sent one, and the
+/// data that was read after the header.
+pub async fn read_header<R>(io: &mut R) -> io::Result<(Option<SocketAddr>, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(256);
+    let mut chunk = [0u8; 256];
+    loop {
+        let n = io.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Parsed::Done(len, addr) = parse(&buf)? {
+            return Ok((addr, buf.split_off(len)));
+        }
+    }
+}
+
+fn parse(buf: &[u8]) -> io::Result<Parsed> {
+    let n = std::cmp::min(buf.len(), V2_SIGNATURE.len());
+    if buf[..n] == V2_SIGNATURE[..n] {
+        return parse_v2(buf);
+    }
+    let n = std::cmp::min(buf.len(), V1_PREFIX.len());
+    if buf[..n] == V1_PREFIX[..n] {
+        return parse_v1(buf);
+    }
+    Err(invalid("no header"))
+}
+
+// "PROXY TCP4 <src> <dst> <srcport> <dstport>\r\n", or "PROXY UNKNOWN ...\r\n".
+fn parse_v1(buf: &[u8]) -> io::Result<Parsed> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(Parsed::Incomplete),
+        _ => return Err(invalid("header too long")),
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("bad v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addr = match fields.get(1) {
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let ip = fields[2].parse::<IpAddr>();
+            let port = fields[4].parse::<u16>();
+            match (ip, port) {
+                (Ok(ip), Ok(port)) => Some(SocketAddr::new(ip, port)),
+                _ => return Err(invalid("bad v1 address")),
+            }
+        }
+        Some(&"UNKNOWN") => None,
+        _ => return Err(invalid("bad v1 header")),
+    };
+    Ok(Parsed::Done(end + 2, addr))
+}
+
+// Binary header: signature, version/command, family, length, addresses.
+fn parse_v2(buf: &[u8]) -> io::Result<Parsed> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(Parsed::Incomplete);
+    }
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+    if buf[12] >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    let addrs = &buf[V2_HEADER_LEN..len];
+    let addr = match (buf[12] & 0x0f, buf[13]) {
+        // LOCAL.
+        (0, _) => None,
+        // PROXY, TCP over IPv4.
+        (1, 0x11) if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // PROXY, TCP over IPv6.
+        (1, 0x21) if addrs.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        // Other protocols, or unix sockets: we don't know the client.
+        (1, _) => None,
+        _ => return Err(invalid("unsupported command")),
+    };
+    Ok(Parsed::Done(len, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn done(buf: &[u8]) -> (usize, Option<SocketAddr>) {
+        match parse(buf).unwrap() {
+            Parsed::Done(len, addr) => (len, addr),
+            Parsed::Incomplete => panic!("incomplete"),
+        }
+    }
+
+    fn incomplete(buf: &[u8]) -> bool {
+        matches!(parse(buf), Ok(Parsed::Incomplete))
+    }
+
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family);
+        buf.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(addrs);
+        buf
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+        let (len, addr) = done(buf);
+        assert_eq!(&buf[len..], b"GET /");
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let (len, addr) = done(buf);
+        assert_eq!(len, buf.len());
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        assert_eq!(done(b"PROXY UNKNOWN\r\n"), (15, None));
+    }
+
+    #[test]
+    fn v1_truncated() {
+        assert!(incomplete(b"PROX"));
+        assert!(incomplete(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443"));
+        assert!(incomplete(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r"));
+    }
+
+    #[test]
+    fn v1_oversized() {
+        // No end of line within the maximum length.
+        let mut buf = b"PROXY UNKNOWN ".to_vec();
+        buf.resize(V1_MAX_LEN, b'x');
+        assert!(parse(&buf).is_err());
+        // An end of line, but after the maximum length.
+        buf.resize(200, b'x');
+        buf.extend_from_slice(b"\r\n");
+        assert!(parse(&buf).is_err());
+    }
+
+    #[test]
+    fn v1_invalid() {
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 not.an.ip 198.51.100.1 56324 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n").is_err());
+        assert!(parse(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n").is_err());
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let addrs = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let mut buf = v2(1, 0x11, &addrs);
+        buf.extend_from_slice(b"GET /");
+        let (len, addr) = done(&buf);
+        assert_eq!(&buf[len..], b"GET /");
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_tcp6_with_tlv() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut addrs = src.octets().to_vec();
+        addrs.extend_from_slice(&dst.octets());
+        addrs.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        // A TLV after the addresses is skipped.
+        addrs.extend_from_slice(&[0x04, 0x00, 0x02, b'h', b'i']);
+        let buf = v2(1, 0x21, &addrs);
+        let (len, addr) = done(&buf);
+        assert_eq!(len, buf.len());
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local_and_unix() {
+        let buf = v2(0, 0x00, &[]);
+        assert_eq!(done(&buf), (V2_HEADER_LEN, None));
+        let buf = v2(1, 0x31, &[0u8; 216]);
+        assert_eq!(done(&buf), (buf.len(), None));
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let addrs = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let buf = v2(1, 0x11, &addrs);
+        for n in 1..buf.len() {
+            assert!(incomplete(&buf[..n]), "length {}", n);
+        }
+    }
+
+    #[test]
+    fn v2_short_addresses() {
+        // Too short for the family: the client is unknown.
+        let buf = v2(1, 0x11, &[192, 0, 2, 1]);
+        assert_eq!(done(&buf), (buf.len(), None));
+        let buf = v2(1, 0x21, &[0u8; 12]);
+        assert_eq!(done(&buf), (buf.len(), None));
+    }
+
+    #[test]
+    fn v2_oversized() {
+        // The largest length the header can have is still only waited for.
+        let mut buf = v2(1, 0x00, &[]);
+        buf[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        buf.extend_from_slice(&[0u8; 1000]);
+        assert!(incomplete(&buf));
+        buf.resize(V2_HEADER_LEN + u16::MAX as usize, 0);
+        assert_eq!(done(&buf), (buf.len(), None));
+    }
+
+    #[test]
+    fn v2_invalid() {
+        let mut buf = v2(1, 0x11, &[0u8; 12]);
+        buf[12] = 0x11;
+        assert!(parse(&buf).is_err());
+        let mut buf = v2(1, 0x11, &[0u8; 12]);
+        buf[12] = 0x22;
+        assert!(parse(&buf).is_err());
+    }
+}
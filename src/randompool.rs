@@ -0,0 +1,156 @@
+//!
+//! A shared pool of pre-generated random data.
+//!
+//! Instead of generating random data for every request, streams hand
+//! out slices of a pool that is generated once at startup. To make sure
+//! that long-lived instances do not serve the same bytes forever, the
+//! pool is regenerated in the background, one segment at a time.
+//!
+use std::cmp;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use rand::{Rng, SeedableRng};
+use tokio::task;
+use tokio::time::Duration;
+use tokio_stream::Stream;
+
+use crate::lehmer64::Lehmer64_3 as RandomGenerator;
+
+// Size of one segment of the pool.
+const SEGMENT_SIZE: usize = 1024 * 1024;
+
+// Size of the chunks we send.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The random pool.
+pub struct RandomPool {
+    segments: Vec<RwLock<Bytes>>,
+}
+
+fn generate_segment() -> Bytes {
+    let mut rng = RandomGenerator::seed_from_u64(rand::random());
+    let mut buf = vec![0u8; SEGMENT_SIZE];
+    rng.fill(&mut buf[..]);
+    Bytes::from(buf)
+}
+
+impl RandomPool {
+    /// Create a pool of (at least) `size` bytes.
+    pub fn new(size: u64) -> RandomPool {
+        let num_segments = cmp::max(1, (size as usize + SEGMENT_SIZE - 1) / SEGMENT_SIZE);
+        let segments = (0..num_segments)
+            .map(|_| RwLock::new(generate_segment()))
+            .collect();
+        RandomPool { segments }
+    }
+
+    fn segment(&self, idx: usize) -> Bytes {
+        self.segments[idx].read().unwrap().clone()
+    }
+
+    /// Regenerate the pool, one segment at a time, so that a full
+    /// refresh takes `interval`. Never returns.
+    pub async fn refresh(self: Arc<Self>, interval: Duration) {
+        let step = interval / self.segments.len() as u32;
+        let step = cmp::max(step, Duration::from_millis(10));
+        let mut ticker = tokio::time::interval(step);
+        ticker.tick().await;
+        let mut idx = 0;
+        loop {
+            ticker.tick().await;
+            // Generate in a blocking thread so we do not stall the transfers.
+            let segment = match task::spawn_blocking(generate_segment).await {
+                Ok(segment) => segment,
+                Err(_) => continue,
+            };
+            // Streams that are using the old segment keep their reference.
+            *self.segments[idx].write().unwrap() = segment;
+            idx = (idx + 1) % self.segments.len();
+        }
+    }
+}
+
+/// Stream of random data from the pool.
+pub struct PoolStream {
+    pool: Arc<RandomPool>,
+    length: u64,
+    done: u64,
+    pos: usize,
+}
+
+impl PoolStream {
+    pub fn new(pool: Arc<RandomPool>, length: u64) -> PoolStream {
+        // Start at a random position, so that streams differ.
+        let pos = rand::thread_rng().gen_range(0..pool.segments.len() * SEGMENT_SIZE);
+        PoolStream {
+            pool,
+            length,
+            done: 0,
+            pos,
+        }
+    }
+}
+
+impl Stream for PoolStream {
+    type Item = Result<Bytes, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.done >= self.length {
+            return Poll::Ready(None);
+        }
+        let idx = self.pos / SEGMENT_SIZE;
+        let offset = self.pos % SEGMENT_SIZE;
+        let count = cmp::min(CHUNK_SIZE, SEGMENT_SIZE - offset);
+        let count = cmp::min(count as u64, self.length - self.done) as usize;
+
+        let chunk = self.pool.segment(idx).slice(offset..offset + count);
+        self.done += count as u64;
+        self.pos = (self.pos + count) % (self.pool.segments.len() * SEGMENT_SIZE);
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use tokio_stream::StreamExt;
+
+    use crate::randomstream::{self, Pattern, RngKind};
+
+    const LENGTH: u64 = 4 * 1024 * 1024 * 1024;
+
+    // Bytes per second of a stream that is read until the end.
+    async fn throughput<S>(mut stream: S) -> f64
+    where
+        S: Stream<Item = Result<Bytes, Infallible>> + Unpin,
+    {
+        let start = Instant::now();
+        let mut total = 0;
+        while let Some(Ok(chunk)) = stream.next().await {
+            total += chunk.len() as u64;
+        }
+        assert_eq!(total, LENGTH);
+        total as f64 / start.elapsed().as_secs_f64()
+    }
+
+    // Compares slicing the pool with generating the data. Run with
+    // cargo test --release -- --ignored --nocapture throughput
+    #[tokio::test]
+    #[ignore]
+    async fn throughput_pool_vs_generated() {
+        let pool = Arc::new(RandomPool::new(64 * 1024 * 1024));
+        let rate = throughput(PoolStream::new(pool, LENGTH)).await;
+        println!("random-pool: {:.1} GB/s", rate / 1e9);
+        for &rng in [RngKind::Lehmer64, RngKind::Xoshiro, RngKind::Simd].iter() {
+            let stream = randomstream::stream(Pattern::Random, rng, 1, 0, LENGTH);
+            let rate = throughput(stream).await;
+            println!("rng {:?}: {:.1} GB/s", rng, rate / 1e9);
+        }
+    }
+}
@@ -1,60 +1,275 @@
 use std::cmp;
 use std::convert::Infallible;
+use std::future::Future;
 use std::pin::Pin;
+use std::str::FromStr;
 use std::task::{Context, Poll};
 
 use bytes::Bytes;
+use once_cell::sync::Lazy;
 use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use tokio::sync::oneshot;
 use tokio_stream::Stream;
 
+use crate::genpool;
 use crate::lehmer64::Lehmer64_3 as RandomGenerator;
+use crate::xoshiro::Xoshiro256x4;
 
 const CHUNK_SIZE: usize = 4096;
 const NUM_CHUNKS: usize = 4;
 const BUF_SIZE: usize = CHUNK_SIZE * NUM_CHUNKS;
 
-// Stream of random data.
-pub struct RandomStream {
-    buf: [u8; BUF_SIZE],
-    rng: Option<RandomGenerator>,
+/// What the data looks like.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    // Incompressible.
+    Random,
+    // Compresses to almost nothing.
+    Zeros,
+    // English-like text, compresses about as well as text does.
+    Text,
+}
+
+impl Pattern {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Pattern::Random => "application/binary",
+            Pattern::Zeros => "application/octet-stream",
+            Pattern::Text => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pattern, String> {
+        match s {
+            "random" => Ok(Pattern::Random),
+            "zeros" => Ok(Pattern::Zeros),
+            "text" => Ok(Pattern::Text),
+            _ => Err(format!("unknown pattern: {}", s)),
+        }
+    }
+}
+
+/// The generator of random data.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RngKind {
+    // Three interleaved Lehmer64 generators.
+    #[default]
+    Lehmer64,
+    // xoshiro256++, four lanes.
+    Xoshiro,
+    // The same, with SIMD instructions if the CPU has them.
+    Simd,
+}
+
+/// Generates the data of a stream, a chunk at a time. The data must only
+/// depend on the position in the stream, so that a range can be sent.
+pub trait Generator: Send + Unpin + 'static {
+    // Generate the buffer in the background while the previous
+    // one is being sent.
+    const PIPELINE: bool;
+
+    /// Generate the next `len` bytes, starting at a chunk boundary.
+    fn generate(&mut self, len: usize) -> Bytes;
+
+    /// Skip `n` chunks.
+    fn skip_chunks(&mut self, n: u64);
+}
+
+impl Generator for RandomGenerator {
+    const PIPELINE: bool = true;
+
+    // The generator always advances by whole chunks, so the data
+    // does not depend on the buffer size.
+    fn generate(&mut self, len: usize) -> Bytes {
+        let mut buf = vec![0u8; (len + CHUNK_SIZE - 1) / CHUNK_SIZE * CHUNK_SIZE];
+        for chunk in buf.chunks_mut(CHUNK_SIZE) {
+            self.fill(chunk);
+        }
+        buf.truncate(len);
+        Bytes::from(buf)
+    }
+
+    // Every chunk takes CHUNK_SIZE / 8 outputs of the generator.
+    fn skip_chunks(&mut self, n: u64) {
+        self.skip(n * (CHUNK_SIZE / 8) as u64);
+    }
+}
+
+/// xoshiro256++ with a freshly seeded generator for every chunk, so
+/// that skipping ahead is cheap.
+pub struct Xoshiro {
+    seed: u64,
+    chunk: u64,
+    simd: bool,
+}
+
+impl Generator for Xoshiro {
+    const PIPELINE: bool = true;
+
+    fn generate(&mut self, len: usize) -> Bytes {
+        let mut buf = vec![0u8; (len + CHUNK_SIZE - 1) / CHUNK_SIZE * CHUNK_SIZE];
+        for chunk in buf.chunks_mut(CHUNK_SIZE) {
+            Xoshiro256x4::new(self.seed, self.chunk).fill(chunk, self.simd);
+            self.chunk += 1;
+        }
+        buf.truncate(len);
+        Bytes::from(buf)
+    }
+
+    fn skip_chunks(&mut self, n: u64) {
+        self.chunk += n;
+    }
+}
+
+// A buffer of the same chunk over and over.
+static ZEROS: Lazy<Bytes> = Lazy::new(|| Bytes::from(vec![0u8; BUF_SIZE]));
+static TEXT: Lazy<Bytes> = Lazy::new(|| {
+    let text = b"The quick brown fox jumps over the lazy dog. \
+                 Pack my box with five dozen liquor jugs.\n";
+    let chunk: Vec<u8> = text.iter().cycle().take(CHUNK_SIZE).cloned().collect();
+    Bytes::from(chunk.repeat(NUM_CHUNKS))
+});
+
+/// The same chunk repeated, for the zeros and text patterns.
+/// Nothing is generated, the buffers are slices of a static one.
+pub struct Repeat(&'static Bytes);
+
+impl Generator for Repeat {
+    const PIPELINE: bool = false;
+
+    fn generate(&mut self, len: usize) -> Bytes {
+        self.0.slice(..len)
+    }
+
+    fn skip_chunks(&mut self, _n: u64) {}
+}
+
+/// A stream of `length` bytes of `pattern`, starting at `offset`.
+/// The generator and seed are only used for random data.
+pub fn stream(
+    pattern: Pattern,
+    rng: RngKind,
+    seed: u64,
+    offset: u64,
+    length: u64,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, Infallible>> + Send>> {
+    let xoshiro = |simd| Xoshiro {
+        seed,
+        chunk: 0,
+        simd,
+    };
+    match (pattern, rng) {
+        (Pattern::Random, RngKind::Lehmer64) => {
+            Box::pin(RandomStream::with_seed(seed, offset, length))
+        }
+        (Pattern::Random, RngKind::Xoshiro) => {
+            Box::pin(RandomStream::new(xoshiro(false), offset, length))
+        }
+        (Pattern::Random, RngKind::Simd) => {
+            Box::pin(RandomStream::new(xoshiro(true), offset, length))
+        }
+        (Pattern::Zeros, _) => Box::pin(RandomStream::new(Repeat(&ZEROS), offset, length)),
+        (Pattern::Text, _) => Box::pin(RandomStream::new(Repeat(&TEXT), offset, length)),
+    }
+}
+
+// Stream of generated data, random by default.
+//
+// This is a two-buffer pipeline: when a buffer is returned, generation
+// of the next one is started in the background, so that it runs while
+// the previous buffer is written to the socket. That is done on the
+// generator pool if there is one, otherwise in a separate task.
+pub struct RandomStream<G: Generator = RandomGenerator> {
+    rng: Option<G>,
+    // Bytes to leave out at the start of the first buffer.
+    head: usize,
+    next: Option<oneshot::Receiver<(G, Bytes)>>,
     length: u64,
     done: u64,
 }
 
+fn generate<G: Generator>(mut rng: G, len: usize) -> (G, Bytes) {
+    let buf = rng.generate(len);
+    (rng, buf)
+}
+
 impl RandomStream {
-    // create a new RandomStream.
-    pub fn new(length: u64) -> RandomStream {
+    // create a new RandomStream that starts at `offset`. The data only
+    // depends on the seed, so a range of it can be sent.
+    pub fn with_seed(seed: u64, offset: u64, length: u64) -> RandomStream {
+        RandomStream::new(RandomGenerator::seed_from_u64(seed), offset, length)
+    }
+}
+
+impl<G: Generator> RandomStream<G> {
+    // create a stream of the data of `rng`, starting at `offset`.
+    pub fn new(mut rng: G, offset: u64, length: u64) -> RandomStream<G> {
+        rng.skip_chunks(offset / CHUNK_SIZE as u64);
         RandomStream {
-            buf: [0u8; BUF_SIZE],
-            rng: Some(RandomGenerator::seed_from_u64(0)),
-            length: length,
+            rng: Some(rng),
+            head: (offset % CHUNK_SIZE as u64) as usize,
+            next: None,
+            length,
             done: 0,
         }
     }
+
+    fn want(&self) -> usize {
+        cmp::min(self.length - self.done, BUF_SIZE as u64) as usize
+    }
 }
 
-impl Stream for RandomStream {
+impl<G: Generator> Stream for RandomStream<G> {
     type Item = Result<Bytes, Infallible>;
 
-    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let this = self;
-        tokio::pin!(this);
-        if this.done >= this.length {
-            Poll::Ready(None)
-        } else {
-            // generate block of random data.
-            let count = cmp::min(this.length - this.done, BUF_SIZE as u64);
-            let mut rng = this.rng.take().unwrap();
-            for i in 0..NUM_CHUNKS {
-                let start = i * CHUNK_SIZE;
-                let end = (i + 1) * CHUNK_SIZE;
-                rng.fill(&mut this.buf[start..end]);
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.done >= self.length {
+            return Poll::Ready(None);
+        }
+
+        // Get the buffer that was generated in the background or,
+        // the first time, generate it right here.
+        let (rng, buf) = match self.next.as_mut() {
+            Some(next) => match Pin::new(next).poll(cx) {
+                Poll::Ready(Ok(res)) => res,
+                Poll::Ready(Err(_)) => panic!("random data generation failed"),
+                Poll::Pending => return Poll::Pending,
+            },
+            None => {
+                // The first buffer ends on a BUF_SIZE boundary, so the
+                // buffers after it are aligned.
+                let head = std::mem::take(&mut self.head);
+                let want = cmp::min(self.want(), BUF_SIZE - head);
+                let (rng, buf) = generate(self.rng.take().unwrap(), head + want);
+                (rng, buf.slice(head..))
             }
-            this.rng = Some(rng);
-            this.done += count;
-            Poll::Ready(Some(Ok(Bytes::copy_from_slice(
-                &this.buf[0..count as usize],
-            ))))
+        };
+        self.next = None;
+        self.done += buf.len() as u64;
+
+        // Start generating the next one, or just keep the
+        // generator if that is cheap.
+        if self.done < self.length && !G::PIPELINE {
+            self.rng = Some(rng);
+        } else if self.done < self.length {
+            let want = self.want();
+            let (tx, rx) = oneshot::channel();
+            let job: genpool::Job = Box::new(move || {
+                let _ = tx.send(generate(rng, want));
+            });
+            if let Err(job) = genpool::spawn(job) {
+                tokio::spawn(async move { job() });
+            }
+            self.next = Some(rx);
         }
+
+        Poll::Ready(Some(Ok(buf)))
     }
 }
@@ -1,41 +1,80 @@
 //! Helper functions and filters.
 //!
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use warp::Filter;
 
-// Get the first IP address from a comma-separared list.
-fn parse_xff(s: &str) -> Option<SocketAddr> {
-    s.split(",").next().map(|s| s.trim()).and_then(|s| {
-        // Now try to parse as IpAddr or SocketAddr.
-        s.parse::<IpAddr>()
-            .map(|i| SocketAddr::new(i, 0))
-            .or_else(|_| s.parse::<SocketAddr>())
-            .ok()
-    })
+use crate::cidr::{self, Cidr};
+use crate::listener::{self, ConnInfo};
+use crate::Config;
+
+/// The peers whose forwarding headers we believe.
+#[derive(Clone, Debug, Default)]
+pub struct Trusted {
+    // use-xff-headers: any peer.
+    all: bool,
+    // trusted-proxies.
+    proxies: Vec<Cidr>,
+}
+
+impl Trusted {
+    pub fn new(config: &Config) -> Trusted {
+        Trusted {
+            all: config.xff,
+            proxies: config.trusted_proxies.clone(),
+        }
+    }
+
+    // Localhost is always trusted.
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = cidr::canonical(ip);
+        self.all || ip.is_loopback() || self.proxies.iter().any(|c| c.contains(&ip))
+    }
+
+    // Walk a list of hops from the right (the one closest to us) to the
+    // first one that is not a trusted proxy. If they all are, that is
+    // the leftmost one.
+    fn client(
+        &self,
+        hops: impl DoubleEndedIterator<Item = Option<SocketAddr>>,
+    ) -> Option<SocketAddr> {
+        let mut client = None;
+        for hop in hops.rev() {
+            // Garbage in the chain, stop at the last good hop.
+            let hop = match hop {
+                Some(hop) => hop,
+                None => break,
+            };
+            client = Some(hop);
+            if !self.contains(hop.ip()) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+// Parse an IP address, with or without a port.
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    let s = s.trim().trim_matches('"');
+    s.parse::<IpAddr>()
+        .map(|i| SocketAddr::new(i, 0))
+        .or_else(|_| s.parse::<SocketAddr>())
+        .ok()
 }
 
-// Get the first for=<ipaddress>.
+// Get the for=<ipaddress> of one element of a Forwarded header.
 fn parse_fwd(s: &str) -> Option<SocketAddr> {
-    // Of a list of comma-separated fields, get the first one.
-    let field = s.split(",").map(|s| s.trim()).next()?;
-    // Then split at ';' into fields again, lowercase, and find "for="
-    field
-        .split(";")
+    // Split at ';' into fields, lowercase, and find "for="
+    s.split(';')
         .map(|s| s.trim().to_lowercase())
         .find(|s| s.starts_with("for="))
-        .and_then(|s| {
-            let s = s[4..].trim_matches('"');
-            // Now try to parse as IpAddr or SocketAddr.
-            s.parse::<IpAddr>()
-                .map(|i| SocketAddr::new(i, 0))
-                .or_else(|_| s.parse::<SocketAddr>())
-                .ok()
-        })
+        .and_then(|s| parse_addr(&s[4..]))
 }
 
 pub fn parse(
     addr: Option<SocketAddr>,
-    xff_headers: bool,
+    trusted: &Trusted,
     xff: Option<impl AsRef<str>>,
     xri: Option<impl AsRef<str>>,
     fwd: Option<impl AsRef<str>>,
@@ -43,40 +82,36 @@ pub fn parse(
     let xff = xff.as_ref().map(|s| s.as_ref());
     let xri = xri.as_ref().map(|s| s.as_ref());
     let fwd = fwd.as_ref().map(|s| s.as_ref());
-    let is_loopback = match addr {
-        Some(SocketAddr::V4(ref addr)) => addr.ip().is_loopback(),
-        Some(SocketAddr::V6(ref addr)) => addr.ip().is_loopback(),
-        None => false,
-    };
-    if is_loopback || xff_headers {
-        // parse X-Forwarded-For, if present.
-        if let Some(ref v) = xff {
-            if let Some(addr) = parse_xff(v) {
-                return Some(addr);
-            }
-        }
-        // parse X-Real-Ip, if present.
-        if let Some(ref v) = xri {
-            if let Some(addr) = parse_xff(v) {
-                return Some(addr);
-            }
+    match addr {
+        Some(addr) if trusted.contains(addr.ip()) => {}
+        _ => return addr,
+    }
+    // parse X-Forwarded-For, if present.
+    if let Some(v) = xff {
+        if let Some(addr) = trusted.client(v.split(',').map(parse_addr)) {
+            return Some(addr);
         }
-        // parse Forwarded, if present.
-        if let Some(ref v) = fwd {
-            if let Some(addr) = parse_fwd(v) {
-                return Some(addr);
-            }
+    }
+    // parse X-Real-Ip, if present.
+    if let Some(addr) = xri.and_then(parse_addr) {
+        return Some(addr);
+    }
+    // parse Forwarded, if present.
+    if let Some(v) = fwd {
+        if let Some(addr) = trusted.client(v.split(',').map(parse_fwd)) {
+            return Some(addr);
         }
     }
     addr
 }
 
-/// Like `warp::addr::remote()` but also takes XFF into account.
+/// Like `warp::addr::remote()` but for our own listeners, and also takes XFF into account.
 #[allow(dead_code)]
 pub fn remoteip(
-    do_xff: bool,
-) -> impl Filter<Extract = (Option<SocketAddr>,), Error = warp::reject::Rejection> + Copy {
-    warp::addr::remote()
+    trusted: Trusted,
+) -> impl Filter<Extract = (Option<SocketAddr>,), Error = warp::reject::Rejection> + Clone {
+    listener::conn_info()
+        .map(|conn: Option<Arc<ConnInfo>>| conn.map(|c| c.remote_addr))
         .and(warp::header::optional::<String>("X-Forwarded-For"))
         .and(warp::header::optional::<String>("X-Real-Ip"))
         .and(warp::header::optional::<String>("Forwarded"))
@@ -84,6 +119,6 @@ pub fn remoteip(
             move |addr: Option<SocketAddr>,
                   xff: Option<String>,
                   xri: Option<String>,
-                  fwd: Option<String>| { parse(addr, do_xff, xff, xri, fwd) },
+                  fwd: Option<String>| { parse(addr, &trusted, xff, xri, fwd) },
         )
 }
@@ -0,0 +1,101 @@
+//!
+//! Periodic reporting to a central aggregator.
+//!
+use std::sync::Arc;
+
+use chrono::offset::Utc;
+use hyper::{Body, Client, Request};
+use ring::hmac;
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+use crate::load::LoadMonitor;
+use crate::shutdown;
+use crate::stats::{PublicStats, Stalls, Stats};
+use crate::Report;
+
+// Timeout for one report.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize)]
+struct ReportBody<'a> {
+    instance: &'a str,
+    version: &'a str,
+    timestamp: i64,
+    uptime: u64,
+    status: &'a str,
+    panics: u64,
+    stalls: Stalls,
+    stats: PublicStats,
+}
+
+/// The default instance name.
+pub fn hostname() -> String {
+    hostname::get()
+        .map(|h| h.to_string_lossy().to_string())
+        .unwrap_or_else(|_| String::from("unknown"))
+}
+
+/// Send a report every `interval` seconds. Never returns.
+///
+/// If a secret is configured, the JSON body is signed with HMAC-SHA256
+/// and the signature is sent in the `X-Signature: sha256=<hex>` header.
+pub async fn run(config: Report, stats: Arc<Stats>, load: Arc<LoadMonitor>) {
+    let connector = hyper_rustls::HttpsConnector::with_native_roots();
+    let client = Client::builder().build::<_, Body>(connector);
+    let key = config
+        .secret
+        .as_ref()
+        .map(|s| hmac::Key::new(hmac::HMAC_SHA256, s.as_bytes()));
+    let instance = config.instance_id.clone().unwrap_or_else(hostname);
+    let started = Instant::now();
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval));
+    loop {
+        interval.tick().await;
+
+        let body = ReportBody {
+            instance: &instance,
+            version: env!("CARGO_PKG_VERSION"),
+            timestamp: Utc::now().timestamp(),
+            uptime: started.elapsed().as_secs(),
+            status: status(&load),
+            panics: stats.panics(),
+            stalls: stats.stalls(),
+            stats: stats.public(),
+        };
+        let body = serde_json::to_string(&body).unwrap();
+
+        let mut req = Request::post(config.url.as_str()).header("content-type", "application/json");
+        if let Some(key) = key.as_ref() {
+            let tag = hmac::sign(key, body.as_bytes());
+            let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+            req = req.header("x-signature", format!("sha256={}", hex));
+        }
+        let req = match req.body(Body::from(body)) {
+            Ok(req) => req,
+            Err(e) => {
+                log::error!("report: {}: {}", config.url, e);
+                return;
+            }
+        };
+
+        match tokio::time::timeout(REPORT_TIMEOUT, client.request(req)).await {
+            Ok(Ok(resp)) if resp.status().is_success() => {}
+            Ok(Ok(resp)) => log::warn!("report: {}: {}", config.url, resp.status()),
+            Ok(Err(e)) => log::warn!("report: {}: {}", config.url, e),
+            Err(_) => log::warn!("report: {}: timeout", config.url),
+        }
+    }
+}
+
+// "draining" while shutting down, "overloaded" while load shedding.
+fn status(load: &LoadMonitor) -> &'static str {
+    if shutdown::is_started() {
+        "draining"
+    } else if load.overloaded() {
+        "overloaded"
+    } else {
+        "ok"
+    }
+}
@@ -0,0 +1,160 @@
+//!
+//! Server-side results of recent transfers.
+//!
+//! Every download gets an id, which is sent to the client in the
+//! `X-Request-Id` header. When the transfer is done, the client can
+//! fetch `/result/<id>` to see what the server measured.
+//!
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use rand::Rng;
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+use crate::listener::ConnInfo;
+use crate::tcpinfo::TcpInfo;
+
+// How long we keep results around.
+const RESULT_TTL: Duration = Duration::from_secs(300);
+
+// And how many, at most.
+const MAX_RESULTS: usize = 100_000;
+
+/// The result of one transfer.
+#[derive(Clone, Debug, Serialize)]
+pub struct TransferResult {
+    id: String,
+    size: u64,
+    bytes: u64,
+    complete: bool,
+    // in seconds.
+    duration: f64,
+    // in bits/sec.
+    throughput: f64,
+    // the part after the warm-up data.
+    warmup: u64,
+    measured_bytes: u64,
+    measured_duration: f64,
+    measured_throughput: f64,
+    tcp_info: Option<TcpInfo>,
+}
+
+#[derive(Default)]
+struct Inner {
+    results: HashMap<String, TransferResult>,
+    expire: VecDeque<(Instant, String)>,
+}
+
+/// Recent results.
+pub struct Results {
+    inner: Mutex<Inner>,
+}
+
+impl Results {
+    pub fn new() -> Results {
+        Results {
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<TransferResult> {
+        self.inner.lock().unwrap().results.get(id).cloned()
+    }
+
+    fn insert(&self, result: TransferResult) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        while let Some((when, id)) = inner.expire.front() {
+            if now.duration_since(*when) < RESULT_TTL && inner.expire.len() < MAX_RESULTS {
+                break;
+            }
+            let id = id.clone();
+            inner.results.remove(&id);
+            inner.expire.pop_front();
+        }
+        inner.expire.push_back((now, result.id.clone()));
+        inner.results.insert(result.id.clone(), result);
+    }
+}
+
+/// Generate a new request id.
+pub fn request_id() -> String {
+    let id: u128 = rand::thread_rng().gen();
+    format!("{:032x}", id)
+}
+
+// bits per second.
+fn throughput(bytes: u64, duration: Duration) -> f64 {
+    let secs = duration.as_secs_f64();
+    if secs > 0f64 {
+        (bytes * 8) as f64 / secs
+    } else {
+        0f64
+    }
+}
+
+/// Measures one transfer, and stores the result when dropped.
+pub struct ResultRecorder {
+    results: Arc<Results>,
+    id: String,
+    size: u64,
+    warmup: u64,
+    bytes: u64,
+    start: Option<Instant>,
+    measured_start: Option<Instant>,
+    conn: Option<Arc<ConnInfo>>,
+}
+
+impl ResultRecorder {
+    pub fn new(
+        results: Arc<Results>,
+        id: String,
+        size: u64,
+        warmup: u64,
+        conn: Option<Arc<ConnInfo>>,
+    ) -> ResultRecorder {
+        ResultRecorder {
+            results,
+            id,
+            size,
+            warmup,
+            bytes: 0,
+            start: None,
+            measured_start: None,
+            conn,
+        }
+    }
+
+    /// Call this just before a chunk is sent.
+    pub fn add_bytes(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.start.get_or_insert(now);
+        if self.bytes >= self.warmup {
+            self.measured_start.get_or_insert(now);
+        }
+        self.bytes += bytes;
+    }
+}
+
+impl Drop for ResultRecorder {
+    fn drop(&mut self) {
+        let now = Instant::now();
+        let duration = now - self.start.unwrap_or(now);
+        let measured_duration = now - self.measured_start.unwrap_or(now);
+        let measured_bytes = self.bytes.saturating_sub(self.warmup);
+        self.results.insert(TransferResult {
+            id: self.id.clone(),
+            size: self.size,
+            bytes: self.bytes,
+            complete: self.bytes >= self.size + self.warmup,
+            duration: duration.as_secs_f64(),
+            throughput: throughput(self.bytes, duration),
+            warmup: self.warmup,
+            measured_bytes,
+            measured_duration: measured_duration.as_secs_f64(),
+            measured_throughput: throughput(measured_bytes, measured_duration),
+            tcp_info: self.conn.as_ref().and_then(|c| c.tcp_info()),
+        });
+    }
+}
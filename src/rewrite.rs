@@ -0,0 +1,80 @@
+//!
+//! Path rewrites and redirects, for keeping legacy URLs alive.
+//!
+use std::collections::HashMap;
+use std::io;
+
+use http::{Request, Response, StatusCode, Uri};
+use hyper::Body;
+
+use crate::Config;
+
+enum Action {
+    Rewrite(String),
+    Redirect(Uri, StatusCode),
+}
+
+/// Map of old paths to new paths or URLs.
+pub struct PathMap {
+    map: HashMap<String, Action>,
+}
+
+fn invalid(path: &str, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("path {}: {}", path, msg),
+    )
+}
+
+impl PathMap {
+    pub fn new(config: &Config) -> io::Result<PathMap> {
+        let mut map = HashMap::new();
+        for p in &config.paths {
+            let action = match (p.rewrite.as_ref(), p.redirect.as_ref()) {
+                (Some(path), None) => {
+                    if !path.starts_with('/') {
+                        return Err(invalid(&p.path, "rewrite must start with /"));
+                    }
+                    Action::Rewrite(path.clone())
+                }
+                (None, Some(uri)) => {
+                    let status = match p.status.unwrap_or(301) {
+                        301 => StatusCode::MOVED_PERMANENTLY,
+                        302 => StatusCode::FOUND,
+                        307 => StatusCode::TEMPORARY_REDIRECT,
+                        308 => StatusCode::PERMANENT_REDIRECT,
+                        _ => return Err(invalid(&p.path, "status must be 301, 302, 307 or 308")),
+                    };
+                    Action::Redirect(uri.clone(), status)
+                }
+                _ => return Err(invalid(&p.path, "need one of rewrite or redirect")),
+            };
+            map.insert(p.path.clone(), action);
+        }
+        Ok(PathMap { map })
+    }
+
+    /// Rewrite the path of the request, or return a redirect.
+    pub fn apply<B>(&self, req: &mut Request<B>) -> Option<Response<Body>> {
+        match self.map.get(req.uri().path())? {
+            Action::Rewrite(path) => {
+                // keep the query string, unless the new path has one.
+                let path_and_query = match req.uri().query() {
+                    Some(query) if !path.contains('?') => format!("{}?{}", path, query),
+                    _ => path.clone(),
+                };
+                let mut parts = req.uri().clone().into_parts();
+                parts.path_and_query = path_and_query.parse().ok();
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+                None
+            }
+            Action::Redirect(uri, status) => Response::builder()
+                .status(*status)
+                .header("location", uri.to_string())
+                .body(Body::empty())
+                .ok(),
+        }
+    }
+}
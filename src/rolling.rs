@@ -0,0 +1,235 @@
+//!
+//! Rolling statistics, for /stats.
+//!
+//! The data served, by client network, and the throughput of finished
+//! downloads are kept per minute, for the last 15 minutes.
+//!
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::cidr::{self, Cidr};
+use crate::metrics;
+
+// Minutes that are kept, the longest window.
+const WINDOW: u64 = 15;
+
+// How many client networks are listed.
+const TOP_NETWORKS: usize = 10;
+
+// Clients are grouped per /24 (IPv4) or /48 (IPv6).
+const PREFIX_V4: u8 = 24;
+const PREFIX_V6: u8 = 48;
+
+// Downloads add their bytes to the totals about this often, not for
+// every chunk, which would take the lock each time.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Minute {
+    minute: u64,
+    bytes: u64,
+    networks: HashMap<Cidr, u64>,
+    // Counts per metrics::THROUGHPUT_BUCKETS, the last one is +Inf.
+    speeds: Vec<u64>,
+}
+
+impl Minute {
+    fn new(minute: u64) -> Minute {
+        Minute {
+            minute,
+            speeds: vec![0; metrics::THROUGHPUT_BUCKETS.len() + 1],
+            ..Minute::default()
+        }
+    }
+}
+
+/// The statistics of the last 15 minutes.
+pub struct Rolling {
+    start: Instant,
+    minutes: Mutex<Vec<Minute>>,
+}
+
+/// What /stats shows.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    active_streams: u64,
+    bytes_1m: u64,
+    bytes_5m: u64,
+    bytes_15m: u64,
+    top_networks: Vec<Network>,
+    throughput: Vec<Bucket>,
+}
+
+#[derive(Debug, Serialize)]
+struct Network {
+    network: String,
+    bytes: u64,
+}
+
+// Number of downloads with a throughput up to `le` bits per second
+// (and above the previous bucket). The last one has no limit.
+#[derive(Debug, Serialize)]
+struct Bucket {
+    le: Option<f64>,
+    count: u64,
+}
+
+// The network of a client, like 192.0.2.0/24.
+fn network(ip: IpAddr) -> Option<Cidr> {
+    let ip = cidr::canonical(ip);
+    let (width, prefix_len) = match ip {
+        IpAddr::V4(_) => (32, PREFIX_V4),
+        IpAddr::V6(_) => (128, PREFIX_V6),
+    };
+    let net = cidr::mask(cidr::ip_to_u128(ip), width, prefix_len);
+    let addr = match ip {
+        IpAddr::V4(_) => IpAddr::from((net as u32).to_be_bytes()),
+        IpAddr::V6(_) => IpAddr::from(net.to_be_bytes()),
+    };
+    Cidr::new(addr, prefix_len)
+}
+
+impl Rolling {
+    pub fn new() -> Rolling {
+        Rolling {
+            start: Instant::now(),
+            minutes: Mutex::new((0..WINDOW).map(|_| Minute::new(0)).collect()),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_secs() / 60
+    }
+
+    // Run `f` on the slot of the current minute.
+    fn update(&self, f: impl FnOnce(&mut Minute)) {
+        let now = self.now();
+        let mut minutes = self.minutes.lock().unwrap();
+        let slot = &mut minutes[(now % WINDOW) as usize];
+        if slot.minute != now {
+            *slot = Minute::new(now);
+        }
+        f(slot)
+    }
+
+    fn add_bytes(&self, network: Option<Cidr>, bytes: u64) {
+        self.update(|m| {
+            m.bytes += bytes;
+            if let Some(network) = network {
+                *m.networks.entry(network).or_insert(0) += bytes;
+            }
+        });
+    }
+
+    fn add_speed(&self, throughput: f64) {
+        let idx = metrics::THROUGHPUT_BUCKETS
+            .iter()
+            .position(|b| throughput <= *b)
+            .unwrap_or(metrics::THROUGHPUT_BUCKETS.len());
+        self.update(|m| m.speeds[idx] += 1);
+    }
+
+    /// The statistics right now.
+    pub fn snapshot(&self, active_streams: u64) -> Snapshot {
+        let now = self.now();
+        let minutes = self.minutes.lock().unwrap();
+        let recent = |n: u64| {
+            minutes
+                .iter()
+                .filter(move |m| m.minute + n > now && m.minute <= now)
+        };
+        let bytes = |n: u64| recent(n).map(|m| m.bytes).sum();
+
+        let mut networks: HashMap<Cidr, u64> = HashMap::new();
+        let mut speeds = vec![0; metrics::THROUGHPUT_BUCKETS.len() + 1];
+        for m in recent(WINDOW) {
+            for (network, bytes) in &m.networks {
+                *networks.entry(*network).or_insert(0) += bytes;
+            }
+            for (total, count) in speeds.iter_mut().zip(m.speeds.iter()) {
+                *total += count;
+            }
+        }
+        let mut top_networks: Vec<_> = networks
+            .into_iter()
+            .map(|(network, bytes)| Network {
+                network: network.to_string(),
+                bytes,
+            })
+            .collect();
+        top_networks.sort_by_key(|n| Reverse(n.bytes));
+        top_networks.truncate(TOP_NETWORKS);
+
+        let bounds = metrics::THROUGHPUT_BUCKETS.iter().map(|b| Some(*b));
+        let throughput = bounds
+            .chain(std::iter::once(None))
+            .zip(speeds)
+            .map(|(le, count)| Bucket { le, count })
+            .collect();
+
+        Snapshot {
+            active_streams,
+            bytes_1m: bytes(1),
+            bytes_5m: bytes(5),
+            bytes_15m: bytes(WINDOW),
+            top_networks,
+            throughput,
+        }
+    }
+}
+
+/// Counts the bytes of one download, and its throughput when done.
+pub struct TransferGuard {
+    rolling: Arc<Rolling>,
+    network: Option<Cidr>,
+    bytes: u64,
+    // Bytes not yet added to the totals, and when that was last done.
+    unflushed: u64,
+    flushed: Instant,
+    start: Instant,
+}
+
+impl TransferGuard {
+    pub fn new(rolling: Arc<Rolling>, client: Option<IpAddr>) -> TransferGuard {
+        let start = Instant::now();
+        TransferGuard {
+            rolling,
+            network: client.and_then(network),
+            bytes: 0,
+            unflushed: 0,
+            flushed: start,
+            start,
+        }
+    }
+
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.unflushed += bytes;
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.unflushed > 0 {
+            self.rolling.add_bytes(self.network, self.unflushed);
+            self.unflushed = 0;
+        }
+        self.flushed = Instant::now();
+    }
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.flush();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if self.bytes > 0 && elapsed > 0f64 {
+            self.rolling.add_speed((self.bytes * 8) as f64 / elapsed);
+        }
+    }
+}
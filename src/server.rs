@@ -1,44 +1,275 @@
 //!
 //! All the actual API handlers.
 //!
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
 
-use http::{Response, StatusCode};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use bytes::{Buf, Bytes};
+use chrono::{offset::Utc, DateTime};
+use futures::FutureExt;
+use http::uri::Authority;
+use http::{Method, Response, StatusCode};
 use human_size::{Byte, ParsingError, Size, SpecificSize};
-use hyper::body::Body;
-use tokio_stream::StreamExt;
+use hyper::body::{Body, HttpBody};
+use ring::digest;
+use serde::Deserialize;
 use tokio::time::{Duration, Instant};
+use tokio_stream::{Stream, StreamExt};
+use warp::path::FullPath;
 use warp::reply::Response as HyperResponse;
 use warp::{filters::BoxedFilter, Filter, Reply};
 
-use crate::logger::LogInfo;
-use crate::randomstream::RandomStream;
+use crate::accounting::Accounting;
+use crate::acme;
+use crate::auth::{self, Denied};
+use crate::cidr;
+use crate::clients::{self, ClientGuard, Clients, Refused};
+use crate::cors;
+use crate::discovery;
+use crate::load::LoadMonitor;
+use crate::logger::{AccessLog, LogInfo, Received, Streamed};
+use crate::openapi;
+use crate::policy::{Policies, QuotaGuard};
+use crate::randompool::{PoolStream, RandomPool};
+use crate::randomstream::{self, Pattern};
+use crate::remoteip::Trusted;
+use crate::results::{self, ResultRecorder, Results};
+use crate::rewrite::PathMap;
+use crate::rolling::{Rolling, TransferGuard};
+use crate::stall::StreamTimer;
+use crate::stats::{Stats, StreamGuard};
 use crate::template;
+use crate::throttle::{SharedBucket, TokenBucket};
+use crate::ws;
 use crate::Config;
 
-// Relative timeout.
-const SEND_TIMEOUT: Duration = Duration::from_secs(20);
+// The methods of the routes. Paths are matched first, so that a known
+// path with another method gets a 405 with these in the Allow header.
+const GET_HEAD: &[Method] = &[Method::GET, Method::HEAD];
+const GET_ONLY: &[Method] = &[Method::GET];
+const PUT_ONLY: &[Method] = &[Method::PUT];
+const POST_PUT: &[Method] = &[Method::POST, Method::PUT];
+const DATA_TR143: &[Method] = &[Method::GET, Method::HEAD, Method::PUT];
+
+// How long browsers may cache the assets of the index page.
+const ASSET_CACHE_CONTROL: &str = "public, max-age=3600";
 
 // 10GiB is the default max size we support.
-const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+pub const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+// Longest duration download, like /300s.
+const MAX_DURATION: Duration = Duration::from_secs(300);
+
+/// Query parameters for data requests.
+#[derive(Debug, Default, Deserialize)]
+pub struct DataQuery {
+    // Send this much data before the measured part.
+    warmup: Option<String>,
+    // TCP congestion control algorithm.
+    cc: Option<String>,
+    // What the data looks like: random, zeros or text.
+    pattern: Option<String>,
+    // Seed of the random data, to get the same data again.
+    seed: Option<String>,
+    // Send a checksum of the body as a trailer: sha256.
+    checksum: Option<String>,
+}
+
+impl DataQuery {
+    // Unparseable query strings are ignored, just like unknown parameters.
+    fn filter() -> impl Filter<Extract = (DataQuery,), Error = Infallible> + Clone {
+        warp::query::<DataQuery>()
+            .or(warp::any().map(DataQuery::default))
+            .unify()
+    }
+}
 
 #[derive(Clone)]
 pub struct FileServer {
-    config: Arc<Config>,
-    access_log: Option<Arc<Mutex<String>>>,
+    config: Arc<ArcSwap<Config>>,
+    access_log: Arc<ArcSwapOption<AccessLog>>,
+    started: DateTime<Utc>,
+    policies: Arc<ArcSwap<Policies>>,
+    stats: Arc<Stats>,
+    rolling: Option<Arc<Rolling>>,
+    load: Arc<LoadMonitor>,
+    pool: Option<Arc<RandomPool>>,
+    results: Arc<Results>,
+    paths: Arc<ArcSwap<PathMap>>,
+    bandwidth: Arc<ArcSwapOption<SharedBucket>>,
+    clients: Arc<Clients>,
 }
 
 impl FileServer {
-    pub fn new(config: &Config) -> FileServer {
-        let access_log = config.access_log.clone();
-        FileServer {
-            config: Arc::new(config.clone()),
-            access_log: access_log.map(|a| Arc::new(Mutex::new(a))),
+    pub fn new(config: &Config) -> io::Result<FileServer> {
+        Ok(FileServer {
+            config: Arc::new(ArcSwap::from_pointee(config.clone())),
+            access_log: Arc::new(ArcSwapOption::new(AccessLog::new(config))),
+            started: Utc::now(),
+            policies: Arc::new(ArcSwap::from_pointee(Policies::new(config)?)),
+            stats: Arc::new(Stats::new(Accounting::load(
+                config.accounting_file.clone(),
+            )?)),
+            rolling: Some(Arc::new(Rolling::new())).filter(|_| config.live_stats),
+            load: Arc::new(LoadMonitor::new()),
+            pool: config
+                .random_pool
+                .as_ref()
+                .map(|p| Arc::new(RandomPool::new(p.size.unwrap_or(0)))),
+            results: Arc::new(Results::new()),
+            paths: Arc::new(ArcSwap::from_pointee(PathMap::new(config)?)),
+            bandwidth: Arc::new(ArcSwapOption::new(
+                config
+                    .max_total_bandwidth
+                    .map(|rate| Arc::new(SharedBucket::new(rate))),
+            )),
+            clients: Arc::new(Clients::new()),
+        })
+    }
+
+    // The current configuration.
+    fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    /// Switch to a new configuration. Settings that change the routes
+    /// (which endpoints are enabled) only take effect after a restart.
+    pub fn reload(&self, config: &Config) -> io::Result<()> {
+        // Build everything first, so that an error leaves the old
+        // configuration in place.
+        let policies = self.policies.load().reload(config)?;
+        let paths = PathMap::new(config)?;
+        if self.config().max_total_bandwidth != config.max_total_bandwidth {
+            self.bandwidth.store(
+                config
+                    .max_total_bandwidth
+                    .map(|rate| Arc::new(SharedBucket::new(rate))),
+            );
+        }
+        // A new access log has a new writer thread, only start one if
+        // the settings it uses changed.
+        let log_settings = |c: &Config| {
+            (
+                c.access_log.clone(),
+                c.log_format,
+                c.log_timezone,
+                c.log_rotation.clone(),
+                c.xff,
+                c.trusted_proxies.clone(),
+                c.tcp_info,
+            )
+        };
+        let new_log = log_settings(&self.config()) != log_settings(config);
+        self.policies.store(Arc::new(policies));
+        self.paths.store(Arc::new(paths));
+        self.config.store(Arc::new(config.clone()));
+        if new_log {
+            self.access_log.store(AccessLog::new(config));
+        } else {
+            self.reopen_log();
+        }
+        Ok(())
+    }
+
+    /// Reopen the access log.
+    pub fn reopen_log(&self) {
+        if let Some(access_log) = self.access_log.load_full() {
+            access_log.reopen();
         }
     }
 
-    fn index(&self, agent: String, config: &Config) -> http::Result<HyperResponse> {
-        let (text, ct, status) = match template::build(config, agent) {
+    /// Write out the access log, before exiting.
+    pub fn flush_log(&self) {
+        if let Some(access_log) = self.access_log.load_full() {
+            access_log.flush();
+        }
+    }
+
+    /// Done before routing: apply the path map, and refuse requests
+    /// that are not safe in TLS early data. Returns a response if the
+    /// request should not be routed.
+    pub fn pre_route<B>(&self, req: &mut http::Request<B>) -> Option<HyperResponse> {
+        self.preflight(req)
+            .or_else(|| self.paths.load().apply(req))
+            .or_else(|| self.too_early(req))
+    }
+
+    // CORS preflight requests, if CORS is enabled.
+    fn preflight<B>(&self, req: &http::Request<B>) -> Option<HyperResponse> {
+        cors::preflight(self.config().cors.as_ref()?, req)
+    }
+
+    /// Done after routing: add the CORS headers for `origin`.
+    pub fn post_route(&self, origin: Option<&http::HeaderValue>, resp: &mut HyperResponse) {
+        if let Some(cors) = self.config().cors.as_ref() {
+            cors::apply(cors, origin, resp.headers_mut());
+        }
+    }
+
+    // Requests that a front-end proxy received in TLS 1.3 early data
+    // (0-RTT) are marked with "Early-Data: 1" (RFC 8470). Since early data
+    // can be replayed, only small, safe requests are allowed. The header
+    // is not checked against trusted-proxies: a client that sends it
+    // itself only gets its own requests refused.
+    fn too_early<B>(&self, req: &http::Request<B>) -> Option<HyperResponse> {
+        if !self.config().early_data_header {
+            return None;
+        }
+        let early = req
+            .headers()
+            .get("early-data")
+            .map(|v| v == "1")
+            .unwrap_or(false);
+        if !early || is_early_data_safe(req.method(), req.uri().path()) {
+            return None;
+        }
+        Response::builder()
+            .status(StatusCode::from_u16(425).unwrap())
+            .body(Body::from("too early"))
+            .ok()
+    }
+
+    /// A "103 Early Hints" response to send before the index page, if enabled.
+    pub fn early_hints<B>(&self, req: &http::Request<B>) -> Option<Vec<u8>> {
+        let config = self.config();
+        let index = &config.index;
+        if !index.early_hints
+            || index.preload.is_empty()
+            || req.method() != http::Method::GET
+            || req.uri().path() != "/"
+            || req.version() != http::Version::HTTP_11
+        {
+            return None;
+        }
+        let mut hints = String::from("HTTP/1.1 103 Early Hints\r\n");
+        for link in preload_links(&index.preload) {
+            hints.push_str(&format!("link: {}\r\n", link));
+        }
+        hints.push_str("\r\n");
+        Some(hints.into_bytes())
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    pub fn load_monitor(&self) -> Arc<LoadMonitor> {
+        self.load.clone()
+    }
+
+    pub fn random_pool(&self) -> Option<Arc<RandomPool>> {
+        self.pool.clone()
+    }
+
+    fn index(&self, req: template::Request, config: &Config) -> http::Result<HyperResponse> {
+        let (text, ct, status) = match template::build(config, req) {
             Ok(index) => (index, "text/html; charset=utf-8", StatusCode::OK),
             Err(e) => (
                 e.to_string(),
@@ -46,25 +277,48 @@ impl FileServer {
                 StatusCode::INTERNAL_SERVER_ERROR,
             ),
         };
-        Response::builder()
+        let mut resp = Response::builder()
             .header("Content-Type", ct)
-            .status(status)
-            .body(Body::from(text))
+            .status(status);
+        for link in preload_links(&config.index.preload) {
+            resp = resp.header("link", link.as_str());
+        }
+        resp.body(Body::from(text))
     }
 
     // Generate a streaming response with random data.
-    fn data(&self, filename: String, mut log_info: LogInfo) -> http::Result<HyperResponse> {
-        let max_size = self.config.max_file_size.unwrap_or(MAX_FILE_SIZE);
+    fn data(
+        &self,
+        filename: String,
+        head: bool,
+        query: DataQuery,
+        range: Option<String>,
+        mut log_info: LogInfo,
+    ) -> http::Result<HyperResponse> {
+        let max_size = self.config().max_file_size.unwrap_or(MAX_FILE_SIZE);
 
-        // parse size.
-        let sz = match size(&filename) {
-            Ok(sz) if sz > max_size => {
+        // parse size, or a duration like "30s". In that case we send
+        // data until the time is up, but never more than max_size.
+        // "infinite" goes on until the client closes the connection,
+        // limited only by max-file-size, if set.
+        let timed = duration(&filename);
+        let infinite = filename.split('.').next() == Some("infinite");
+        let unbounded = timed.is_some() || infinite;
+        let sz = match (timed, size(&filename)) {
+            (Some(d), _) if d.as_secs() == 0 || d > MAX_DURATION => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("bad duration"))
+            }
+            (Some(_), _) => max_size,
+            (None, _) if infinite => self.config().max_file_size.unwrap_or(u64::MAX),
+            (None, Ok(sz)) if sz > max_size => {
                 return Response::builder()
                     .status(StatusCode::BAD_REQUEST)
                     .body(Body::from("too big"))
             }
-            Ok(sz) => sz,
-            Err(_) => {
+            (None, Ok(sz)) => sz,
+            (None, Err(_)) => {
                 let is_num = filename
                     .chars()
                     .next()
@@ -82,12 +336,278 @@ impl FileServer {
             }
         };
 
+        // optional warm-up data, sent before the requested file.
+        let warmup = match query.warmup.as_ref().map(|w| size(w)) {
+            Some(Ok(_)) if unbounded => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("no warm-up without a size"))
+            }
+            Some(Ok(w)) if matches!(w.checked_add(sz), Some(t) if t <= max_size) => w,
+            Some(Ok(_)) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("too big"))
+            }
+            Some(Err(_)) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("cannot parse warmup size"))
+            }
+            None => 0,
+        };
+
+        // what the data looks like.
+        let pattern = match query.pattern.as_ref().map(|p| p.parse::<Pattern>()) {
+            Some(Ok(pattern)) => pattern,
+            Some(Err(e)) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(e))
+            }
+            None => self.config().data_pattern.unwrap_or(Pattern::Random),
+        };
+
+        // the seed of the random data. Different for every request, unless
+        // the client asks for a specific one.
+        let seed = match query.seed.as_ref().map(|s| s.parse::<u64>()) {
+            Some(Ok(seed)) => Some(seed),
+            Some(Err(_)) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("cannot parse seed"))
+            }
+            None => None,
+        };
+
+        // a checksum of the body, sent as a trailer. hyper only sends
+        // trailers over HTTP/2.
+        let checksum = match query.checksum.as_deref() {
+            Some("sha256") if log_info.version() == http::Version::HTTP_2 => true,
+            Some("sha256") => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("checksum needs HTTP/2"))
+            }
+            Some(_) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("unknown checksum"))
+            }
+            None => false,
+        };
+
+        // data from the pool cannot be reproduced, so it is not
+        // used if a seed was asked for.
+        let pool = self
+            .pool
+            .clone()
+            .filter(|_| pattern == Pattern::Random && seed.is_none());
+        // Only reproducible data is sent in ranges: random data with a seed
+        // the client asked for, or the zeros and text patterns. Otherwise
+        // a range would not match the rest of the file, and the whole
+        // file is sent.
+        let reproducible = seed.is_some() || pattern != Pattern::Random;
+        let seed = seed.unwrap_or_else(rand::random);
+
+        // a single byte range. Not together with warm-up data
+        // or without a size, though.
+        let range = match range
+            .filter(|_| reproducible && warmup == 0 && !unbounded)
+            .map(|r| byte_range(&r, sz))
+        {
+            Some(Ok(range)) => range,
+            Some(Err(())) => {
+                return Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header("content-range", format!("bytes */{}", sz).as_str())
+                    .body(Body::from("range not satisfiable"))
+            }
+            None => None,
+        };
+        let (offset, mut len) = match range {
+            Some((start, end)) => (start, end - start + 1),
+            None => (0, sz),
+        };
+        let total = warmup + len;
+
+        // select the congestion control algorithm.
+        if let Some(cc) = query.cc.as_ref() {
+            let res = match log_info.conn() {
+                Some(conn) => conn.set_congestion_control(cc),
+                None => Err(io::Error::from(io::ErrorKind::Unsupported)),
+            };
+            if let Err(e) = res {
+                // Not allowed is the client's problem, the rest is ours.
+                if e.kind() != io::ErrorKind::PermissionDenied {
+                    log::warn!("cc={}: {}", cc, e);
+                }
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(format!("cc={}: {}", cc, e)));
+            }
+        }
+
+        // if the server is overloaded, refuse large requests.
+        if let Some(load_shedding) = self.config().load_shedding.as_ref() {
+            if sz > load_shedding.max_file_size.unwrap_or(0) && self.load.overloaded() {
+                return Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header("retry-after", "30")
+                    .body(Body::from("server overloaded, try again later"));
+            }
+        }
+
+        // see if there is a bandwidth policy for this client.
+        let mut rate_limit = self.config().rate_limit;
+        let mut quota_guard = None;
+        let client_ip = log_info.remote_ip(&Trusted::new(&self.config()));
+
+        // per-client limits.
+        let client_guard = match self.client_limits(client_ip, &filename) {
+            Ok(guard) => guard,
+            Err(refused) => return Ok(too_many(refused)),
+        };
+        let policies = self.policies.load_full();
+        if let Some((ip, policy)) = client_ip.and_then(|ip| policies.lookup(ip).map(|p| (ip, p))) {
+            if let Some(quota) = policy.quota {
+                let used = policies.used(ip);
+                if unbounded && used < quota {
+                    // send no more than what is left of the quota.
+                    len = std::cmp::min(len, quota - used);
+                } else if unbounded || used.saturating_add(total) > quota {
+                    return Response::builder()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .body(Body::from("quota exceeded"));
+                }
+                quota_guard = Some(QuotaGuard::new(policies.clone(), ip));
+            }
+            if policy.rate_limit.is_some() {
+                rate_limit = policy.rate_limit;
+            }
+        }
+
+        // response headers.
+        let mut resp = Response::builder()
+            .header("content-type", pattern.content_type())
+            .header(
+                "content-disposition",
+                format!("attachment; filename={}", filename).as_str(),
+            )
+            .header(
+                "cache-control",
+                "no-cache, no-store, no-transform, must-revalidate",
+            )
+            .header("pragma", "no-cache");
+
+        // Without a size we do not know the length in advance,
+        // so the body is sent chunked.
+        if !unbounded {
+            resp = resp.header("content-length", total.to_string().as_str());
+            if reproducible {
+                resp = resp.header("accept-ranges", "bytes");
+            }
+        }
+
+        resp = match range {
+            Some((start, end)) => resp
+                .header(
+                    "content-range",
+                    format!("bytes {}-{}/{}", start, end, sz).as_str(),
+                )
+                .status(StatusCode::PARTIAL_CONTENT),
+            None => resp.status(StatusCode::OK),
+        };
+
+        // The measured part of the body starts at this offset.
+        if warmup > 0 {
+            resp = resp.header("x-warmup-length", warmup.to_string().as_str());
+        }
+
+        // TR-143 clients (CPEs) might re-use the connection, and like
+        // to see a plain static file.
+        if self.config().tr143 {
+            let modified = self.started.format("%a, %d %b %Y %H:%M:%S GMT");
+            resp = resp.header("last-modified", modified.to_string().as_str());
+        } else if self.config().close_connection {
+            resp = resp.header("connection", "close");
+        }
+
+        // HEAD gets the same headers, but nothing is generated or counted.
+        if head {
+            return resp.body(Body::empty());
+        }
+
         // wrap the RandomStream in another stream, so we can handle timeouts etc.
+        // The bytes are always counted, the size only if there is one.
+        let mut stream_guard =
+            StreamGuard::download(self.stats.clone(), Some(sz).filter(|_| !unbounded));
+        let mut stall_timer = self.config().stall_detection.as_ref().map(|s| {
+            let client = client_ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| String::from("unknown"));
+            let threshold = Duration::from_millis(s.threshold);
+            StreamTimer::new(
+                format!("{} {}", client, filename),
+                threshold,
+                self.stats.clone(),
+            )
+        });
+        let mut transfer = self
+            .rolling
+            .clone()
+            .map(|r| TransferGuard::new(r, client_ip));
+        let request_id = results::request_id();
+        let mut recorder = if self.config().transfer_results {
+            Some(ResultRecorder::new(
+                self.results.clone(),
+                request_id.clone(),
+                len,
+                warmup,
+                log_info.conn(),
+            ))
+        } else {
+            None
+        };
+        let bandwidth = self.bandwidth.load_full();
+        let send_timeout = Duration::from_secs(self.config().send_timeout);
+        let max_duration = self.config().max_transfer_duration.map(Duration::from_secs);
+        let end = log_info.end_reason();
+        let rng = self.config().rng.unwrap_or_default();
+        let seeded = pool.is_none() && pattern == Pattern::Random;
         let stream = Box::pin(async_stream::stream! {
-            let mut strm = RandomStream::new(sz);
-            let mut timeout = Box::pin(tokio::time::sleep(SEND_TIMEOUT));
+            // Keep counting this stream for the client until it is done.
+            let _client_guard = client_guard;
+            let random = |offset, len| -> Pin<Box<dyn Stream<Item = Result<Bytes, Infallible>> + Send>> {
+                match pool.clone() {
+                    Some(pool) => Box::pin(PoolStream::new(pool, len)),
+                    None => randomstream::stream(pattern, rng, seed, offset, len),
+                }
+            };
+            // The warm-up data is a separate stream, so that the measured
+            // part starts at a chunk boundary.
+            let mut strm = if warmup > 0 {
+                Box::pin(random(0, warmup).chain(random(0, sz)))
+            } else {
+                random(offset, len)
+            };
+            let mut timeout = Box::pin(tokio::time::sleep(send_timeout));
+            let mut bucket = rate_limit.map(TokenBucket::new);
+            let deadline = timed.map(|d| Instant::now() + d);
+            let cutoff = max_duration.map(|d| Instant::now() + d);
 
             loop {
+                if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                    end.set("completed");
+                    break;
+                }
+                if cutoff.map(|c| Instant::now() >= c).unwrap_or(false) {
+                    end.set("max-duration");
+                    break;
+                }
+                if let Some(timer) = stall_timer.as_mut() {
+                    timer.resumed();
+                }
                 let value = tokio::select! {
                     value = strm.next() => {
                         match value {
@@ -95,43 +615,456 @@ impl FileServer {
                             None => break,
                         }
                     }
-                    _ = timeout.as_mut() => break,
+                    _ = timeout.as_mut() => {
+                        end.set("timeout");
+                        break;
+                    }
                 };
-                timeout.as_mut().reset(Instant::now() + SEND_TIMEOUT);
+                if let Some(timer) = stall_timer.as_mut() {
+                    timer.ready();
+                }
+                let len = value.as_ref().map(|b| b.len()).unwrap_or(0);
+                if let Some(bucket) = bucket.as_mut() {
+                    bucket.take(len).await;
+                }
+                if let Some(bandwidth) = bandwidth.as_ref() {
+                    bandwidth.take(len).await;
+                }
+                if let Some(guard) = quota_guard.as_mut() {
+                    guard.bytes += len as u64;
+                }
+                stream_guard.add_bytes(len as u64);
+                if let Some(transfer) = transfer.as_mut() {
+                    transfer.add_bytes(len as u64);
+                }
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.add_bytes(len as u64);
+                }
+                timeout.as_mut().reset(Instant::now() + send_timeout);
+                if let Some(timer) = stall_timer.as_mut() {
+                    timer.yielded();
+                }
                 yield value;
             }
         });
 
-        // response headers and body.
-        let resp = Response::builder()
-            .header("content-type", "application/binary")
-            .header(
-                "content-disposition",
-                format!("attachment; filename={}", filename).as_str(),
+        // If the stream panics, all we can do is abort the connection.
+        let stats = self.stats.clone();
+        let stream =
+            futures::StreamExt::catch_unwind(AssertUnwindSafe(stream)).map(
+                move |item| match item {
+                    Ok(Ok(data)) => Ok(data),
+                    Ok(Err(e)) => match e {},
+                    Err(_) => {
+                        stats.count_panic();
+                        Err(io::Error::new(io::ErrorKind::Other, "stream panicked"))
+                    }
+                },
+            );
+
+        // Headers that are about the body.
+        if self.config().transfer_results {
+            resp = resp.header("x-request-id", request_id.as_str());
+        }
+        if seeded {
+            resp = resp.header("x-seed", seed.to_string().as_str());
+        }
+        if checksum {
+            resp = resp.header("trailer", "content-digest");
+        }
+        log_info.log_on_drop(self.access_log.load_full());
+        let resp = log_info.wrap(resp, stream)?;
+        Ok(if checksum { with_digest(resp) } else { resp })
+    }
+
+    // Count a request of the client, and start a stream if the
+    // per-client limits allow it.
+    fn client_limits(
+        &self,
+        client_ip: Option<IpAddr>,
+        what: &str,
+    ) -> Result<Option<ClientGuard>, Refused> {
+        let config = self.config();
+        let (max_streams, max_requests) =
+            (config.max_streams_per_ip, config.max_requests_per_minute);
+        let ip = match client_ip.filter(|_| max_streams.is_some() || max_requests.is_some()) {
+            Some(ip) => ip,
+            None => return Ok(None),
+        };
+        match self.clients.start(ip, max_streams, max_requests) {
+            Ok(guard) => Ok(Some(guard)),
+            Err(refused) => {
+                log::info!("{}: {}, refused {}", ip, refused.reason(), what);
+                Err(refused)
+            }
+        }
+    }
+
+    /// A download on the websocket. It goes through data(), so it gets
+    /// the same limits, accounting and logging as a download over http.
+    pub fn ws_download(&self, size: &str, log_info: LogInfo) -> HyperResponse {
+        self.catch_panic(|| {
+            self.data(
+                size.to_string(),
+                false,
+                DataQuery::default(),
+                None,
+                log_info,
             )
-            .header("content-length", sz.to_string().as_str())
+        })
+        .or_else(|_| internal_error())
+        .unwrap()
+    }
+
+    /// An upload on the websocket, with the per-client limits of a
+    /// download. It is logged like an upload over http.
+    pub async fn ws_upload<S, B>(
+        &self,
+        body: S,
+        mut log_info: LogInfo,
+    ) -> Result<u64, HyperResponse>
+    where
+        S: Stream<Item = Result<B, warp::Error>>,
+        B: Buf,
+    {
+        let client_ip = log_info.remote_ip(&Trusted::new(&self.config()));
+        let res = match self.client_limits(client_ip, "ws upload") {
+            Ok(_client_guard) => self.receive(None, body).await,
+            Err(refused) => Err(too_many(refused)),
+        };
+        let (status, length) = match res.as_ref() {
+            Ok(done) => (StatusCode::OK, *done),
+            Err(resp) => {
+                let received = resp.extensions().get::<Received>();
+                (resp.status(), received.map(|r| r.0).unwrap_or(0))
+            }
+        };
+        log_info.set_status(status);
+        log_info.set_length(length);
+        log_info.log_on_drop(self.access_log.load_full());
+        log_info.log();
+        res
+    }
+
+    // A file from the data directory. The body is wrapped so that
+    // the bytes actually sent are logged.
+    fn file(&self, file: warp::fs::File, mut log_info: LogInfo) -> http::Result<HyperResponse> {
+        let (parts, body) = file.into_response().into_parts();
+        let mut resp = Response::builder().status(parts.status);
+        for (name, value) in parts.headers.iter() {
+            resp = resp.header(name, value);
+        }
+        log_info.set_status(parts.status);
+        log_info.log_on_drop(self.access_log.load_full());
+        match self.bandwidth.load_full() {
+            Some(bandwidth) => {
+                let body = Box::pin(async_stream::stream! {
+                    tokio::pin!(body);
+                    while let Some(item) = body.next().await {
+                        if let Ok(data) = item.as_ref() {
+                            bandwidth.take(data.len()).await;
+                        }
+                        yield item;
+                    }
+                });
+                log_info.wrap(resp, body)
+            }
+            None => log_info.wrap(resp, body),
+        }
+    }
+
+    // An asset of the index page, from index.assets.
+    fn asset(&self, file: warp::fs::File, log_info: LogInfo) -> http::Result<HyperResponse> {
+        let mut resp = self.file(file, log_info)?;
+        let headers = resp.headers_mut();
+        headers.insert(
+            "cache-control",
+            http::HeaderValue::from_static(ASSET_CACHE_CONTROL),
+        );
+        headers.insert(
+            "x-content-type-options",
+            http::HeaderValue::from_static("nosniff"),
+        );
+        Ok(resp)
+    }
+
+    // Receive an upload and throw it away (TR-143 UploadDiagnostics).
+    async fn sink<S, B>(
+        self,
+        length: Option<u64>,
+        body: S,
+    ) -> Result<HyperResponse, warp::Rejection>
+    where
+        S: Stream<Item = Result<B, warp::Error>>,
+        B: Buf,
+    {
+        let done = match self.receive(length, body).await {
+            Ok(done) => done,
+            Err(resp) => return Ok(resp),
+        };
+
+        Ok(Response::builder()
+            .header("content-length", "0")
+            .status(StatusCode::OK)
+            .extension(Received(done))
+            .body(Body::empty())
+            .unwrap())
+    }
+
+    // LibreSpeed getIP.php. There is no ISP lookup, so rawIspInfo is empty.
+    fn librespeed_ip(&self, log_info: LogInfo) -> http::Result<HyperResponse> {
+        let ip = log_info
+            .remote_ip(&Trusted::new(&self.config()))
+            .map(|ip| cidr::canonical(ip).to_string())
+            .unwrap_or_default();
+        let body = serde_json::json!({ "processedString": ip, "rawIspInfo": "" });
+        Response::builder()
+            .header("content-type", "application/json")
+            .header("cache-control", "no-cache, no-store, must-revalidate")
+            .status(StatusCode::OK)
+            .body(Body::from(body.to_string()))
+    }
+
+    // Upload test. Like the sink, but returns a summary.
+    async fn upload<S, B>(
+        self,
+        length: Option<u64>,
+        body: S,
+    ) -> Result<HyperResponse, warp::Rejection>
+    where
+        S: Stream<Item = Result<B, warp::Error>>,
+        B: Buf,
+    {
+        let start = Instant::now();
+        let done = match self.receive(length, body).await {
+            Ok(done) => done,
+            Err(resp) => return Ok(resp),
+        };
+        let elapsed = start.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0f64 {
+            (done * 8) as f64 / elapsed
+        } else {
+            0f64
+        };
+
+        let body = serde_json::json!({
+            "bytes": done,
+            "duration": elapsed,
+            "throughput": throughput,
+        });
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .header("cache-control", "no-cache")
+            .status(StatusCode::OK)
+            .extension(Received(done))
+            .body(Body::from(body.to_string()))
+            .unwrap())
+    }
+
+    // Read and discard a request body. Returns the number of bytes, or
+    // an error response if the body is larger than max-file-size.
+    async fn receive<S, B>(&self, length: Option<u64>, body: S) -> Result<u64, HyperResponse>
+    where
+        S: Stream<Item = Result<B, warp::Error>>,
+        B: Buf,
+    {
+        let max_size = self.config().max_file_size.unwrap_or(MAX_FILE_SIZE);
+        if length.map(|l| l > max_size).unwrap_or(false) {
+            return Err(Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Body::from("too big"))
+                .unwrap());
+        }
+
+        let _stream_guard = StreamGuard::upload(self.stats.clone());
+        let mut done = 0u64;
+        tokio::pin!(body);
+        while let Some(item) = body.next().await {
+            let count = match item {
+                Ok(buf) => buf.remaining() as u64,
+                Err(_) => break,
+            };
+            done += count;
+            if done > max_size {
+                return Err(Response::builder()
+                    .status(StatusCode::PAYLOAD_TOO_LARGE)
+                    .header("connection", "close")
+                    .extension(Received(done))
+                    .body(Body::from("too big"))
+                    .unwrap());
+            }
+        }
+        Ok(done)
+    }
+
+    // Public statistics.
+    fn stats_json(&self) -> http::Result<HyperResponse> {
+        let body = serde_json::to_string(&self.stats.public()).unwrap();
+        Response::builder()
+            .header("content-type", "application/json")
+            .header("cache-control", "no-cache")
+            .status(StatusCode::OK)
+            .body(Body::from(body))
+    }
+
+    // Rolling statistics of the last 15 minutes.
+    fn live_stats(&self) -> http::Result<HyperResponse> {
+        let snapshot = match self.rolling.as_ref() {
+            Some(rolling) => rolling.snapshot(self.stats.active_streams()),
+            None => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("Not Found"))
+            }
+        };
+        Response::builder()
+            .header("content-type", "application/json")
+            .header("cache-control", "no-cache")
+            .status(StatusCode::OK)
+            .body(Body::from(serde_json::to_string(&snapshot).unwrap()))
+    }
+
+    // The files of the index page, for scripts.
+    fn files_json(&self) -> http::Result<HyperResponse> {
+        let body = serde_json::to_string(&template::files(&self.config())).unwrap();
+        Response::builder()
+            .header("content-type", "application/json")
+            .header("cache-control", "no-cache")
+            .status(StatusCode::OK)
+            .body(Body::from(body))
+    }
+
+    // Prometheus metrics.
+    fn metrics(&self) -> http::Result<HyperResponse> {
+        Response::builder()
+            .header("content-type", "text/plain; version=0.0.4")
+            .header("cache-control", "no-cache")
+            .status(StatusCode::OK)
+            .body(Body::from(self.stats.prometheus()))
+    }
+
+    // Description of the API.
+    fn openapi(&self) -> http::Result<HyperResponse> {
+        let body = serde_json::to_string_pretty(&openapi::spec(&self.config())).unwrap();
+        Response::builder()
+            .header("content-type", "application/json")
+            .status(StatusCode::OK)
+            .body(Body::from(body))
+    }
+
+    // Capabilities of this instance.
+    fn discovery(&self) -> http::Result<HyperResponse> {
+        let body = serde_json::to_string_pretty(&discovery::discovery(&self.config())).unwrap();
+        Response::builder()
+            .header("content-type", "application/json")
+            .header("access-control-allow-origin", "*")
+            .status(StatusCode::OK)
+            .body(Body::from(body))
+    }
+
+    // Nothing, for measuring the round-trip time.
+    fn empty(&self) -> http::Result<HyperResponse> {
+        let mut resp = Response::builder()
+            .status(StatusCode::NO_CONTENT)
             .header(
                 "cache-control",
                 "no-cache, no-store, no-transform, must-revalidate",
             )
-            .header("pragma", "no-cache")
-            .header("connection", "close")
-            .status(StatusCode::OK);
-        log_info.log_on_drop(self.access_log.clone(), self.config.xff);
-        log_info.wrap(resp, stream)
-    }
-
-    fn log(&self, info: warp::log::Info) {
-        // Don't log streams here.
-        let file = info.path().split('/').last().unwrap();
-        let is_num = file.chars().next().map(|c| c.is_numeric()).unwrap_or(false);
-        if is_num && info.status() == http::StatusCode::OK {
+            .header("pragma", "no-cache");
+        if self.config().server_timestamp {
+            let now = Utc::now().timestamp_millis();
+            resp = resp.header("x-server-timestamp", now.to_string().as_str());
+        }
+        resp.body(Body::empty())
+    }
+
+    // RFC 9116 security.txt.
+    fn security_txt(&self) -> http::Result<HyperResponse> {
+        let body = self
+            .config()
+            .security_txt
+            .as_ref()
+            .map(|s| s.render())
+            .unwrap_or_default();
+        Response::builder()
+            .header("content-type", "text/plain; charset=utf-8")
+            .status(StatusCode::OK)
+            .body(Body::from(body))
+    }
+
+    // Key authorization for an ACME HTTP-01 challenge.
+    fn acme_challenge(&self, token: String) -> http::Result<HyperResponse> {
+        let config = self.config();
+        let dir = config.https.as_ref().and_then(|h| h.acme.as_ref());
+        let body = dir
+            .filter(|_| acme::valid_token(&token))
+            .and_then(|a| std::fs::read(acme::challenge_dir(&a.cache_dir).join(&token)).ok());
+        match body {
+            Some(body) => Response::builder()
+                .header("content-type", "application/octet-stream")
+                .status(StatusCode::OK)
+                .body(Body::from(body)),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not Found")),
+        }
+    }
+
+    // Result of a recent transfer.
+    fn result(&self, id: String) -> http::Result<HyperResponse> {
+        match self.results.get(&id) {
+            Some(result) => Response::builder()
+                .header("content-type", "application/json")
+                .header("cache-control", "no-cache")
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&result).unwrap())),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not Found")),
+        }
+    }
+
+    // Run an async handler. If it panics, return a 500 error.
+    async fn catch_panic_async<F>(&self, f: F) -> Result<HyperResponse, warp::Rejection>
+    where
+        F: Future<Output = Result<HyperResponse, warp::Rejection>>,
+    {
+        match AssertUnwindSafe(f).catch_unwind().await {
+            Ok(resp) => resp,
+            Err(_) => {
+                self.stats.count_panic();
+                Ok(internal_error().unwrap())
+            }
+        }
+    }
+
+    // Run a handler. If it panics, return a 500 error.
+    fn catch_panic<F>(&self, f: F) -> http::Result<HyperResponse>
+    where
+        F: FnOnce() -> http::Result<HyperResponse>,
+    {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(resp) => resp,
+            Err(_) => {
+                self.stats.count_panic();
+                internal_error()
+            }
+        }
+    }
+
+    /// Log a request, once the response is ready.
+    pub fn log(&self, mut log_info: LogInfo, resp: &HyperResponse) {
+        // Don't log streams here, they log themselves when done.
+        if resp.extensions().get::<Streamed>().is_some() {
             return;
         }
 
         // Do log everything else.
-        let mut log_info =
-            LogInfo::from_warp_log_info(info, self.access_log.clone(), self.config.xff);
+        log_info.set_status(resp.status());
+        if let Some(Received(length)) = resp.extensions().get::<Received>() {
+            log_info.set_length(*length);
+        }
+        log_info.log_on_drop(self.access_log.load_full());
         log_info.log();
     }
 
@@ -150,40 +1083,597 @@ impl FileServer {
             })
     }
 
+    /// The /metrics endpoint, for on a separate listener.
+    pub fn metrics_routes(&self) -> BoxedFilter<(impl Reply,)> {
+        let this = self.clone();
+        warp::path("metrics")
+            .and(warp::path::end())
+            .and(methods(GET_HEAD))
+            .map(move || this.catch_panic(|| this.metrics()))
+            .recover(rejection)
+            .boxed()
+    }
+
+    // Filter that checks the bearer token or the signed URL, if the
+    // auth section asks for one.
+    fn authorized(&self) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        let this = self.clone();
+        warp::header::optional::<String>("authorization")
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and_then(
+                move |authorization: Option<String>, path: FullPath, query: String| {
+                    let config = this.config();
+                    async move {
+                        match config.auth.as_ref() {
+                            Some(auth) => {
+                                auth::check(auth, authorization.as_deref(), path.as_str(), &query)
+                                    .map_err(warp::reject::custom)
+                            }
+                            None => Ok(()),
+                        }
+                    }
+                },
+            )
+            .untuple_one()
+    }
+
     // bundle up "index" and "data" into one Filter.
     pub fn routes(&self, redirect_uri: Option<&http::Uri>) -> BoxedFilter<(impl Reply,)> {
-        let config = self.config.clone();
+        let config = self.config();
         let this = self.clone();
         let index = warp::path::end()
+            .and(methods(GET_HEAD))
             .and(warp::header("user-agent"))
-            .map(move |agent: String| this.index(agent, &config));
+            .and(warp::host::optional())
+            .and(LogInfo::new())
+            .map(
+                move |agent: String, host: Option<Authority>, log_info: LogInfo| {
+                    let config = this.config();
+                    let req = template::Request {
+                        agent,
+                        client_ip: log_info.remote_ip(&Trusted::new(&config)),
+                        tls: log_info.conn().map(|c| c.tls).unwrap_or(false),
+                        host: host.map(|h| h.to_string()),
+                    };
+                    this.catch_panic(|| this.index(req, &config))
+                },
+            );
 
+        // With TR-143, PUT on the same paths is the upload test (sink).
         let this = self.clone();
+        let data_methods = if config.tr143 { DATA_TR143 } else { GET_HEAD };
         let data = warp::path::param()
             .and(warp::path::end())
+            .and(methods(data_methods))
+            .and(self.authorized())
+            .and(warp::method())
+            .and(DataQuery::filter())
+            .and(warp::header::optional::<String>("range"))
+            .and(LogInfo::new())
+            .map(
+                move |param: String,
+                      method: Method,
+                      query: DataQuery,
+                      range: Option<String>,
+                      log_info: LogInfo| {
+                    let head = method == Method::HEAD;
+                    this.catch_panic(|| this.data(param, head, query, range, log_info))
+                },
+            );
+
+        // Not for "/": warp would serve an index.html from the directory
+        // there, and "/" is the index page.
+        let this = self.clone();
+        let files = match config.data_dir.clone() {
+            Some(dir) => warp::path::peek()
+                .and_then(|path: warp::path::Peek| async move {
+                    match path.as_str() {
+                        "" => Err(warp::reject::not_found()),
+                        _ => Ok(()),
+                    }
+                })
+                .untuple_one()
+                .and(methods(GET_HEAD))
+                .and(self.authorized())
+                .and(warp::fs::dir(dir))
+                .and(LogInfo::new())
+                .map(move |file: warp::fs::File, log_info: LogInfo| {
+                    this.catch_panic(|| this.file(file, log_info))
+                })
+                .boxed(),
+            None => warp::any()
+                .and_then(|| async { Err(warp::reject::not_found()) })
+                .boxed(),
+        };
+
+        // Under /static/, so that an asset is never taken for a size.
+        // Browsers look for /favicon.ico by themselves.
+        let this = self.clone();
+        let assets = match config.index.assets.clone() {
+            Some(dir) => warp::path("static")
+                .and(warp::fs::dir(dir.clone()))
+                .or(warp::path("favicon.ico")
+                    .and(warp::path::end())
+                    .and(warp::fs::file(dir.join("favicon.ico"))))
+                .unify()
+                .and(LogInfo::new())
+                .map(move |file: warp::fs::File, log_info: LogInfo| {
+                    this.catch_panic(|| this.asset(file, log_info))
+                })
+                .boxed(),
+            None => warp::any()
+                .and_then(|| async { Err(warp::reject::not_found()) })
+                .boxed(),
+        };
+
+        let this = self.clone();
+        let sink = enabled(config.tr143)
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(methods(PUT_ONLY))
+            .and(self.authorized())
+            .and(warp::header::optional::<u64>("content-length"))
+            .and(warp::body::stream())
+            .and_then(move |_param, length, body| {
+                let this = this.clone();
+                async move {
+                    this.catch_panic_async(this.clone().sink(length, body))
+                        .await
+                }
+            });
+
+        let this = self.clone();
+        let upload = enabled(config.upload)
+            .and(warp::path("upload"))
+            .and(warp::path::end())
+            .and(methods(POST_PUT))
+            .and(self.authorized())
+            .and(warp::header::optional::<u64>("content-length"))
+            .and(warp::body::stream())
+            .and_then(move |length, body| {
+                let this = this.clone();
+                async move {
+                    this.catch_panic_async(this.clone().upload(length, body))
+                        .await
+                }
+            });
+
+        // LibreSpeed compatible endpoints, with or without "backend/".
+        let this = self.clone();
+        let garbage = enabled(config.librespeed)
+            .and(librespeed_path("garbage.php"))
+            .and(methods(GET_HEAD))
+            .and(self.authorized())
+            .and(warp::method())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(LogInfo::new())
+            .map(
+                move |method: Method, query: HashMap<String, String>, log_info: LogInfo| {
+                    // ckSize is the number of 1MiB chunks.
+                    let chunks = query
+                        .get("ckSize")
+                        .and_then(|c| c.parse::<u64>().ok())
+                        .unwrap_or(4)
+                        .clamp(1, 1024);
+                    let filename = format!("{}MiB", chunks);
+                    let head = method == Method::HEAD;
+                    this.catch_panic(|| {
+                        this.data(filename, head, DataQuery::default(), None, log_info)
+                    })
+                },
+            );
+
+        let this = self.clone();
+        let empty_php = enabled(config.librespeed)
+            .and(librespeed_path("empty.php"))
+            .and(self.authorized())
+            .and(warp::header::optional::<u64>("content-length"))
+            .and(warp::body::stream())
+            .and_then(move |length, body| {
+                let this = this.clone();
+                async move {
+                    this.catch_panic_async(this.clone().sink(length, body))
+                        .await
+                }
+            });
+
+        let this = self.clone();
+        let get_ip = enabled(config.librespeed)
+            .and(librespeed_path("getIP.php"))
+            .and(methods(GET_HEAD))
+            .and(LogInfo::new())
+            .map(move |log_info: LogInfo| this.catch_panic(|| this.librespeed_ip(log_info)));
+
+        let this = self.clone();
+        let stats_json = enabled(config.public_stats)
+            .and(warp::path("stats.json"))
+            .and(warp::path::end())
+            .and(methods(GET_HEAD))
+            .map(move || this.catch_panic(|| this.stats_json()));
+
+        let this = self.clone();
+        let live_stats = enabled(config.live_stats)
+            .and(warp::path("stats"))
+            .and(warp::path::end())
+            .and(methods(GET_HEAD))
+            .map(move || this.catch_panic(|| this.live_stats()));
+
+        let this = self.clone();
+        let files_json = warp::path!("api" / "files.json")
+            .and(methods(GET_HEAD))
+            .map(move || this.catch_panic(|| this.files_json()));
+
+        let this = self.clone();
+        let result = enabled(config.transfer_results)
+            .and(warp::path!("result" / String))
+            .and(methods(GET_HEAD))
+            .map(move |id: String| this.catch_panic(|| this.result(id)));
+
+        // Only if metrics are not served on a listener of their own.
+        let metrics_here = match config.metrics.as_ref() {
+            Some(metrics) => metrics.listen.is_empty(),
+            None => false,
+        };
+        let metrics = enabled(metrics_here).and(self.metrics_routes());
+
+        let this = self.clone();
+        let openapi = warp::path("openapi.json")
+            .and(warp::path::end())
+            .and(methods(GET_HEAD))
+            .map(move || this.catch_panic(|| this.openapi()));
+
+        let this = self.clone();
+        let websocket = enabled(config.websocket)
+            .and(warp::path("ws"))
+            .and(warp::path::end())
+            .and(methods(GET_ONLY))
+            .and(self.authorized())
+            .and(warp::ws())
             .and(LogInfo::new())
-            .map(move |param: String, log_info: LogInfo| this.data(param, log_info));
+            .map(move |ws: warp::ws::Ws, log_info: LogInfo| {
+                let session = ws::Session {
+                    server: this.clone(),
+                    log_info,
+                    max_size: this.config().max_file_size.unwrap_or(MAX_FILE_SIZE),
+                    send_timeout: Duration::from_secs(this.config().send_timeout),
+                };
+                ws.on_upgrade(move |socket| session.run(socket))
+            });
+
+        let this = self.clone();
+        let empty = warp::path("empty")
+            .and(warp::path::end())
+            .and(methods(GET_HEAD))
+            .map(move || this.catch_panic(|| this.empty()));
+
+        let this = self.clone();
+        let discovery = warp::path!(".well-known" / "speedtest")
+            .and(methods(GET_HEAD))
+            .map(move || this.catch_panic(|| this.discovery()));
 
         let this = self.clone();
-        self.redirect(redirect_uri)
+        let security_txt = enabled(config.security_txt.is_some())
+            .and(warp::path!(".well-known" / "security.txt"))
+            .and(methods(GET_HEAD))
+            .map(move || this.catch_panic(|| this.security_txt()));
+
+        // Before the redirect, the CA checks challenges over plain http.
+        let this = self.clone();
+        let acme_challenge = enabled(
+            config
+                .https
+                .as_ref()
+                .and_then(|h| h.acme.as_ref())
+                .is_some(),
+        )
+        .and(warp::path!(".well-known" / "acme-challenge" / String))
+        .and(methods(GET_HEAD))
+        .map(move |token: String| this.catch_panic(|| this.acme_challenge(token)));
+
+        acme_challenge
+            .or(self.redirect(redirect_uri))
+            .or(discovery)
+            .or(empty)
+            .or(security_txt)
+            .or(openapi)
+            .or(stats_json)
+            .or(live_stats)
+            .or(files_json)
+            .or(metrics)
+            .or(result)
+            .or(upload)
+            .or(websocket)
+            .or(garbage)
+            .or(empty_php)
+            .or(get_ip)
+            .or(sink)
+            .or(assets)
+            .or(files)
             .or(data)
             .or(index)
-            .with(warp::log::custom(move |info| this.log(info)))
+            .recover(rejection)
             .boxed()
     }
 }
 
+// Small GET requests without side effects can be sent in early data.
+// Downloads and uploads cannot.
+fn is_early_data_safe(method: &http::Method, path: &str) -> bool {
+    if method != http::Method::GET && method != http::Method::HEAD {
+        return false;
+    }
+    path == "/"
+        || path == "/empty"
+        || path == "/openapi.json"
+        || path == "/api/files.json"
+        || path == "/stats.json"
+        || path == "/stats"
+        || path.starts_with("/.well-known/")
+        || path.starts_with("/result/")
+        || path == "/favicon.ico"
+        || path.starts_with("/static/")
+}
+
+// Parse a Range header. Only a single byte range is supported, anything
+// else is ignored, so that the whole file is sent. Returns the first and
+// last byte, or an error if the range is not satisfiable.
+fn byte_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let (first, last) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // bytes=X-Y
+        (Ok(first), Ok(last)) if first <= last => (first, last),
+        // bytes=X-
+        (Ok(first), Err(_)) if last.is_empty() => (first, u64::MAX),
+        // bytes=-N, the last N bytes.
+        (Err(_), Ok(n)) if first.is_empty() => {
+            if n == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(n), u64::MAX)
+        }
+        _ => return Ok(None),
+    };
+    if first >= size {
+        return Err(());
+    }
+    Ok(Some((first, std::cmp::min(last, size - 1))))
+}
+
+// Link headers for the assets the index page should preload.
+fn preload_links(assets: &[String]) -> Vec<String> {
+    assets
+        .iter()
+        .map(|asset| {
+            let ext = asset.rsplit('.').next().unwrap_or("").to_lowercase();
+            let kind = match ext.as_str() {
+                "css" => "style",
+                "js" | "mjs" => "script",
+                "woff" | "woff2" | "ttf" | "otf" => "font; crossorigin",
+                "png" | "jpg" | "jpeg" | "gif" | "svg" | "webp" | "ico" => "image",
+                _ => "fetch; crossorigin",
+            };
+            format!("<{}>; rel=preload; as={}", asset, kind)
+        })
+        .collect()
+}
+
+// Send the body through a channel, so that a SHA-256 of it can be sent
+// as a Content-Digest trailer (RFC 9530). Only for HTTP/2, hyper does
+// not send trailers over HTTP/1.1.
+fn with_digest(resp: HyperResponse) -> HyperResponse {
+    let (parts, mut body) = resp.into_parts();
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        while let Some(data) = body.data().await {
+            let data = match data {
+                Ok(data) => data,
+                Err(_) => return tx.abort(),
+            };
+            ctx.update(&data);
+            if tx.send_data(data).await.is_err() {
+                return;
+            }
+        }
+        let value = format!("sha-256=:{}:", base64::encode(ctx.finish()));
+        let mut trailers = http::HeaderMap::new();
+        if let Ok(value) = http::HeaderValue::from_str(&value) {
+            trailers.insert("content-digest", value);
+        }
+        let _ = tx.send_trailers(trailers).await;
+    });
+    Response::from_parts(parts, rx)
+}
+
+fn internal_error() -> http::Result<HyperResponse> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .header("connection", "close")
+        .body(Body::from("internal server error"))
+}
+
+// The path of a LibreSpeed backend file, also under backend/.
+fn librespeed_path(
+    name: &'static str,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::path(name)
+        .or(warp::path("backend").and(warp::path(name)))
+        .unify()
+        .and(warp::path::end())
+}
+
+// A request with a method that the path does not accept.
+#[derive(Debug)]
+struct MethodNotAllowed(&'static [Method]);
+
+impl warp::reject::Reject for MethodNotAllowed {}
+
+// Filter that only passes the `allowed` methods.
+fn methods(
+    allowed: &'static [Method],
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and_then(move |method: Method| async move {
+            if allowed.contains(&method) {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(MethodNotAllowed(allowed)))
+            }
+        })
+        .untuple_one()
+}
+
+// Answer Denied with a 401 or 403, and MethodNotAllowed with a 405
+// and an Allow header.
+async fn rejection(rej: warp::Rejection) -> Result<HyperResponse, warp::Rejection> {
+    match rej.find::<Denied>() {
+        Some(Denied::Unauthorized) => {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header("www-authenticate", "Bearer")
+                .body(Body::from("unauthorized"))
+                .unwrap())
+        }
+        Some(Denied::Forbidden) => {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("forbidden"))
+                .unwrap())
+        }
+        None => {}
+    }
+    let allowed = match rej.find::<MethodNotAllowed>() {
+        Some(MethodNotAllowed(allowed)) => allowed,
+        None => return Err(rej),
+    };
+    let allow: Vec<_> = allowed.iter().map(|m| m.as_str()).collect();
+    Ok(Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header("allow", allow.join(", ").as_str())
+        .body(Body::from("method not allowed"))
+        .unwrap())
+}
+
+// The answer to a client that is over its per-client limits.
+fn too_many(refused: Refused) -> HyperResponse {
+    let retry_after = match refused {
+        Refused::Streams => clients::STREAMS_RETRY_AFTER,
+        Refused::Requests(wait) => wait,
+    };
+    // Round up, so that the client does not come back too early.
+    let retry_after = retry_after.as_secs() + 1;
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("retry-after", retry_after.to_string().as_str())
+        .body(Body::from(refused.reason()))
+        .unwrap()
+}
+
+// Filter that only passes if `enabled` is true.
+fn enabled(enabled: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
 // Strip any extension (like .bin), then parse the remaining
 // name as size using the "human size" crate. Also allow
-// lowercase variants (like 1000mb.bin).
+// lowercase variants (like 1000mb.bin or 100mib.bin).
 pub fn size(name: &str) -> Result<u64, ParsingError> {
     let name = name.split(".").next().unwrap();
     let name = name.replace("kb", "kB");
     let name = name.replace("KB", "kB");
     let sz: Size = match name.parse() {
         Ok(sz) => sz,
-        Err(_) => name.to_uppercase().parse()?,
+        Err(_) => normalize_unit(&name).parse()?,
     };
     let sz: SpecificSize<Byte> = sz.into();
     Ok(sz.value() as u64)
 }
+
+// Parse a name like "30s" or "30sec.bin" as a duration in seconds.
+// Returns None if it is not a duration.
+pub fn duration(name: &str) -> Option<Duration> {
+    let name = name.split('.').next().unwrap();
+    let secs = name
+        .strip_suffix("sec")
+        .or_else(|| name.strip_suffix('s'))?;
+    secs.parse::<u64>().ok().map(Duration::from_secs)
+}
+
+// Fix up the case of the unit, so that "100mib" becomes "100MiB".
+fn normalize_unit(name: &str) -> String {
+    let pos = name.find(|c: char| c.is_alphabetic()).unwrap_or(name.len());
+    let (num, unit) = name.split_at(pos);
+    let unit = unit.to_lowercase();
+    let unit = match unit.as_str() {
+        "kb" => "kB".to_string(),
+        u if u.len() == 3 && u.ends_with("ib") => format!("{}iB", u[..1].to_uppercase()),
+        u => u.to_uppercase(),
+    };
+    format!("{}{}", num, unit)
+}
+
+/// The same size in the "other" unit: 100MB becomes 100MiB, and
+/// 100MiB becomes 100MB. Returns None for plain byte counts.
+pub fn other_unit(name: &str) -> Option<String> {
+    let name = normalize_unit(name);
+    let pos = name.find(|c: char| c.is_alphabetic())?;
+    let (num, unit) = name.split_at(pos);
+    let unit = match unit {
+        "kB" => "KiB".to_string(),
+        "KiB" => "kB".to_string(),
+        u if u.len() == 3 && u.ends_with("iB") => format!("{}B", &u[..1]),
+        u if u.len() == 2 && u.ends_with('B') => format!("{}iB", &u[..1]),
+        _ => return None,
+    };
+    Some(format!("{}{}", num, unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_range_first_last() {
+        assert_eq!(byte_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(byte_range("bytes=500-499", 1000), Ok(None));
+        // The end is capped at the size.
+        assert_eq!(byte_range("bytes=900-2000", 1000), Ok(Some((900, 999))));
+    }
+
+    #[test]
+    fn byte_range_open() {
+        assert_eq!(byte_range("bytes=100-", 1000), Ok(Some((100, 999))));
+        assert_eq!(byte_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(byte_range("bytes=-2000", 1000), Ok(Some((0, 999))));
+    }
+
+    #[test]
+    fn byte_range_not_satisfiable() {
+        assert_eq!(byte_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(byte_range("bytes=-0", 1000), Err(()));
+    }
+
+    #[test]
+    fn byte_range_ignored() {
+        assert_eq!(byte_range("bytes=0-9,20-29", 1000), Ok(None));
+        assert_eq!(byte_range("items=0-9", 1000), Ok(None));
+        assert_eq!(byte_range("bytes=a-b", 1000), Ok(None));
+        assert_eq!(byte_range("bytes=10", 1000), Ok(None));
+    }
+}
@@ -0,0 +1,80 @@
+//!
+//! Graceful shutdown.
+//!
+//! On SIGTERM or SIGINT the listeners stop accepting connections, idle
+//! connections are closed, and the transfers that are running get some
+//! time to finish.
+//!
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+// How often drain() checks the number of connections.
+const DRAIN_CHECK: Duration = Duration::from_millis(250);
+
+struct State {
+    tx: watch::Sender<bool>,
+    // Kept, so that sending never fails.
+    rx: watch::Receiver<bool>,
+    conns: AtomicUsize,
+}
+
+static STATE: Lazy<State> = Lazy::new(|| {
+    let (tx, rx) = watch::channel(false);
+    State {
+        tx,
+        rx,
+        conns: AtomicUsize::new(0),
+    }
+});
+
+/// Start shutting down.
+pub fn start() {
+    let _ = STATE.tx.send(true);
+}
+
+/// Has the shutdown started.
+pub fn is_started() -> bool {
+    *STATE.rx.borrow()
+}
+
+/// Resolves when the shutdown starts.
+pub async fn started() {
+    let mut rx = STATE.rx.clone();
+    while !*rx.borrow() {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Wait until all connections are closed, or until `timeout` has
+/// passed. Returns the number of connections that are still open.
+pub async fn drain(timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let conns = STATE.conns.load(Ordering::SeqCst);
+        if conns == 0 || Instant::now() >= deadline {
+            return conns;
+        }
+        tokio::time::sleep(DRAIN_CHECK).await;
+    }
+}
+
+/// Counts an open connection, until dropped.
+pub struct ConnGuard(());
+
+impl ConnGuard {
+    pub fn new() -> ConnGuard {
+        STATE.conns.fetch_add(1, Ordering::SeqCst);
+        ConnGuard(())
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        STATE.conns.fetch_sub(1, Ordering::SeqCst);
+    }
+}
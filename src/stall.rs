@@ -0,0 +1,100 @@
+//!
+//! Stall diagnostics.
+//!
+//! Measures how long the event loop takes to wake up a task, and per
+//! stream, how long it takes us to produce the next chunk once the
+//! client is ready for it. If that takes too long, the server itself
+//! (and not the network) slowed the transfer down.
+//!
+use std::sync::Arc;
+
+use tokio::time::{Duration, Instant};
+
+use crate::stats::Stats;
+
+// How often we probe the event loop.
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Measure event loop latency. Never returns.
+pub async fn run(threshold: Duration, stats: Arc<Stats>) {
+    loop {
+        let start = Instant::now();
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        let lag = start.elapsed().saturating_sub(PROBE_INTERVAL);
+        if lag >= threshold {
+            stats.count_loop_stall();
+            log::warn!("event loop stalled for {}ms", lag.as_millis());
+        }
+    }
+}
+
+/// Keeps track of the timing of one stream.
+pub struct StreamTimer {
+    name: String,
+    threshold: Duration,
+    stats: Arc<Stats>,
+    last_yield: Option<Instant>,
+    chunk_start: Instant,
+    // Time we spent producing chunks, and the longest time.
+    stalls: u32,
+    stalled: Duration,
+    max_stall: Duration,
+    // Longest time the client (network) made us wait.
+    max_gap: Duration,
+}
+
+impl StreamTimer {
+    pub fn new(name: String, threshold: Duration, stats: Arc<Stats>) -> StreamTimer {
+        StreamTimer {
+            name,
+            threshold,
+            stats,
+            last_yield: None,
+            chunk_start: Instant::now(),
+            stalls: 0,
+            stalled: Duration::from_secs(0),
+            max_stall: Duration::from_secs(0),
+            max_gap: Duration::from_secs(0),
+        }
+    }
+
+    /// Call this when the client asks for the next chunk.
+    pub fn resumed(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_yield {
+            self.max_gap = std::cmp::max(self.max_gap, now - last);
+        }
+        self.chunk_start = now;
+    }
+
+    /// Call this when the next chunk is ready (before throttling).
+    pub fn ready(&mut self) {
+        let elapsed = self.chunk_start.elapsed();
+        if elapsed >= self.threshold {
+            self.stalls += 1;
+            self.stalled += elapsed;
+            self.max_stall = std::cmp::max(self.max_stall, elapsed);
+        }
+    }
+
+    /// Call this just before the chunk is handed to the client.
+    pub fn yielded(&mut self) {
+        self.last_yield = Some(Instant::now());
+    }
+}
+
+impl Drop for StreamTimer {
+    fn drop(&mut self) {
+        if self.stalls > 0 {
+            self.stats.count_stream_stall();
+            log::warn!(
+                "{}: server stalled {} times for {}ms total (longest {}ms), longest network wait {}ms",
+                self.name,
+                self.stalls,
+                self.stalled.as_millis(),
+                self.max_stall.as_millis(),
+                self.max_gap.as_millis(),
+            );
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//!
+//! Transfer statistics.
+//!
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{offset::Local, NaiveDate};
+use serde::Serialize;
+
+use crate::accounting::Accounting;
+use crate::metrics::{self, Histogram};
+
+/// Server-wide counters.
+pub struct Stats {
+    requests: AtomicU64,
+    tests: AtomicU64,
+    active_streams: AtomicU64,
+    bytes_served: AtomicU64,
+    today: Mutex<(NaiveDate, u64)>,
+    panics: AtomicU64,
+    loop_stalls: AtomicU64,
+    stream_stalls: AtomicU64,
+    download_sizes: Histogram,
+    throughput: Histogram,
+    accounting: Arc<Accounting>,
+}
+
+/// The counters that are safe to show to the public.
+#[derive(Debug, Serialize)]
+pub struct PublicStats {
+    tests_today: u64,
+    bytes_served: u64,
+    active_streams: u64,
+}
+
+/// Stall counters (see stall.rs).
+#[derive(Debug, Serialize)]
+pub struct Stalls {
+    event_loop: u64,
+    streams: u64,
+}
+
+impl Stats {
+    pub fn new(accounting: Accounting) -> Stats {
+        Stats {
+            requests: AtomicU64::new(0),
+            tests: AtomicU64::new(0),
+            active_streams: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            today: Mutex::new((Local::now().naive_local().date(), 0)),
+            panics: AtomicU64::new(0),
+            loop_stalls: AtomicU64::new(0),
+            stream_stalls: AtomicU64::new(0),
+            download_sizes: Histogram::new(metrics::SIZE_BUCKETS),
+            throughput: Histogram::new(metrics::THROUGHPUT_BUCKETS),
+            accounting: Arc::new(accounting),
+        }
+    }
+
+    pub fn accounting(&self) -> Arc<Accounting> {
+        self.accounting.clone()
+    }
+
+    /// Count an HTTP request.
+    pub fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Count a test. Counting of today's tests restarts at midnight.
+    fn count_test(&self) {
+        self.tests.fetch_add(1, Ordering::Relaxed);
+        let day = Local::now().naive_local().date();
+        let mut today = self.today.lock().unwrap();
+        if today.0 != day {
+            *today = (day, 0);
+        }
+        today.1 += 1;
+    }
+
+    fn tests_today(&self) -> u64 {
+        let day = Local::now().naive_local().date();
+        let today = self.today.lock().unwrap();
+        if today.0 == day {
+            today.1
+        } else {
+            0
+        }
+    }
+
+    /// A request handler or stream panicked.
+    pub fn count_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// The event loop was late waking up a task.
+    pub fn count_loop_stall(&self) {
+        self.loop_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The server was slow producing data for a stream.
+    pub fn count_stream_stall(&self) {
+        self.stream_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stalls(&self) -> Stalls {
+        Stalls {
+            event_loop: self.loop_stalls.load(Ordering::Relaxed),
+            streams: self.stream_stalls.load(Ordering::Relaxed),
+        }
+    }
+
+    /// All counters, in Prometheus format.
+    pub fn prometheus(&self) -> String {
+        let mut m = metrics::Writer::new();
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        m.counter(
+            "speedtest_requests_total",
+            "HTTP requests.",
+            load(&self.requests),
+        );
+        m.counter(
+            "speedtest_tests_total",
+            "Download and upload tests.",
+            load(&self.tests),
+        );
+        m.counter(
+            "speedtest_bytes_served_total",
+            "Bytes sent in downloads.",
+            load(&self.bytes_served),
+        );
+        m.gauge(
+            "speedtest_active_streams",
+            "Tests in progress.",
+            load(&self.active_streams),
+        );
+        m.counter(
+            "speedtest_panics_total",
+            "Request handlers that panicked.",
+            load(&self.panics),
+        );
+        m.histogram(
+            "speedtest_download_size_bytes",
+            "Requested size of downloads.",
+            &self.download_sizes,
+        );
+        m.histogram(
+            "speedtest_download_throughput_bits_per_second",
+            "Throughput of finished downloads.",
+            &self.throughput,
+        );
+        m.finish()
+    }
+
+    pub fn active_streams(&self) -> u64 {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn public(&self) -> PublicStats {
+        PublicStats {
+            tests_today: self.tests_today(),
+            bytes_served: self.bytes_served.load(Ordering::Relaxed),
+            active_streams: self.active_streams.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keeps track of one running test (download or upload).
+/// For downloads, `size` is the size of the requested file, if it has one.
+pub struct StreamGuard {
+    stats: Arc<Stats>,
+    download: bool,
+    size: Option<u64>,
+    bytes: u64,
+    start: Instant,
+}
+
+impl StreamGuard {
+    /// A download. Downloads without a size (like /30s) are counted
+    /// as size 0 in the accounting.
+    pub fn download(stats: Arc<Stats>, size: Option<u64>) -> StreamGuard {
+        stats.accounting.request(size.unwrap_or(0));
+        if let Some(size) = size {
+            stats.download_sizes.observe(size as f64);
+        }
+        StreamGuard::new(stats, true, size)
+    }
+
+    /// An upload.
+    pub fn upload(stats: Arc<Stats>) -> StreamGuard {
+        StreamGuard::new(stats, false, None)
+    }
+
+    fn new(stats: Arc<Stats>, download: bool, size: Option<u64>) -> StreamGuard {
+        stats.count_test();
+        stats.active_streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard {
+            stats,
+            download,
+            size,
+            bytes: 0,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.stats.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+        self.bytes += bytes;
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.stats.active_streams.fetch_sub(1, Ordering::Relaxed);
+        if self.download {
+            self.stats
+                .accounting
+                .bytes(self.size.unwrap_or(0), self.bytes);
+            let elapsed = self.start.elapsed().as_secs_f64();
+            if self.bytes > 0 && elapsed > 0f64 {
+                let throughput = (self.bytes * 8) as f64 / elapsed;
+                self.stats.throughput.observe(throughput);
+            }
+        }
+    }
+}
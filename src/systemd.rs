@@ -0,0 +1,158 @@
+//!
+//! systemd integration: socket activation and sd_notify.
+//!
+//! With socket activation, systemd binds the listening sockets, and
+//! passes them in file descriptors 3 and up (LISTEN_FDS). That way the
+//! server can listen on port 80 and 443 without being root.
+//!
+use std::env;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+use once_cell::sync::OnceCell;
+use tokio::time::Duration;
+
+// The first passed file descriptor.
+const LISTEN_FDS_START: i32 = 3;
+
+// The socket to systemd, connected before a chroot can hide it.
+static NOTIFY: OnceCell<Option<UnixDatagram>> = OnceCell::new();
+
+/// A listening socket passed to us by systemd.
+pub struct Inherited {
+    pub listener: TcpListener,
+    pub addr: SocketAddr,
+    // FileDescriptorName= of the socket unit.
+    pub name: String,
+}
+
+// A variable that is meant for this process (if $<name>_PID is set).
+fn env_for_us(name: &str, pid_var: &str) -> Option<String> {
+    if let Ok(pid) = env::var(pid_var) {
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+    env::var(name).ok()
+}
+
+/// Take the listening sockets that were passed by systemd.
+pub fn listen_fds() -> Vec<Inherited> {
+    let count = match env_for_us("LISTEN_FDS", "LISTEN_PID").and_then(|n| n.parse::<i32>().ok()) {
+        Some(count) => count,
+        None => return Vec::new(),
+    };
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    // Child processes should not think these are theirs.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let mut inherited = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or("").to_string();
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(addr) => inherited.push(Inherited {
+                listener,
+                addr,
+                name,
+            }),
+            Err(e) => log::error!("LISTEN_FDS: fd {}: not a TCP socket: {}", fd, e),
+        }
+    }
+    inherited
+}
+
+/// Connect to the notify socket of systemd, if it is listening. This
+/// must be done before a chroot, after that the path of the socket
+/// cannot be found. notify() does it too, if it was not done yet.
+pub fn connect_notify() {
+    NOTIFY.get_or_init(|| {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        match connect(&path) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                log::warn!("NOTIFY_SOCKET {}: {}", path, e);
+                None
+            }
+        }
+    });
+}
+
+/// Send a state update (like "READY=1") to systemd, if it is listening.
+pub fn notify(state: &str) {
+    connect_notify();
+    if let Some(socket) = NOTIFY.get().and_then(|s| s.as_ref()) {
+        if let Err(e) = socket.send(state.as_bytes()) {
+            log::debug!("sd_notify {}: {}", state, e);
+        }
+    }
+}
+
+// A socket in the abstract namespace starts with '@', in the address
+// that is a NUL byte.
+#[cfg(target_os = "linux")]
+fn connect(path: &str) -> io::Result<UnixDatagram> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let socket = UnixDatagram::unbound()?;
+    let name = match path.strip_prefix('@') {
+        Some(name) => name,
+        None => {
+            socket.connect(path)?;
+            return Ok(socket);
+        }
+    };
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if name.len() >= addr.sun_path.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    for (dst, src) in addr.sun_path[1..].iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+    let res = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Ping the systemd watchdog, if it is enabled. Never returns.
+/// This runs on the event loop, so if that hangs, systemd notices.
+pub async fn watchdog() {
+    let usec = env_for_us("WATCHDOG_USEC", "WATCHDOG_PID").and_then(|u| u.parse::<u64>().ok());
+    let interval = match usec {
+        Some(usec) if usec > 0 => Duration::from_micros(usec / 2),
+        _ => return,
+    };
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
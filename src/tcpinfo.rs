@@ -0,0 +1,200 @@
+//!
+//! Read TCP statistics (TCP_INFO) from a socket, and set the
+//! congestion control algorithm.
+//!
+//! This needs unsafe code, for getsockopt() and setsockopt().
+//!
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use serde::Serialize;
+
+/// The TCP statistics we are interested in.
+#[derive(Clone, Debug, Serialize)]
+pub struct TcpInfo {
+    // Smoothed round trip time and its variance, in microseconds.
+    pub rtt: u32,
+    pub rttvar: u32,
+    // Total number of retransmitted segments.
+    pub retransmits: u32,
+    // Congestion window in bytes.
+    pub cwnd: u64,
+    // Most recent delivery rate in bytes/sec (Linux 4.9+).
+    pub delivery_rate: Option<u64>,
+}
+
+impl fmt::Display for TcpInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rtt={:.3}ms rttvar={:.3}ms retrans={} cwnd={}",
+            self.rtt as f64 / 1000f64,
+            self.rttvar as f64 / 1000f64,
+            self.retransmits,
+            self.cwnd
+        )?;
+        if let Some(rate) = self.delivery_rate {
+            write!(f, " rate={:.2}Mbit/s", (rate * 8) as f64 / 1_000_000f64)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::mem;
+    use std::os::unix::io::RawFd;
+
+    use super::TcpInfo;
+
+    // struct tcp_info from <linux/tcp.h>, up to tcpi_delivery_rate.
+    // The libc crate only has the fields up to tcpi_total_retrans.
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(non_camel_case_types, dead_code)]
+    struct tcp_info {
+        tcpi_state: u8,
+        tcpi_ca_state: u8,
+        tcpi_retransmits: u8,
+        tcpi_probes: u8,
+        tcpi_backoff: u8,
+        tcpi_options: u8,
+        tcpi_snd_rcv_wscale: u8,
+        tcpi_rate_flags: u8,
+        tcpi_rto: u32,
+        tcpi_ato: u32,
+        tcpi_snd_mss: u32,
+        tcpi_rcv_mss: u32,
+        tcpi_unacked: u32,
+        tcpi_sacked: u32,
+        tcpi_lost: u32,
+        tcpi_retrans: u32,
+        tcpi_fackets: u32,
+        tcpi_last_data_sent: u32,
+        tcpi_last_ack_sent: u32,
+        tcpi_last_data_recv: u32,
+        tcpi_last_ack_recv: u32,
+        tcpi_pmtu: u32,
+        tcpi_rcv_ssthresh: u32,
+        tcpi_rtt: u32,
+        tcpi_rttvar: u32,
+        tcpi_snd_ssthresh: u32,
+        tcpi_snd_cwnd: u32,
+        tcpi_advmss: u32,
+        tcpi_reordering: u32,
+        tcpi_rcv_rtt: u32,
+        tcpi_rcv_space: u32,
+        tcpi_total_retrans: u32,
+        tcpi_pacing_rate: u64,
+        tcpi_max_pacing_rate: u64,
+        tcpi_bytes_acked: u64,
+        tcpi_bytes_received: u64,
+        tcpi_segs_out: u32,
+        tcpi_segs_in: u32,
+        tcpi_notsent_bytes: u32,
+        tcpi_min_rtt: u32,
+        tcpi_data_segs_in: u32,
+        tcpi_data_segs_out: u32,
+        tcpi_delivery_rate: u64,
+    }
+
+    pub fn tcp_info(fd: RawFd) -> Option<TcpInfo> {
+        let mut info = tcp_info::default();
+        let mut len = mem::size_of::<tcp_info>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut tcp_info as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            return None;
+        }
+        // Older kernels return a shorter struct.
+        let has_rate = len as usize >= mem::size_of::<tcp_info>();
+        Some(TcpInfo {
+            rtt: info.tcpi_rtt,
+            rttvar: info.tcpi_rttvar,
+            retransmits: info.tcpi_total_retrans,
+            cwnd: info.tcpi_snd_cwnd as u64 * info.tcpi_snd_mss as u64,
+            delivery_rate: if has_rate {
+                Some(info.tcpi_delivery_rate)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+mod sys {
+    use std::mem;
+    use std::os::unix::io::RawFd;
+
+    use super::TcpInfo;
+
+    pub fn tcp_info(fd: RawFd) -> Option<TcpInfo> {
+        let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            return None;
+        }
+        Some(TcpInfo {
+            rtt: info.tcpi_rtt,
+            rttvar: info.tcpi_rttvar,
+            retransmits: info.tcpi_snd_rexmitpack,
+            cwnd: info.tcpi_snd_cwnd as u64,
+            delivery_rate: None,
+        })
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+mod sys {
+    use super::TcpInfo;
+
+    pub fn tcp_info(_fd: i32) -> Option<TcpInfo> {
+        None
+    }
+}
+
+pub use sys::tcp_info;
+
+/// Set the congestion control algorithm (TCP_CONGESTION) of a socket.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn set_congestion_control(fd: RawFd, name: &str) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+pub fn set_congestion_control(_fd: RawFd, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_CONGESTION not supported",
+    ))
+}
@@ -1,11 +1,14 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::error::Error;
+use std::net::IpAddr;
 
 use handlebars::*;
 use once_cell::sync::Lazy;
 use serde::Serialize;
 use woothee::parser::Parser;
 
+use crate::report;
 use crate::server;
 use crate::Config;
 
@@ -36,13 +39,152 @@ impl Browser<'_> {
     }
 }
 
+/// What the index page knows about the request.
+pub struct Request {
+    pub agent: String,
+    // The client address, taking X-Forwarded-For etc into account.
+    pub client_ip: Option<IpAddr>,
+    pub tls: bool,
+    // The Host header, or :authority with HTTP/2.
+    pub host: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Server {
+    hostname: String,
+    version: &'static str,
+}
+
 #[derive(Debug, Serialize)]
-struct Vars<'a, 'b> {
+struct Vars<'a> {
     browser: Option<Browser<'a>>,
-    sizes: &'b Vec<String>,
+    sizes: Vec<String>,
+    labels: HashMap<String, String>,
+    files: Vec<File>,
+    server: Server,
+    client_ip: Option<String>,
+    scheme: &'static str,
+    host: Option<String>,
+    // scheme://host, for absolute URLs.
+    base_url: Option<String>,
+}
+
+// The list of sizes on the index page. If `both-units` is set,
+// every size is listed both in decimal and binary units.
+fn sizes(config: &Config) -> Vec<String> {
+    let mut sizes = Vec::new();
+    for size in &config.index.sizes {
+        let variants = if config.index.both_units {
+            vec![Some(size.clone()), server::other_unit(size)]
+        } else {
+            vec![Some(size.clone())]
+        };
+        for variant in variants.into_iter().flatten() {
+            if !sizes.contains(&variant) {
+                sizes.push(variant);
+            }
+        }
+    }
+    sizes
+}
+
+// Labels, by size. A label for "1GB" also applies to "1gb".
+fn labels(config: &Config, sizes: &[String]) -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    for size in sizes {
+        let info = config
+            .index
+            .size_info
+            .iter()
+            .find(|i| i.size.eq_ignore_ascii_case(size));
+        if let Some(label) = info.and_then(|i| i.label.as_ref()) {
+            labels.insert(size.clone(), label.clone());
+        }
+    }
+    labels
+}
+
+/// One file of the index, for /api/files.json.
+#[derive(Debug, Serialize)]
+pub struct File {
+    name: String,
+    size: String,
+    bytes: u64,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+/// The files of the index page, as JSON.
+#[derive(Debug, Serialize)]
+pub struct Files {
+    version: &'static str,
+    max_file_size: u64,
+    files: Vec<File>,
+}
+
+// Every size with its file name, URL, exact size in bytes and label.
+fn file_list(config: &Config) -> Vec<File> {
+    let sizes = sizes(config);
+    let mut labels = labels(config, &sizes);
+    sizes
+        .into_iter()
+        .filter_map(|size| {
+            let bytes = server::size(&size).ok()?;
+            let name = format!("{}.bin", size);
+            Some(File {
+                url: format!("/{}", name),
+                name,
+                bytes,
+                label: labels.remove(&size),
+                size,
+            })
+        })
+        .collect()
+}
+
+/// The same list of files as on the index page.
+pub fn files(config: &Config) -> Files {
+    Files {
+        version: env!("CARGO_PKG_VERSION"),
+        max_file_size: config.max_file_size.unwrap_or(server::MAX_FILE_SIZE),
+        files: file_list(config),
+    }
+}
+
+// A number of bytes for humans, like "100 MB" or "1.5 GiB". Binary
+// units are only used for exact multiples of 1024.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [(&str, &str); 5] = [
+        ("kB", "KiB"),
+        ("MB", "MiB"),
+        ("GB", "GiB"),
+        ("TB", "TiB"),
+        ("PB", "PiB"),
+    ];
+    let mut unit = None;
+    for (i, names) in UNITS.iter().enumerate() {
+        let exp = i as u32 + 1;
+        if bytes >= 1000u64.pow(exp) {
+            unit = Some((exp, names));
+        }
+    }
+    let (exp, (dec, bin)) = match unit {
+        Some(unit) => unit,
+        None => return format!("{} B", bytes),
+    };
+    let decimal = bytes as f64 / 1000u64.pow(exp) as f64;
+    let (value, name) = if bytes.trailing_zeros() >= 10 * exp && decimal.fract() != 0.0 {
+        (bytes as f64 / 1024u64.pow(exp) as f64, bin)
+    } else {
+        (decimal, dec)
+    };
+    let value = format!("{:.2}", value);
+    let value = value.trim_end_matches('0').trim_end_matches('.');
+    format!("{} {}", value, name)
 }
 
-pub fn build(config: &Config, agent: String) -> Result<String, Box<dyn Error + Sync + Send>> {
+pub fn build(config: &Config, req: Request) -> Result<String, Box<dyn Error + Sync + Send>> {
     let mut hbs = Handlebars::new();
     if let Some(file) = config.index.file.as_ref() {
         hbs.register_template_file("index", file)?;
@@ -63,13 +205,39 @@ pub fn build(config: &Config, agent: String) -> Result<String, Box<dyn Error + S
     handlebars_helper!(contains: |haystack: str, needle: str| {
         haystack.contains(needle)
     });
+    handlebars_helper!(bytes: |n: u64| format_bytes(n));
     hbs.register_helper("size", Box::new(size));
     hbs.register_helper("contains", Box::new(contains));
+    hbs.register_helper("format_bytes", Box::new(bytes));
 
+    let sizes = sizes(config);
+    let scheme = if req.tls { "https" } else { "http" };
     let vars = Vars {
-        browser: Browser::parse(&agent),
-        sizes: &config.index.sizes,
+        browser: Browser::parse(&req.agent),
+        labels: labels(config, &sizes),
+        sizes,
+        files: file_list(config),
+        server: Server {
+            hostname: report::hostname(),
+            version: env!("CARGO_PKG_VERSION"),
+        },
+        client_ip: req.client_ip.map(|ip| ip.to_string()),
+        scheme,
+        base_url: req.host.as_ref().map(|h| format!("{}://{}", scheme, h)),
+        host: req.host,
     };
 
     Ok(hbs.render("index", &vars)?)
 }
+
+/// Compile and render the index page once, to find errors in a custom
+/// template before the first request.
+pub fn check(config: &Config) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let req = Request {
+        agent: String::new(),
+        client_ip: None,
+        tls: false,
+        host: None,
+    };
+    build(config, req).map(|_| ())
+}
@@ -0,0 +1,140 @@
+//!
+//! Bandwidth throttling.
+//!
+use std::sync::Mutex;
+
+use tokio::time::{Duration, Instant};
+
+/// A token bucket, refilled at `rate` bytes per second.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new bucket. `rate` is in bytes per second. The bucket
+    /// can hold 1/10th of a second worth of tokens.
+    pub fn new(rate: u64) -> TokenBucket {
+        let rate = rate as f64;
+        TokenBucket {
+            rate,
+            burst: rate / 10.0,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take `n` tokens out of the bucket. If the bucket runs dry, wait
+    /// until it has been refilled.
+    pub async fn take(&mut self, n: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 {
+            let wait = Duration::from_secs_f64(-self.tokens / self.rate);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A rate limiter shared by all streams.
+///
+/// Every `take` reserves the next free time slot, and waits until it
+/// has passed. Since a stream only asks for more after its previous
+/// slot, concurrent streams are served in turn.
+pub struct SharedBucket {
+    rate: f64,
+    burst: Duration,
+    next: Mutex<Instant>,
+}
+
+impl SharedBucket {
+    /// Create a new limiter. `rate` is in bytes per second. After an
+    /// idle period, 1/10th of a second worth of data can be sent at once.
+    pub fn new(rate: u64) -> SharedBucket {
+        SharedBucket {
+            rate: rate as f64,
+            burst: Duration::from_millis(100),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Take `n` bytes worth of time. Waits if the limit is reached.
+    pub async fn take(&self, n: usize) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let earliest = now.checked_sub(self.burst).unwrap_or(now);
+            if *next < earliest {
+                *next = earliest;
+            }
+            *next += Duration::from_secs_f64(n as f64 / self.rate);
+            next.checked_duration_since(now)
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parse a rate like "50mbit" or "1gbit" (also "bps"), and return
+/// it in bytes per second.
+pub fn rate(s: &str) -> Result<u64, String> {
+    let lc = s.trim().to_lowercase();
+    let num = lc.trim_end_matches("bit").trim_end_matches("bps");
+    if num.len() == lc.len() {
+        return Err(format!("{}: unit must be bit or bps", s));
+    }
+    let (num, mult) = match num.chars().last() {
+        Some('k') => (&num[..num.len() - 1], 1_000f64),
+        Some('m') => (&num[..num.len() - 1], 1_000_000f64),
+        Some('g') => (&num[..num.len() - 1], 1_000_000_000f64),
+        Some('t') => (&num[..num.len() - 1], 1_000_000_000_000f64),
+        _ => (num, 1f64),
+    };
+    let num: f64 = num
+        .trim()
+        .parse()
+        .map_err(|_| format!("{}: cannot parse rate", s))?;
+    let bytes = (num * mult / 8.0) as u64;
+    if bytes == 0 {
+        return Err(format!("{}: rate too low", s));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_suffixes() {
+        assert_eq!(rate("8bit"), Ok(1));
+        assert_eq!(rate("50kbit"), Ok(6_250));
+        assert_eq!(rate("50mbit"), Ok(6_250_000));
+        assert_eq!(rate("1gbit"), Ok(125_000_000));
+        assert_eq!(rate("1tbit"), Ok(125_000_000_000));
+        assert_eq!(rate("100mbps"), Ok(12_500_000));
+        assert_eq!(rate("1.5Gbit"), Ok(187_500_000));
+        assert_eq!(rate(" 10 mbit "), Ok(1_250_000));
+    }
+
+    #[test]
+    fn rate_invalid() {
+        assert!(rate("").is_err());
+        assert!(rate("50").is_err());
+        assert!(rate("50mb").is_err());
+        assert!(rate("mbit").is_err());
+        assert!(rate("xmbit").is_err());
+        assert!(rate("50pbit").is_err());
+        // Less than a byte per second.
+        assert!(rate("1bit").is_err());
+        assert!(rate("0mbit").is_err());
+        assert!(rate("-5mbit").is_err());
+    }
+}
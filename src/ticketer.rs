@@ -0,0 +1,143 @@
+//!
+//! TLS session ticket encryption with key rotation.
+//!
+//! Tickets are encrypted with the current key. Every `interval`, a new
+//! key is generated; the previous one is kept around for one more
+//! interval so that recently issued tickets can still be used, and
+//! then it is erased. One ticketer is shared by all https listeners.
+//!
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::ProducesTickets;
+
+// Tickets start with a key id, so we know which key to use.
+const KEY_ID_LEN: usize = 16;
+
+struct TicketKey {
+    id: [u8; KEY_ID_LEN],
+    key: LessSafeKey,
+}
+
+impl TicketKey {
+    fn generate(rng: &SystemRandom) -> Option<TicketKey> {
+        let mut id = [0u8; KEY_ID_LEN];
+        let mut key = [0u8; 32];
+        rng.fill(&mut id).ok()?;
+        rng.fill(&mut key).ok()?;
+        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, &key).ok()?;
+        Some(TicketKey {
+            id,
+            key: LessSafeKey::new(key),
+        })
+    }
+}
+
+struct Keys {
+    current: TicketKey,
+    previous: Option<TicketKey>,
+    rotated: Instant,
+}
+
+/// Ticket encrypter/decrypter with periodic key rotation.
+pub struct RotatingTicketer {
+    interval: Duration,
+    rng: SystemRandom,
+    keys: Mutex<Keys>,
+}
+
+impl RotatingTicketer {
+    pub fn new(interval: Duration) -> Option<RotatingTicketer> {
+        let rng = SystemRandom::new();
+        let current = TicketKey::generate(&rng)?;
+        Some(RotatingTicketer {
+            interval,
+            rng,
+            keys: Mutex::new(Keys {
+                current,
+                previous: None,
+                rotated: Instant::now(),
+            }),
+        })
+    }
+
+    // Rotate the keys if it is time to do so.
+    fn rotate(&self, keys: &mut Keys) {
+        let elapsed = keys.rotated.elapsed();
+        if elapsed < self.interval {
+            return;
+        }
+        if let Some(key) = TicketKey::generate(&self.rng) {
+            let previous = std::mem::replace(&mut keys.current, key);
+            // if we were idle for more than two intervals, the
+            // previous key is too old to keep.
+            keys.previous = if elapsed < self.interval * 2 {
+                Some(previous)
+            } else {
+                None
+            };
+            keys.rotated = Instant::now();
+            log::debug!("rotated TLS session ticket key");
+        }
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.interval.as_secs() as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut keys = self.keys.lock().unwrap();
+        self.rotate(&mut keys);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+
+        // key id || nonce || ciphertext || tag
+        let mut ticket = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + plain.len() + 16);
+        ticket.extend_from_slice(&keys.current.id);
+        ticket.extend_from_slice(&nonce);
+        let mut data = plain.to_vec();
+        keys.current
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&keys.current.id),
+                &mut data,
+            )
+            .ok()?;
+        ticket.extend_from_slice(&data);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < KEY_ID_LEN + NONCE_LEN {
+            return None;
+        }
+        let (id, rest) = cipher.split_at(KEY_ID_LEN);
+        let (nonce, data) = rest.split_at(NONCE_LEN);
+
+        let mut keys = self.keys.lock().unwrap();
+        self.rotate(&mut keys);
+        let key = if keys.current.id[..] == *id {
+            &keys.current
+        } else {
+            keys.previous.as_ref().filter(|k| k.id[..] == *id)?
+        };
+
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut data = data.to_vec();
+        let plain = key
+            .key
+            .open_in_place(nonce, Aad::from(&key.id), &mut data)
+            .ok()?;
+        Some(plain.to_vec())
+    }
+}
@@ -0,0 +1,299 @@
+//!
+//! TLS setup.
+//!
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientHello, NoClientAuth, PrivateKey,
+    ResolvesServerCert, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::TlsAcceptor;
+use yasna::models::ObjectIdentifier;
+use yasna::tags::{TAG_GENERALIZEDTIME, TAG_UTCTIME};
+use yasna::{BERReaderSeq, Tag};
+
+use crate::ticketer::RotatingTicketer;
+use crate::Https;
+
+// How often the certificate files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+fn invalid_data(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, msg))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+}
+
+/// Read a PEM encoded certificate chain.
+pub fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut open(path)?)
+        .map_err(|_| invalid_data(path, "cannot parse certificate chain"))?;
+    if certs.is_empty() {
+        return Err(invalid_data(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+/// Read a PEM encoded private key (PKCS#8 or RSA).
+pub fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?)
+        .map_err(|_| invalid_data(path, "cannot parse private key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?)
+            .map_err(|_| invalid_data(path, "cannot parse private key"))?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid_data(path, "no private key found"))
+}
+
+/// Read the CA certificates that client certificates must be signed by.
+pub fn load_client_ca(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| invalid_data(path, &format!("{:?}", e)))?;
+    }
+    Ok(roots)
+}
+
+// Skip the rest of a sequence.
+fn skip_rest(r: &mut BERReaderSeq) -> yasna::ASN1Result<()> {
+    while r.read_optional(|r| r.read_der())?.is_some() {}
+    Ok(())
+}
+
+/// The common name (CN) of the subject of a DER encoded certificate.
+pub fn common_name(der: &[u8]) -> Option<String> {
+    let cn_oid = ObjectIdentifier::from_slice(&[2, 5, 4, 3]);
+    let mut cn = None;
+    yasna::parse_der(der, |r| {
+        r.read_sequence(|r| {
+            r.next().read_sequence(|r| {
+                // Version, serial number, signature algorithm, issuer, validity.
+                r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                for _ in 0..4 {
+                    r.next().read_der()?;
+                }
+                // The subject is a sequence of sets of attributes.
+                r.next().read_sequence_of(|r| {
+                    r.read_set_of(|r| {
+                        r.read_sequence(|r| {
+                            let oid = r.next().read_oid()?;
+                            let value = r.next().read_tagged_der()?;
+                            if oid == cn_oid {
+                                cn = value.as_str().map(String::from);
+                            }
+                            Ok(())
+                        })
+                    })
+                })?;
+                skip_rest(r)
+            })?;
+            skip_rest(r)
+        })
+    })
+    .ok()?;
+    cn
+}
+
+/// The end of the validity period (notAfter) of a DER encoded certificate.
+pub fn not_after(der: &[u8]) -> Option<DateTime<Utc>> {
+    let mut time = None;
+    yasna::parse_der(der, |r| {
+        r.read_sequence(|r| {
+            r.next().read_sequence(|r| {
+                // Version, serial number, signature algorithm, issuer.
+                r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                for _ in 0..3 {
+                    r.next().read_der()?;
+                }
+                // Validity: notBefore, notAfter.
+                r.next().read_sequence(|r| {
+                    r.next().read_der()?;
+                    time = Some(r.next().read_tagged_der()?);
+                    Ok(())
+                })?;
+                skip_rest(r)
+            })?;
+            skip_rest(r)
+        })
+    })
+    .ok()?;
+    // UTCTime is YYMMDDHHMMSSZ, GeneralizedTime YYYYMMDDHHMMSSZ.
+    let time = time?;
+    let value = std::str::from_utf8(time.value()).ok()?;
+    let value = match time.tag() {
+        TAG_UTCTIME if value.starts_with(|c| c >= '5') => format!("19{}", value),
+        TAG_UTCTIME => format!("20{}", value),
+        TAG_GENERALIZEDTIME => value.to_string(),
+        _ => return None,
+    };
+    let time = NaiveDateTime::parse_from_str(&value, "%Y%m%d%H%M%SZ").ok()?;
+    Some(Utc.from_utc_datetime(&time))
+}
+
+/// Check that the key belongs to the first certificate of the chain,
+/// by signing something with the key and verifying it with the certificate.
+pub fn check_pair(key: &Path, chain: &Path) -> io::Result<()> {
+    let certs = load_certs(chain)?;
+    let signing_key = sign::any_supported_type(&load_key(key)?)
+        .map_err(|_| invalid_data(key, "unsupported private key type"))?;
+    let schemes = [
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+    ];
+    let offered: Vec<_> = schemes.iter().map(|(s, _)| *s).collect();
+    let signer = signing_key
+        .choose_scheme(&offered)
+        .ok_or_else(|| invalid_data(key, "unsupported private key type"))?;
+    let (_, alg) = schemes
+        .iter()
+        .find(|(s, _)| *s == signer.get_scheme())
+        .unwrap();
+    let msg = b"speedtest-fileserver";
+    let sig = signer
+        .sign(msg)
+        .map_err(|e| invalid_data(key, &e.to_string()))?;
+    let cert = webpki::EndEntityCert::from(&certs[0].0)
+        .map_err(|e| invalid_data(chain, &format!("{:?}", e)))?;
+    cert.verify_signature(alg, msg, &sig)
+        .map_err(|_| invalid_data(key, "does not match the certificate"))
+}
+
+/// The certificate of the https listeners. It can be replaced while
+/// running, new connections get the new certificate.
+#[derive(Default)]
+pub struct CertStore {
+    current: RwLock<Option<CertifiedKey>>,
+}
+
+impl CertStore {
+    pub fn new() -> CertStore {
+        CertStore::default()
+    }
+
+    /// Load a key file and a certificate chain file.
+    pub fn load(&self, key: &Path, chain: &Path) -> io::Result<()> {
+        let certs = load_certs(chain)?;
+        let signing_key = sign::any_supported_type(&load_key(key)?)
+            .map_err(|_| invalid_data(key, "unsupported private key type"))?;
+        let certified = CertifiedKey::new(certs, Arc::new(signing_key));
+        *self.current.write().unwrap() = Some(certified);
+        Ok(())
+    }
+}
+
+/// Reload the certificate when the key or chain file changes, for
+/// example after a renewal by certbot. Never returns.
+pub async fn watch(certs: Arc<CertStore>, key: PathBuf, chain: PathBuf) {
+    let mut loaded = modified(&key, &chain);
+    let mut seen = loaded;
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        // Wait until both files have been written, they are
+        // usually replaced one after the other.
+        let current = modified(&key, &chain);
+        if current.is_none() && seen.is_some() {
+            log::error!(
+                "https: cannot read {:?} or {:?}, keeping the old certificate",
+                key,
+                chain
+            );
+        }
+        if current.is_some() && current != loaded && current == seen {
+            match certs.load(&key, &chain) {
+                Ok(()) => log::info!("https: reloaded {:?}", chain),
+                Err(e) => log::error!("https: {}, keeping the old certificate", e),
+            }
+            loaded = current;
+        }
+        seen = current;
+    }
+}
+
+fn modified(key: &Path, chain: &Path) -> Option<(SystemTime, SystemTime)> {
+    let mtime = |p: &Path| p.metadata().and_then(|m| m.modified()).ok();
+    Some((mtime(key)?, mtime(chain)?))
+}
+
+impl ResolvesServerCert for CertStore {
+    // Without a certificate (ACME did not get one yet), the handshake fails.
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// Build a TLS acceptor that uses the certificate in `certs`. With
+/// `client_ca`, clients must have a certificate signed by that CA.
+pub fn acceptor(
+    certs: Arc<CertStore>,
+    https: &Https,
+    client_ca: Option<&Path>,
+) -> io::Result<TlsAcceptor> {
+    let mut config = match client_ca {
+        Some(path) => ServerConfig::new(AllowAnyAuthenticatedClient::new(load_client_ca(path)?)),
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+    config.cert_resolver = certs;
+    match https.http2.as_ref() {
+        Some(h2) if h2.disable => config.set_protocols(&[b"http/1.1".to_vec()]),
+        _ => config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]),
+    }
+
+    // Session tickets, only if key rotation is configured.
+    if let Some(interval) = https.ticket_key_rotation {
+        let ticketer = RotatingTicketer::new(Duration::from_secs(interval)).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "cannot generate session ticket key")
+        })?;
+        config.ticketer = Arc::new(ticketer);
+    }
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_after_of_generated_cert() {
+        // Before 2050 that is a UTCTime, after it a GeneralizedTime.
+        for time in ["20310203040506Z", "20510203040506Z"] {
+            let time = NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%SZ").unwrap();
+            let time = Utc.from_utc_datetime(&time);
+            let mut params = rcgen::CertificateParams::new(vec!["example.com".to_string()]);
+            params.not_after = time;
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            assert_eq!(not_after(&cert.serialize_der().unwrap()), Some(time));
+        }
+    }
+
+    #[test]
+    fn not_after_garbage() {
+        assert_eq!(not_after(b"not a certificate"), None);
+    }
+}
@@ -0,0 +1,191 @@
+//!
+//! WebSocket speedtest protocol, on /ws.
+//!
+//! The client sends text messages with commands:
+//!
+//! - `download <size>`: the server sends <size> bytes of random data,
+//!   in binary messages.
+//! - `upload`: the client sends binary messages, then `done`.
+//!
+//! During a transfer, the server sends `{"bytes":<n>}` text messages
+//! every 250ms, and at the end a summary like the one of /upload:
+//! `{"bytes":<n>,"duration":<secs>,"throughput":<bits/sec>}`.
+//!
+//! Every command goes through the same limits as a request over http,
+//! and is logged as a request for /ws/download/<size> or /ws/upload.
+//!
+use std::future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::time::{Duration, Instant};
+use warp::reply::Response as HyperResponse;
+use warp::ws::{Message, WebSocket};
+
+use crate::logger::LogInfo;
+use crate::server::{self, FileServer};
+
+// How often the byte count is sent.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+// Commands per connection. After that, the connection is closed.
+const MAX_COMMANDS: usize = 100;
+
+type Sender = SplitSink<WebSocket, Message>;
+type Receiver = SplitStream<WebSocket>;
+
+/// What a websocket session needs from the server.
+pub struct Session {
+    pub server: FileServer,
+    // Of the request that opened the websocket.
+    pub log_info: LogInfo,
+    pub max_size: u64,
+    // Give up if the client does not read a message for this long.
+    pub send_timeout: Duration,
+}
+
+impl Session {
+    /// Run commands until the client closes the connection.
+    pub async fn run(self, ws: WebSocket) {
+        let (mut tx, mut rx) = ws.split();
+        let mut commands = 0;
+        while let Some(msg) = rx.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => return log::debug!("ws: {}", e),
+            };
+            // Pings are answered by the library, binary data outside
+            // an upload is ignored.
+            let cmd = match msg.to_str() {
+                Ok(cmd) => cmd.trim().to_string(),
+                Err(()) => continue,
+            };
+            commands += 1;
+            if commands > MAX_COMMANDS {
+                let _ = self
+                    .send(&mut tx, json!({ "error": "too many commands" }))
+                    .await;
+                return;
+            }
+            let mut args = cmd.split_whitespace();
+            let res = match (args.next(), args.next()) {
+                (Some("download"), Some(size)) => match server::size(size) {
+                    Ok(sz) if sz <= self.max_size => self.download(&mut tx, size).await,
+                    Ok(_) => self.send(&mut tx, json!({ "error": "too big" })).await,
+                    Err(_) => {
+                        self.send(&mut tx, json!({ "error": "cannot parse size" }))
+                            .await
+                    }
+                },
+                (Some("upload"), None) => self.upload(&mut tx, &mut rx).await,
+                _ => {
+                    self.send(
+                        &mut tx,
+                        json!({ "error": format!("unknown command: {}", cmd) }),
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = res {
+                return log::debug!("ws: {}", e);
+            }
+        }
+    }
+
+    async fn download(&self, tx: &mut Sender, size: &str) -> io::Result<()> {
+        let log_info = self.log_info.command(&format!("download/{}", size));
+        let resp = self.server.ws_download(size, log_info);
+        if !resp.status().is_success() {
+            return self.send(tx, json!({ "error": error(resp).await })).await;
+        }
+        // The log line is written when the body is dropped.
+        let mut body = resp.into_body();
+        let start = Instant::now();
+        let mut progress = start;
+        let mut sent = 0u64;
+        while let Some(Ok(data)) = body.next().await {
+            sent += data.len() as u64;
+            self.send_message(tx, Message::binary(data.to_vec()))
+                .await?;
+            if progress.elapsed() >= PROGRESS_INTERVAL {
+                progress = Instant::now();
+                self.send(tx, json!({ "bytes": sent })).await?;
+            }
+        }
+        self.send(tx, summary(sent, start)).await
+    }
+
+    async fn upload(&self, tx: &mut Sender, rx: &mut Receiver) -> io::Result<()> {
+        let log_info = self.log_info.command("upload");
+        let received = AtomicU64::new(0);
+        // The binary messages, until "done".
+        let body = rx
+            .by_ref()
+            .take_while(|msg| future::ready(!matches!(msg, Ok(m) if m.to_str() == Ok("done"))))
+            .filter_map(|msg| {
+                future::ready(match msg {
+                    Ok(msg) if msg.is_binary() => Some(Ok(Bytes::from(msg.into_bytes()))),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                })
+            })
+            .inspect(|item| {
+                if let Ok(data) = item {
+                    received.fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+            });
+
+        let start = Instant::now();
+        let upload = self.server.ws_upload(body, log_info);
+        tokio::pin!(upload);
+        let mut progress = tokio::time::interval_at(start + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+        let res = loop {
+            tokio::select! {
+                res = &mut upload => break res,
+                _ = progress.tick() => {
+                    let bytes = received.load(Ordering::Relaxed);
+                    self.send(tx, json!({ "bytes": bytes })).await?;
+                }
+            }
+        };
+        match res {
+            Ok(done) => self.send(tx, summary(done, start)).await,
+            Err(resp) => self.send(tx, json!({ "error": error(resp).await })).await,
+        }
+    }
+
+    async fn send(&self, tx: &mut Sender, value: Value) -> io::Result<()> {
+        self.send_message(tx, Message::text(value.to_string()))
+            .await
+    }
+
+    // Send a message, or give up after send-timeout.
+    async fn send_message(&self, tx: &mut Sender, msg: Message) -> io::Result<()> {
+        match tokio::time::timeout(self.send_timeout, tx.send(msg)).await {
+            Ok(res) => res.map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "send timeout")),
+        }
+    }
+}
+
+// The text of an error response, like "too many streams".
+async fn error(resp: HyperResponse) -> String {
+    match hyper::body::to_bytes(resp.into_body()).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(e) => e.to_string(),
+    }
+}
+
+fn summary(bytes: u64, start: Instant) -> Value {
+    let elapsed = start.elapsed().as_secs_f64();
+    let throughput = if elapsed > 0f64 {
+        (bytes * 8) as f64 / elapsed
+    } else {
+        0f64
+    };
+    json!({ "bytes": bytes, "duration": elapsed, "throughput": throughput })
+}
@@ -0,0 +1,189 @@
+//!
+//! xoshiro256++, four generators side by side.
+//!
+//! The four lanes are independent, so with AVX2 they fit in one SIMD
+//! register and every step is done for all four at once. The output is
+//! the same with or without SIMD.
+//!
+use once_cell::sync::Lazy;
+
+const LANES: usize = 4;
+
+// One step of all four lanes.
+const BLOCK: usize = 8 * LANES;
+
+// Is AVX2 available on this CPU.
+static HAS_AVX2: Lazy<bool> = Lazy::new(|| {
+    #[cfg(target_arch = "x86_64")]
+    {
+        is_x86_feature_detected!("avx2")
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
+});
+
+/// Can `fill` use SIMD instructions. On aarch64 NEON is always
+/// there, so the plain code already uses it.
+pub fn simd_available() -> bool {
+    cfg!(target_arch = "aarch64") || *HAS_AVX2
+}
+
+/// The state of the four generators, one array per state word.
+pub struct Xoshiro256x4 {
+    s: [[u64; LANES]; 4],
+}
+
+// splitmix64, to turn a seed into the state.
+fn splitmix64(x: &mut u64) -> u64 {
+    *x = x.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *x;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Xoshiro256x4 {
+    /// A generator for `seed` and `stream`. Different streams of the
+    /// same seed are unrelated.
+    pub fn new(seed: u64, stream: u64) -> Xoshiro256x4 {
+        let mut x = seed;
+        let mut x = splitmix64(&mut x) ^ stream;
+        let mut s = [[0u64; LANES]; 4];
+        for word in s.iter_mut() {
+            for lane in word.iter_mut() {
+                *lane = splitmix64(&mut x);
+            }
+        }
+        Xoshiro256x4 { s }
+    }
+
+    /// Fill `buf` with random data. If the length is not a multiple
+    /// of 32, the last block is cut short.
+    pub fn fill(&mut self, buf: &mut [u8], simd: bool) {
+        let whole = buf.len() - buf.len() % BLOCK;
+        let (buf, tail) = buf.split_at_mut(whole);
+        self.fill_blocks(buf, simd);
+        if !tail.is_empty() {
+            let mut block = [0u8; BLOCK];
+            self.fill_blocks(&mut block, simd);
+            tail.copy_from_slice(&block[..tail.len()]);
+        }
+    }
+
+    // Fill whole blocks.
+    fn fill_blocks(&mut self, buf: &mut [u8], simd: bool) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if simd && *HAS_AVX2 {
+                // Safe, since we checked that the CPU has AVX2.
+                return unsafe { self.fill_avx2(buf) };
+            }
+        }
+        // simd is only used on x86_64.
+        let _ = simd;
+        self.fill_lanes(buf)
+    }
+
+    // The same as fill_lanes, with AVX2 instructions. AVX2 has no
+    // rotate, so that is two shifts and an or.
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn fill_avx2(&mut self, buf: &mut [u8]) {
+        use std::arch::x86_64::*;
+
+        let [w0, w1, w2, w3] = &self.s;
+        let mut s0 = _mm256_loadu_si256(w0.as_ptr() as *const __m256i);
+        let mut s1 = _mm256_loadu_si256(w1.as_ptr() as *const __m256i);
+        let mut s2 = _mm256_loadu_si256(w2.as_ptr() as *const __m256i);
+        let mut s3 = _mm256_loadu_si256(w3.as_ptr() as *const __m256i);
+        for block in buf.chunks_exact_mut(BLOCK) {
+            let sum = _mm256_add_epi64(s0, s3);
+            let rot = _mm256_or_si256(_mm256_slli_epi64(sum, 23), _mm256_srli_epi64(sum, 41));
+            let out = _mm256_add_epi64(rot, s0);
+            _mm256_storeu_si256(block.as_mut_ptr() as *mut __m256i, out);
+            let t = _mm256_slli_epi64(s1, 17);
+            s2 = _mm256_xor_si256(s2, s0);
+            s3 = _mm256_xor_si256(s3, s1);
+            s1 = _mm256_xor_si256(s1, s2);
+            s0 = _mm256_xor_si256(s0, s3);
+            s2 = _mm256_xor_si256(s2, t);
+            s3 = _mm256_or_si256(_mm256_slli_epi64(s3, 45), _mm256_srli_epi64(s3, 19));
+        }
+        let [w0, w1, w2, w3] = &mut self.s;
+        _mm256_storeu_si256(w0.as_mut_ptr() as *mut __m256i, s0);
+        _mm256_storeu_si256(w1.as_mut_ptr() as *mut __m256i, s1);
+        _mm256_storeu_si256(w2.as_mut_ptr() as *mut __m256i, s2);
+        _mm256_storeu_si256(w3.as_mut_ptr() as *mut __m256i, s3);
+    }
+
+    fn fill_lanes(&mut self, buf: &mut [u8]) {
+        // Work on a copy, so that the state can stay in registers.
+        let [mut s0, mut s1, mut s2, mut s3] = self.s;
+        for block in buf.chunks_exact_mut(BLOCK) {
+            let mut out = [0u64; LANES];
+            for l in 0..LANES {
+                out[l] = s0[l]
+                    .wrapping_add(s3[l])
+                    .rotate_left(23)
+                    .wrapping_add(s0[l]);
+                let t = s1[l] << 17;
+                s2[l] ^= s0[l];
+                s3[l] ^= s1[l];
+                s1[l] ^= s2[l];
+                s0[l] ^= s3[l];
+                s2[l] ^= t;
+                s3[l] = s3[l].rotate_left(45);
+            }
+            for (dst, val) in block.chunks_exact_mut(8).zip(out.iter()) {
+                dst.copy_from_slice(&val.to_le_bytes());
+            }
+        }
+        self.s = [s0, s1, s2, s3];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(len: usize, simd: bool) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        let mut rng = Xoshiro256x4::new(42, 7);
+        // In two calls, so that the state carried over is compared too.
+        rng.fill(&mut buf[..len / 2 / BLOCK * BLOCK], simd);
+        rng.fill(&mut buf[len / 2 / BLOCK * BLOCK..], simd);
+        buf
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn avx2_same_as_lanes() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        for len in [0, 1, 31, 32, 33, 100, 4096, 4097, 65536 + 17] {
+            let mut plain = vec![0u8; len];
+            let mut simd = vec![0u8; len];
+            let whole = len - len % BLOCK;
+            let mut a = Xoshiro256x4::new(1, 2);
+            let mut b = Xoshiro256x4::new(1, 2);
+            a.fill_lanes(&mut plain[..whole]);
+            unsafe { b.fill_avx2(&mut simd[..whole]) };
+            assert_eq!(plain, simd, "len {}", len);
+            assert_eq!(a.s, b.s, "len {}", len);
+            assert_eq!(fill(len, false), fill(len, true), "len {}", len);
+        }
+    }
+
+    #[test]
+    fn tail_is_prefix_of_block() {
+        let full = fill(64, false);
+        for len in 33..64 {
+            let mut buf = vec![0u8; len];
+            Xoshiro256x4::new(42, 7).fill(&mut buf, false);
+            assert_eq!(buf[..], full[..len], "len {}", len);
+        }
+    }
+}